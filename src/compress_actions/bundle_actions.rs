@@ -0,0 +1,628 @@
+use crate::config_actions::PipelineConfig;
+use crate::dedupe_actions::release_group;
+use crate::err_impl::CompressionError;
+use crate::inspect_actions::archive_info;
+use crate::xz_decoder_impl::AsyncXzDecoder;
+use crate::xz_encoder_impl::AsyncXzEncoder;
+use liblzma::read::XzDecoder;
+use liblzma::stream::{Check, Filters, LzmaOptions, MatchFinder, MtStreamBuilder, Stream};
+use liblzma::write::XzEncoder;
+use rayon::prelude::*;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::path::{Component, Path, PathBuf};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Extension of bundle files.
+pub const BUNDLE_EXTENSION: &str = "cbz.xz";
+
+/// Default xz preset used for bundles.
+pub const BUNDLE_LEVEL: u32 = 9;
+
+/// Dictionary of solid bundles, chapters further apart than this don't help each other.
+pub const SOLID_DICT_SIZE: u32 = 256 << 20;
+
+/// `LZMA_PRESET_EXTREME` flag, liblzma doesn't re-export it.
+const PRESET_EXTREME: u32 = 1 << 31;
+
+/// Smallest & largest xz dictionary liblzma accepts.
+pub const DICT_SIZE_RANGE: std::ops::RangeInclusive<u32> = 4096..=1536 << 20;
+
+/// Settings of the xz encoder bundles are written with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct XzOptions {
+    /// xz preset, 0 to 9.
+    pub level: u32,
+    /// Encoder threads, 0 uses every core. Ignored for solid bundles.
+    pub threads: u32,
+    /// Uncompressed bytes per xz block, 0 lets liblzma pick 3 times the dictionary size.
+    /// Ignored for solid bundles, those are one block.
+    pub block_size: u64,
+    /// Dictionary size in bytes, 0 keeps the one of the preset.
+    pub dict_size: u32,
+    /// Long-range settings for cold storage: one single threaded block,
+    /// extreme preset, longest matches & a `SOLID_DICT_SIZE` dictionary
+    /// unless `dict_size` is set.
+    pub solid: bool,
+}
+
+impl Default for XzOptions {
+    fn default() -> Self {
+        Self {
+            level: BUNDLE_LEVEL,
+            threads: 0,
+            block_size: 0,
+            dict_size: 0,
+            solid: false,
+        }
+    }
+}
+
+impl From<&PipelineConfig> for XzOptions {
+    fn from(config: &PipelineConfig) -> Self {
+        Self {
+            level: config.xz_level,
+            threads: config.xz_threads,
+            block_size: config.xz_block_size,
+            dict_size: config.xz_dict_size,
+            solid: false,
+        }
+    }
+}
+
+impl XzOptions {
+    /// Number of encoder threads once 0 is resolved to the core count, always 1 when solid.
+    pub fn thread_count(&self) -> u32 {
+        match (self.threads, self.solid) {
+            (_, true) => 1,
+            (0, false) => {
+                std::thread::available_parallelism().map_or(1, |cores| cores.get() as u32)
+            }
+            (threads, false) => threads,
+        }
+    }
+
+    /// Dictionary the encoder ends up with, 0 for the one of the preset.
+    pub fn resolved_dict_size(&self) -> u32 {
+        match (self.dict_size, self.solid) {
+            (0, true) => SOLID_DICT_SIZE,
+            (dict_size, _) => dict_size,
+        }
+    }
+
+    /// LZMA2 filter chain of the preset with the dictionary & solid settings applied.
+    fn filters(&self) -> Result<Filters, CompressionError> {
+        let dict_size = self.resolved_dict_size();
+        let preset = if self.solid {
+            self.level | PRESET_EXTREME
+        } else {
+            self.level
+        };
+        let mut lzma =
+            LzmaOptions::new_preset(preset).map_err(|err| CompressionError::xz(err.into()))?;
+        if dict_size > 0 {
+            lzma.dict_size(dict_size);
+        }
+        if self.solid {
+            //NOTE: Longest matches on the binary tree finder, so repeats across chapters are found.
+            lzma.nice_len(273).match_finder(MatchFinder::BinaryTree4);
+        }
+        let mut filters = Filters::new();
+        filters.lzma2(&lzma);
+        Ok(filters)
+    }
+
+    /// Set up the multithreaded encoder.
+    fn builder(&self) -> Result<MtStreamBuilder, CompressionError> {
+        let mut builder = MtStreamBuilder::new();
+        builder
+            .threads(self.thread_count())
+            .block_size(self.block_size)
+            .check(Check::Crc64);
+        if self.resolved_dict_size() == 0 && !self.solid {
+            builder.preset(self.level);
+        } else {
+            builder.filters(self.filters()?);
+        }
+        Ok(builder)
+    }
+
+    /// Build the xz stream encoder.
+    ///
+    /// Without `solid` every thread compresses its own block, so the blocks
+    /// are independent and a reader can seek to one without decoding those
+    /// before it. Each thread holds about 3 times `block_size` of memory.
+    /// A solid bundle is one block from a single threaded encoder instead,
+    /// so every archive can match against everything before it in the dictionary.
+    pub fn encoder(&self) -> Result<Stream, CompressionError> {
+        if self.solid {
+            return Stream::new_stream_encoder(&self.filters()?, Check::Crc64)
+                .map_err(|err| CompressionError::xz(err.into()));
+        }
+        self.builder()?
+            .encoder()
+            .map_err(|err| CompressionError::xz(err.into()))
+    }
+
+    /// Rough memory the encoder needs with these settings, in bytes.
+    pub fn memory_usage(&self) -> Result<u64, CompressionError> {
+        if self.solid {
+            //NOTE: liblzma only estimates through the multithreaded builder, one thread
+            //with the smallest block leaves about the single threaded encoder.
+            let mut builder = self.builder()?;
+            builder.block_size(1);
+            return Ok(builder.memusage());
+        }
+        Ok(self.builder()?.memusage())
+    }
+}
+
+/// Pack archives into one xz compressed bundle.
+///
+/// Every member is written as a `name:size` line followed by its raw bytes,
+/// so similar archives compress against each other.
+/// * `members`: (name_in_bundle, archive_path) pairs, names may hold `/`.
+/// * `output_file`: Bundle to write.
+/// * `options`: xz preset, threads & block size.
+///
+/// Return `u64` compressed size of the bundle.
+pub fn write_bundle<P: AsRef<Path>>(
+    members: &[(String, PathBuf)],
+    output_file: P,
+    options: &XzOptions,
+) -> Result<u64, CompressionError> {
+    let stream = options
+        .encoder()
+        .map_err(|err| err.in_archive(output_file.as_ref()))?;
+    let out = BufWriter::new(File::create(output_file.as_ref())?);
+    let mut encoder = XzEncoder::new_stream(out, stream);
+    for (name, path) in members {
+        let mut in_file = File::open(path)?;
+        let size = in_file.metadata()?.len();
+        let meta = format!("{}:{}\n", name, size);
+        let copied = encoder
+            .write_all(meta.as_bytes())
+            .and_then(|_| io::copy(&mut (&mut in_file).take(size), &mut encoder))
+            .map_err(|err| CompressionError::xz(err).in_archive(path))?;
+        let grew = in_file.read(&mut [0; 1])? != 0;
+        check_member_size(name, size, copied, grew).map_err(|err| err.in_archive(path))?;
+    }
+    encoder
+        .try_finish()
+        .map_err(|err| CompressionError::xz(err).in_archive(output_file.as_ref()))?;
+    let compressed_size = encoder.total_out();
+    //NOTE: Dropping the `BufWriter` would flush the tail of the stream & hide a failure.
+    encoder
+        .finish()?
+        .into_inner()
+        .map_err(io::IntoInnerError::into_error)?;
+    Ok(compressed_size)
+}
+
+/// Pack archives into one xz compressed bundle streamed to an async writer.
+///
+/// Same layout as `write_bundle`, the writer is shut down once the stream is complete.
+/// * `members`: (name_in_bundle, archive_path) pairs, names may hold `/`.
+/// * `writer`: Where the bundle goes, like a socket or a file.
+/// * `options`: xz preset, threads & block size.
+///
+/// Return `u64` compressed size of the bundle.
+pub async fn write_bundle_async<W: AsyncWrite + Unpin>(
+    members: &[(String, PathBuf)],
+    writer: W,
+    options: &XzOptions,
+) -> Result<u64, CompressionError> {
+    let mut encoder = AsyncXzEncoder::with_options(writer, options)?;
+    for (name, path) in members {
+        let mut in_file = tokio::fs::File::open(path).await?;
+        let size = in_file.metadata().await?.len();
+        let meta = format!("{}:{}\n", name, size);
+        encoder
+            .write_all(meta.as_bytes())
+            .await
+            .map_err(|err| CompressionError::xz(err).in_archive(path))?;
+        let copied = tokio::io::copy(&mut (&mut in_file).take(size), &mut encoder)
+            .await
+            .map_err(|err| CompressionError::xz(err).in_archive(path))?;
+        let grew = in_file.read(&mut [0; 1]).await? != 0;
+        check_member_size(name, size, copied, grew).map_err(|err| err.in_archive(path))?;
+    }
+    encoder.shutdown().await.map_err(CompressionError::xz)?;
+    Ok(encoder.total_out())
+}
+
+/// Make sure a member got exactly the bytes its header announced.
+///
+/// An archive that changed size after its header was written would leave
+/// the bundle misaligned if it shrank, or cut off in there if it grew.
+/// * `size`: Size in the member header.
+/// * `copied`: Bytes written after the header.
+/// * `grew`: Whether the archive had more bytes than that.
+fn check_member_size(
+    name: &str,
+    size: u64,
+    copied: u64,
+    grew: bool,
+) -> Result<(), CompressionError> {
+    if copied == size && !grew {
+        return Ok(());
+    }
+    Err(CompressionError::container(format!(
+        "Member {} changed size while it was bundled, its header says {} bytes",
+        name, size
+    )))
+}
+
+/// Piece of a member name, numbers compare by value so `Ch2` comes before `Ch10`.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+enum NameChunk {
+    Number(u64),
+    Text(String),
+}
+
+/// Split a name into text & number chunks for natural ordering.
+fn natural_key(name: &str) -> Vec<NameChunk> {
+    let mut chunks = Vec::new();
+    let mut rest = name;
+    while let Some(first) = rest.chars().next() {
+        let is_digit = first.is_ascii_digit();
+        let end = rest
+            .find(|ch: char| ch.is_ascii_digit() != is_digit)
+            .unwrap_or(rest.len());
+        let (chunk, tail) = rest.split_at(end);
+        chunks.push(match chunk.parse() {
+            Ok(number) if is_digit => NameChunk::Number(number),
+            _ => NameChunk::Text(chunk.to_lowercase()),
+        });
+        rest = tail;
+    }
+    chunks
+}
+
+/// Series a member belongs to when its `ComicInfo.xml` doesn't say.
+///
+/// The folder plus the file name without numbers & `[..]`/`(..)` tags,
+/// so `Horimiya/Horimiya Ch12 [Group].cbz` becomes `horimiya/horimiya ch`.
+fn series_from_name(name: &str) -> String {
+    let (folder, file_name) = name.rsplit_once('/').unwrap_or(("", name));
+    //NOTE: Only the extension goes, names like `Mr. Robot Ch1.cbz` hold dots too.
+    let stem = file_name
+        .rsplit_once('.')
+        .map_or(file_name, |(stem, _)| stem);
+    let mut series = String::new();
+    let mut depth = 0;
+    for ch in stem.chars() {
+        match ch {
+            '[' | '(' => depth += 1,
+            ']' | ')' => depth = (depth - 1).max(0),
+            _ if depth > 0 || ch.is_ascii_digit() => {}
+            _ if ch.is_alphanumeric() => series.extend(ch.to_lowercase()),
+            _ if !series.ends_with(' ') => series.push(' '),
+            _ => {}
+        }
+    }
+    format!("{}/{}", folder.to_lowercase(), series.trim())
+}
+
+/// Where a member goes in a solid bundle, compared field by field.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct SolidKey {
+    series: String,
+    /// Main page codec, pages of one codec compress against each other best.
+    kind: String,
+    /// Release group & page size, only filled in when grouping similar archives.
+    group: Option<String>,
+    resolution: Option<(u32, u32)>,
+    natural: Vec<NameChunk>,
+}
+
+/// Order members for a solid bundle, so the xz dictionary still holds
+/// the chapters that look most like the one being compressed.
+///
+/// Members are lined up by series (the `ComicInfo.xml` one, else taken from
+/// the name), then by main page codec, then in natural chapter order.
+/// Archives that can't be read are ordered by their name alone.
+/// * `members`: (name_in_bundle, archive_path) pairs.
+/// * `group_similar`: Also keep the archives of one release group & page
+///   size together within a series, they share scans & encoder settings.
+pub fn solid_order(members: &mut Vec<(String, PathBuf)>, group_similar: bool) {
+    let keys: Vec<SolidKey> = members
+        .par_iter()
+        .map(|(name, path)| {
+            let info = archive_info(path).ok();
+            let series = info
+                .as_ref()
+                .and_then(|info| {
+                    info.comic_info
+                        .iter()
+                        .find(|(field, _)| field == "Series")
+                        .map(|(_, series)| series.to_lowercase())
+                })
+                .unwrap_or_else(|| series_from_name(name));
+            let kind = info
+                .as_ref()
+                .and_then(|info| {
+                    info.codecs
+                        .iter()
+                        .max_by_key(|(_, pages)| **pages)
+                        .map(|(codec, _)| codec.clone())
+                })
+                .unwrap_or_default();
+            let (group, resolution) = if group_similar {
+                (
+                    release_group(path),
+                    info.as_ref().and_then(|info| info.max_dimensions),
+                )
+            } else {
+                (None, None)
+            };
+            SolidKey {
+                series,
+                kind,
+                group,
+                resolution,
+                //NOTE: Without the extension, so `Ch1` still comes before `Ch1.5`.
+                natural: natural_key(name.strip_suffix(".cbz").unwrap_or(name)),
+            }
+        })
+        .collect();
+    let mut keyed: Vec<_> = keys.into_iter().zip(members.drain(..)).collect();
+    keyed.sort();
+    members.extend(keyed.into_iter().map(|(_, member)| member));
+}
+
+/// Walk the members of a bundle in order.
+///
+/// * `bundle_file`: Bundle to read.
+/// * `visit`: Called with the name, size & a reader limited to each member.
+///   Whatever the reader isn't read to the end is skipped.
+pub fn read_bundle<P, F>(bundle_file: P, mut visit: F) -> Result<(), CompressionError>
+where
+    P: AsRef<Path>,
+    F: FnMut(&str, u64, &mut dyn Read) -> Result<(), CompressionError>,
+{
+    let bundle_file = bundle_file.as_ref();
+    let xz_error = |err: io::Error| CompressionError::xz(err).in_archive(bundle_file);
+    let mut decoder = BufReader::new(XzDecoder::new(BufReader::new(File::open(bundle_file)?)));
+    loop {
+        let mut header = String::new();
+        if decoder.read_line(&mut header).map_err(xz_error)? == 0 {
+            return Ok(());
+        }
+        let (name, size) =
+            parse_member_header(&header).map_err(|err| err.in_archive(bundle_file))?;
+        let mut member = (&mut decoder).take(size);
+        visit(&name, size, &mut member)?;
+        io::copy(&mut member, &mut io::sink()).map_err(xz_error)?;
+        if member.limit() > 0 {
+            return Err(
+                CompressionError::metadata(format!("Member {} is truncated", name))
+                    .in_archive(bundle_file),
+            );
+        }
+    }
+}
+
+/// Unpack every member of a bundle into `output_dir`.
+///
+/// Member names that would end up outside `output_dir` are refused.
+/// * `bundle_file`: Bundle to unpack.
+/// * `output_dir`: Folder the archives are written to.
+///
+/// Return `Vec<PathBuf>` paths of the unpacked archives.
+pub fn extract_bundle<P1: AsRef<Path>, P2: AsRef<Path>>(
+    bundle_file: P1,
+    output_dir: P2,
+) -> Result<Vec<PathBuf>, CompressionError> {
+    let mut written = Vec::new();
+    read_bundle(bundle_file.as_ref(), |name, _, member| {
+        let target = member_path(output_dir.as_ref(), name)
+            .map_err(|err| err.in_archive(bundle_file.as_ref()))?;
+        if let Some(parent) = target.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let mut out = BufWriter::new(File::create(&target)?);
+        io::copy(member, &mut out)
+            .map_err(|err| CompressionError::xz(err).in_archive(bundle_file.as_ref()))?;
+        out.flush()?;
+        written.push(target);
+        Ok(())
+    })?;
+    Ok(written)
+}
+
+/// Unpack every member of a bundle streamed from an async reader into `output_dir`.
+///
+/// Member names that would end up outside `output_dir` are refused.
+/// * `reader`: Bundle stream, wrap plain readers in a `tokio::io::BufReader`.
+/// * `output_dir`: Folder the archives are written to.
+///
+/// Return `Vec<PathBuf>` paths of the unpacked archives.
+pub async fn extract_bundle_async<R: AsyncBufRead + Unpin, P: AsRef<Path>>(
+    reader: R,
+    output_dir: P,
+) -> Result<Vec<PathBuf>, CompressionError> {
+    let mut decoder = tokio::io::BufReader::new(AsyncXzDecoder::new(reader)?);
+    let mut written = Vec::new();
+    loop {
+        let mut header = String::new();
+        if decoder
+            .read_line(&mut header)
+            .await
+            .map_err(CompressionError::xz)?
+            == 0
+        {
+            return Ok(written);
+        }
+        let (name, size) = parse_member_header(&header)?;
+        let target = member_path(output_dir.as_ref(), &name)?;
+        if let Some(parent) = target.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        let mut out = tokio::fs::File::create(&target).await?;
+        let copied = tokio::io::copy(&mut (&mut decoder).take(size), &mut out)
+            .await
+            .map_err(CompressionError::xz)?;
+        if copied < size {
+            return Err(CompressionError::metadata(format!(
+                "Member {} is truncated",
+                name
+            )));
+        }
+        out.flush().await?;
+        written.push(target);
+    }
+}
+
+/// Split a `name:size` member header.
+fn parse_member_header(header: &str) -> Result<(String, u64), CompressionError> {
+    header
+        .trim_end_matches('\n')
+        .rsplit_once(':')
+        .and_then(|(name, size)| Some((name.to_string(), size.parse::<u64>().ok()?)))
+        .ok_or_else(|| CompressionError::metadata(format!("Invalid member header {:?}", header)))
+}
+
+/// Path a member unpacks to, refusing names that leave `output_dir`.
+fn member_path(output_dir: &Path, name: &str) -> Result<PathBuf, CompressionError> {
+    let relative = Path::new(name);
+    if !relative
+        .components()
+        .all(|component| matches!(component, Component::Normal(_)))
+    {
+        return Err(CompressionError::metadata(format!(
+            "Unsafe member name {:?}",
+            name
+        )));
+    }
+    Ok(output_dir.join(relative))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "comics_archiver_bundle_{}_{}",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[tokio::test]
+    async fn async_bundle_round_trips() {
+        let dir = scratch_dir("async");
+        //NOTE: Bigger than a job's chunk, so members span several jobs.
+        let big: Vec<u8> = (0..600_000u32).map(|i| (i * 7 % 251) as u8).collect();
+        std::fs::write(dir.join("a.cbz"), &big).unwrap();
+        std::fs::write(dir.join("b.cbz"), b"").unwrap();
+        std::fs::write(dir.join("c.cbz"), b"chapter three").unwrap();
+        let members = vec![
+            ("Series/Ch 01.cbz".to_string(), dir.join("a.cbz")),
+            ("Series/Ch 02.cbz".to_string(), dir.join("b.cbz")),
+            ("Ch 03.cbz".to_string(), dir.join("c.cbz")),
+        ];
+        let options = XzOptions {
+            level: 1,
+            threads: 2,
+            ..XzOptions::default()
+        };
+        let bundle = dir.join("bundle.cbz.xz");
+        let file = tokio::fs::File::create(&bundle).await.unwrap();
+        let written = write_bundle_async(&members, file, &options).await.unwrap();
+        assert_eq!(written, bundle.metadata().unwrap().len());
+
+        let output_dir = dir.join("out");
+        let reader = tokio::io::BufReader::new(tokio::fs::File::open(&bundle).await.unwrap());
+        let extracted = extract_bundle_async(reader, &output_dir).await.unwrap();
+        assert_eq!(extracted.len(), members.len());
+        for ((name, path), target) in members.iter().zip(&extracted) {
+            assert_eq!(target, &output_dir.join(name));
+            assert_eq!(std::fs::read(target).unwrap(), std::fs::read(path).unwrap());
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn members_have_to_keep_their_size() {
+        assert!(check_member_size("Ch1.cbz", 10, 10, false).is_ok());
+        let shrank = check_member_size("Ch1.cbz", 10, 7, false).unwrap_err();
+        assert!(matches!(shrank, CompressionError::ContainerError { .. }));
+        assert!(shrank.to_string().contains("Ch1.cbz"));
+        assert!(check_member_size("Ch1.cbz", 10, 10, true).is_err());
+    }
+
+    #[tokio::test]
+    async fn truncated_async_bundle_fails() {
+        let dir = scratch_dir("truncated");
+        let data: Vec<u8> = (0..200_000u32).map(|i| (i * 13 % 241) as u8).collect();
+        std::fs::write(dir.join("a.cbz"), &data).unwrap();
+        let members = vec![("Ch 01.cbz".to_string(), dir.join("a.cbz"))];
+        let file = tokio::fs::File::create(dir.join("bundle.cbz.xz"))
+            .await
+            .unwrap();
+        write_bundle_async(&members, file, &XzOptions::default())
+            .await
+            .unwrap();
+        let mut bundle = std::fs::read(dir.join("bundle.cbz.xz")).unwrap();
+        bundle.truncate(bundle.len() / 2);
+        let result = extract_bundle_async(bundle.as_slice(), dir.join("out")).await;
+        assert!(result.is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn natural_key_orders_numbers_by_value() {
+        let mut names = vec!["Ch10", "ch2", "Ch1.5", "Ch1", "Extra"];
+        names.sort_by_key(|name| natural_key(name));
+        assert_eq!(names, ["Ch1", "Ch1.5", "ch2", "Ch10", "Extra"]);
+        assert_eq!(
+            natural_key("Vol 02"),
+            [NameChunk::Text("vol ".to_string()), NameChunk::Number(2)]
+        );
+        assert!(natural_key("").is_empty());
+    }
+
+    #[test]
+    fn series_from_name_drops_numbers_tags_and_extension() {
+        assert_eq!(
+            series_from_name("Horimiya/Horimiya Ch12 [Group].cbz"),
+            "horimiya/horimiya ch"
+        );
+        assert_eq!(series_from_name("Mr. Robot Ch1.cbz"), "/mr robot ch");
+        assert_eq!(
+            series_from_name("Mr. Robot Ch1.cbz"),
+            series_from_name("Mr. Robot Ch2 (v2).cbz")
+        );
+    }
+
+    #[test]
+    fn member_header_round_trips() {
+        let header = format!("{}:{}\n", "Series: Part 1/Ch 01.cbz", 1234);
+        let (name, size) = parse_member_header(&header).unwrap();
+        assert_eq!(name, "Series: Part 1/Ch 01.cbz");
+        assert_eq!(size, 1234);
+    }
+
+    #[test]
+    fn invalid_member_headers_are_refused() {
+        assert!(parse_member_header("Ch 01.cbz\n").is_err());
+        assert!(parse_member_header("Ch 01.cbz:12ab\n").is_err());
+        assert!(parse_member_header("Ch 01.cbz:-1\n").is_err());
+    }
+
+    #[test]
+    fn member_paths_stay_inside_output() {
+        let output_dir = Path::new("out");
+        assert_eq!(
+            member_path(output_dir, "Series/Ch 01.cbz").unwrap(),
+            output_dir.join("Series/Ch 01.cbz")
+        );
+        assert!(member_path(output_dir, "../Ch 01.cbz").is_err());
+        assert!(member_path(output_dir, "/tmp/Ch 01.cbz").is_err());
+    }
+}
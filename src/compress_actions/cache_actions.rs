@@ -0,0 +1,252 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+/// Name of the cache file kept next to the optimised archives.
+pub const CACHE_FILE_NAME: &str = ".comics_archiver.cache";
+
+/// Hash the raw bytes of an archive.
+///
+/// * `cbz_file`: Path of the archive to hash.
+///
+/// Return `String` hex encoded blake3 hash.
+pub fn hash_archive<P: AsRef<Path>>(cbz_file: P) -> io::Result<String> {
+    let mut hasher = blake3::Hasher::new();
+    let mut file = BufReader::new(File::open(cbz_file.as_ref())?);
+    io::copy(&mut file, &mut hasher)?;
+    Ok(hasher.finalize().to_hex().to_string())
+}
+
+/// One line of the cache file.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct CacheEntry {
+    /// Hash of the source archive.
+    hash: String,
+    settings: String,
+    /// Hash of its unpacked entries, see `dedupe_actions::content_hash`.
+    content: String,
+    /// `None` for an archive with the same content as another one, it got no output.
+    output: Option<PathBuf>,
+    /// Size & hash of the optimised archive when it was written.
+    size: u64,
+    output_hash: String,
+}
+
+/// What the cache knows of an archive it has seen before.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CacheHit<'a> {
+    /// Optimised archive, `None` when the archive was skipped as a duplicate.
+    pub output: Option<&'a Path>,
+    /// Hash of the unpacked entries of the source archive.
+    pub content: &'a str,
+}
+
+/// Persistent cache of archives that were already optimised.
+///
+/// Entries are keyed by the hash of the source archive plus the pipeline
+/// settings used, so changing either one makes the archive miss the cache.
+/// Every entry is appended as a JSON line, so settings can hold any character.
+pub struct ContentCache {
+    file: File,
+    entries: HashMap<(String, String), CacheEntry>,
+}
+
+impl ContentCache {
+    /// Open the cache inside `output_dir`, creating it if needed.
+    ///
+    /// * `output_dir`: Directory the optimised archives are written to.
+    ///
+    /// Return `ContentCache` with the entries of previous runs loaded.
+    pub fn open<P: AsRef<Path>>(output_dir: P) -> io::Result<Self> {
+        let path = output_dir.as_ref().join(CACHE_FILE_NAME);
+        let mut entries = HashMap::new();
+        if path.exists() {
+            let reader = BufReader::new(File::open(&path)?);
+            for line in reader.lines() {
+                //NOTE: Torn lines & lines of older cache formats are just misses.
+                if let Ok(entry) = serde_json::from_str::<CacheEntry>(&line?) {
+                    entries.insert((entry.hash.clone(), entry.settings.clone()), entry);
+                }
+            }
+        }
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        Ok(Self { file, entries })
+    }
+
+    /// Find what became of an archive.
+    ///
+    /// * `input_hash`: Hash of the source archive from `hash_archive`.
+    /// * `settings`: Pipeline settings the output has to be made with.
+    ///
+    /// Return `Option<CacheHit>`, for an archive with an output only if that
+    /// is still on disk untouched. The output is hashed again to be sure of
+    /// that, a cheap size check goes first.
+    pub fn lookup(&self, input_hash: &str, settings: &str) -> Option<CacheHit<'_>> {
+        let entry = self
+            .entries
+            .get(&(input_hash.to_string(), settings.to_string()))?;
+        let hit = CacheHit {
+            output: entry.output.as_deref(),
+            content: &entry.content,
+        };
+        let Some(output) = hit.output else {
+            return Some(hit);
+        };
+        match output.metadata() {
+            Ok(meta) if meta.len() == entry.size => {}
+            _ => return None,
+        }
+        match hash_archive(output) {
+            Ok(output_hash) if output_hash == entry.output_hash => Some(hit),
+            _ => None,
+        }
+    }
+
+    /// Remember the optimised output of an archive and flush it to disk.
+    ///
+    /// * `input_hash`: Hash of the source archive from `hash_archive`.
+    /// * `settings`: Pipeline settings the output was made with.
+    /// * `content`: Hash of the unpacked entries of the source archive.
+    /// * `output`: Path of the optimised archive.
+    pub fn insert<P: AsRef<Path>>(
+        &mut self,
+        input_hash: &str,
+        settings: &str,
+        content: &str,
+        output: P,
+    ) -> io::Result<()> {
+        let output = output.as_ref();
+        self.append(CacheEntry {
+            hash: input_hash.to_string(),
+            settings: settings.to_string(),
+            content: content.to_string(),
+            output: Some(output.to_owned()),
+            size: output.metadata()?.len(),
+            output_hash: hash_archive(output)?,
+        })
+    }
+
+    /// Remember that an archive duplicated another one and flush it to disk.
+    ///
+    /// * `input_hash`: Hash of the source archive from `hash_archive`.
+    /// * `settings`: Pipeline settings of the run.
+    /// * `content`: Hash of the unpacked entries it shares with the other archive.
+    pub fn insert_duplicate(
+        &mut self,
+        input_hash: &str,
+        settings: &str,
+        content: &str,
+    ) -> io::Result<()> {
+        //NOTE: An identical archive shares the key, its output mustn't be forgotten.
+        let key = (input_hash.to_string(), settings.to_string());
+        if self
+            .entries
+            .get(&key)
+            .is_some_and(|entry| entry.output.is_some())
+        {
+            return Ok(());
+        }
+        self.append(CacheEntry {
+            hash: input_hash.to_string(),
+            settings: settings.to_string(),
+            content: content.to_string(),
+            output: None,
+            size: 0,
+            output_hash: String::new(),
+        })
+    }
+
+    /// Append `entry` as a JSON line & keep it.
+    fn append(&mut self, entry: CacheEntry) -> io::Result<()> {
+        let line = serde_json::to_string(&entry).map_err(io::Error::other)?;
+        writeln!(self.file, "{}", line)?;
+        self.file.sync_data()?;
+        self.entries
+            .insert((entry.hash.clone(), entry.settings.clone()), entry);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "comics_archiver_cache_{}_{}",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn settings_with_colons_round_trip() {
+        let dir = scratch_dir("colons");
+        let output = dir.join("Ch 01.cbz");
+        std::fs::write(&output, b"optimised").unwrap();
+        let settings = "my:stage,C:/stages/custom,deflate-9";
+        ContentCache::open(&dir)
+            .unwrap()
+            .insert("abc", settings, "pages", &output)
+            .unwrap();
+        let cache = ContentCache::open(&dir).unwrap();
+        assert_eq!(
+            cache.lookup("abc", settings),
+            Some(CacheHit {
+                output: Some(output.as_path()),
+                content: "pages"
+            })
+        );
+        assert_eq!(cache.lookup("abc", "my"), None);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn changed_output_misses() {
+        let dir = scratch_dir("changed");
+        let output = dir.join("Ch 01.cbz");
+        std::fs::write(&output, b"optimised").unwrap();
+        let mut cache = ContentCache::open(&dir).unwrap();
+        cache.insert("abc", "jpeg-90", "pages", &output).unwrap();
+        //NOTE: Same size, other content.
+        std::fs::write(&output, b"optimisex").unwrap();
+        assert_eq!(cache.lookup("abc", "jpeg-90"), None);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn duplicates_are_remembered() {
+        let dir = scratch_dir("duplicate");
+        ContentCache::open(&dir)
+            .unwrap()
+            .insert_duplicate("abc", "jpeg-90", "pages")
+            .unwrap();
+        let cache = ContentCache::open(&dir).unwrap();
+        assert_eq!(
+            cache.lookup("abc", "jpeg-90"),
+            Some(CacheHit {
+                output: None,
+                content: "pages"
+            })
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn torn_and_old_lines_are_skipped() {
+        let dir = scratch_dir("torn");
+        std::fs::write(
+            dir.join(CACHE_FILE_NAME),
+            "abc:jpeg-90:9:/tmp/old.cbz\n{\"hash\":\"abc\",\"sett",
+        )
+        .unwrap();
+        let cache = ContentCache::open(&dir).unwrap();
+        assert!(cache.entries.is_empty());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,62 +1,402 @@
+use crate::config_actions::PipelineConfig;
+use crate::encoding_actions::{zip_entry_name, zip_name_encoding, NameEncoding};
 use crate::err_impl::CompressionError;
-use image::codecs::jpeg::JpegEncoder;
-use image::{DynamicImage, GenericImageView, ImageFormat, ImageOutputFormat};
-use indicatif::ProgressBar;
-use liblzma::bufread::XzEncoder;
+use crate::stage_actions::{ImageChain, Page};
+use image::ImageOutputFormat;
 use liblzma::write::XzDecoder;
 use rayon::prelude::*;
+use std::collections::HashMap;
+use std::fs::File;
 use std::io::{self, Cursor, Read, Write};
-use std::path::{Path, PathBuf};
-use std::sync::Arc;
-use tokio::fs::File as AsyncFile;
-use tokio::io::{AsyncBufRead, AsyncRead, AsyncReadExt, AsyncSeekExt, BufReader as AsyncBufReader};
-use zip::{write::FileOptions, CompressionMethod, ZipArchive, ZipWriter};
+use std::path::{Component, Path, PathBuf};
+use zip::result::ZipError;
+use zip::{write::FileOptions, CompressionMethod, DateTime, ZipArchive, ZipWriter};
+
+/// Default jpeg quality, see `PipelineConfig`.
+pub const JPEG_QUALITY: u8 = 90;
+
+/// Default deflate level, see `PipelineConfig`.
+pub const DEFLATE_LEVEL: i32 = 9;
+
+/// Unpacked `.cbz` entries | (archive_name, file_data, file_path)
+pub type CbzEntries = Vec<(String, Vec<u8>, PathBuf)>;
+
+/// Permissions of repacked entries when the source has none, pages aren't executables.
+pub const DEFAULT_ENTRY_PERMISSIONS: u32 = 0o644;
+
+/// Entries from this size on are written as Zip64 large files.
+///
+/// Stays below 4 GiB since Deflate grows data that doesn't compress a bit.
+pub const LARGE_FILE_THRESHOLD: u64 = u32::MAX as u64 - u32::MAX as u64 / 64;
+
+/// Zip64 extra field id, the zip writer adds its own when needed.
+const ZIP64_EXTRA_FIELD_ID: u16 = 0x0001;
+
+/// Entry details carried over from the source archive on repack.
+#[derive(Debug, Clone, Default)]
+pub struct EntryMetadata {
+    /// Position of the entry in the source archive.
+    pub index: usize,
+    pub last_modified: Option<DateTime>,
+    pub unix_mode: Option<u32>,
+    /// Raw extra fields, like extended timestamps.
+    pub extra_data: Vec<u8>,
+}
+
+/// Archive details carried over from the source archive on repack.
+#[derive(Debug, Clone, Default)]
+pub struct ArchiveMetadata {
+    /// Archive comment, where ComicBookInfo keeps its metadata.
+    pub comment: Vec<u8>,
+    pub entries: HashMap<String, EntryMetadata>,
+}
+
+/// Read the timestamps, permissions, extra fields & comment of an archive.
+///
+/// Only the central directory is read, entry data is left alone.
+/// Entries are keyed by their decoded name.
+/// * `cbz_file`: `.cbz` archive to read.
+/// * `name_encoding`: How to decode entry names without the UTF-8 flag.
+pub fn read_archive_metadata<P: AsRef<Path>>(
+    cbz_file: P,
+    name_encoding: NameEncoding,
+) -> Result<ArchiveMetadata, CompressionError> {
+    let file = io::BufReader::new(File::open(cbz_file.as_ref())?);
+    let mut zip_file = ZipArchive::new(file)
+        .map_err(|err| CompressionError::from(err).in_archive(cbz_file.as_ref()))?;
+    let encoding = zip_name_encoding(&mut zip_file, name_encoding)
+        .map_err(|err| CompressionError::from(err).in_archive(cbz_file.as_ref()))?;
+    let mut metadata = ArchiveMetadata {
+        comment: zip_file.comment().to_vec(),
+        ..Default::default()
+    };
+    for idx in 0..zip_file.len() {
+        let inner_file = zip_file
+            .by_index_raw(idx)
+            .map_err(|err| CompressionError::from(err).in_archive(cbz_file.as_ref()))?;
+        metadata.entries.insert(
+            zip_entry_name(&inner_file, encoding),
+            EntryMetadata {
+                index: idx,
+                last_modified: Some(inner_file.last_modified()),
+                unix_mode: inner_file.unix_mode(),
+                extra_data: carried_extra_fields(inner_file.extra_data()),
+            },
+        );
+    }
+    Ok(metadata)
+}
+
+/// Keep the extra fields that can be written again as they are.
+///
+/// Zip64 fields are rebuilt by the writer, a malformed field list is dropped.
+fn carried_extra_fields(extra_data: &[u8]) -> Vec<u8> {
+    let mut carried = Vec::new();
+    let mut rest = extra_data;
+    while rest.len() >= 4 {
+        let id = u16::from_le_bytes([rest[0], rest[1]]);
+        let size = u16::from_le_bytes([rest[2], rest[3]]) as usize;
+        let Some(field) = rest.get(..4 + size) else {
+            return Vec::new();
+        };
+        if id != ZIP64_EXTRA_FIELD_ID {
+            carried.extend_from_slice(field);
+        }
+        rest = &rest[4 + size..];
+    }
+    if rest.is_empty() {
+        carried
+    } else {
+        Vec::new()
+    }
+}
+
+/// Permissions a repacked entry gets from the source ones.
+fn entry_permissions(mode: u32) -> u32 {
+    (mode & 0o666) | 0o600
+}
+
+/// Whether the entry can be copied raw & still come out like `write_entry` writes it.
+///
+/// A raw copy keeps the permissions of the source entry & drops its extra
+/// fields, so only entries with permissions `entry_permissions` keeps as
+/// they are & no extra fields to carry qualify.
+/// * `metadata`: Details of the entry in the source archive.
+pub fn can_copy_raw(metadata: &EntryMetadata) -> bool {
+    metadata.extra_data.is_empty()
+        && metadata
+            .unix_mode
+            .is_none_or(|mode| entry_permissions(mode) & 0o777 == mode & 0o777)
+}
+
+/// Start a new entry & write its data, with the source entry details if known.
+///
+/// Execute bits are dropped from the source permissions & the owner can
+/// always read & write, so extracted pages are never locked. Entries too big
+/// for a plain zip header are switched to Zip64 automatically, the writer
+/// takes care of the Zip64 end records for archives with many entries.
+fn write_entry<W: Write + io::Seek>(
+    zip_writer: &mut ZipWriter<W>,
+    name: &str,
+    data: &[u8],
+    mut options: FileOptions,
+    metadata: Option<&EntryMetadata>,
+) -> zip::result::ZipResult<()> {
+    let unix_mode = metadata.and_then(|metadata| metadata.unix_mode);
+    options =
+        options.unix_permissions(unix_mode.map_or(DEFAULT_ENTRY_PERMISSIONS, entry_permissions));
+    options = options.large_file(data.len() as u64 >= LARGE_FILE_THRESHOLD);
+    if let Some(last_modified) = metadata.and_then(|metadata| metadata.last_modified) {
+        options = options.last_modified_time(last_modified);
+    }
+    match metadata.filter(|metadata| !metadata.extra_data.is_empty()) {
+        Some(metadata) => {
+            zip_writer.start_file_with_extra_data(name, options)?;
+            zip_writer.write_all(&metadata.extra_data)?;
+            zip_writer.end_extra_data()?;
+        }
+        None => zip_writer.start_file(name, options)?,
+    }
+    zip_writer.write_all(data)?;
+    Ok(())
+}
 
 /// Extract files from `.cbz` archive.
 ///
-/// Extract files and attach them together with path
+/// Extract files and attach them together with path, blocks on the zip reads.
 /// * `cbz_file`: `.cbz file`
+/// * `name_encoding`: How to decode entry names without the UTF-8 flag.
+///
 /// Return `<Vec(Vec<u8>, PathBuf)>` | (file_data, file_path)
-pub async fn extract_dir_and_files_from_cbz<P1: AsRef<Path>>(
+pub fn read_dir_and_files_from_cbz<P1: AsRef<Path>>(
     cbz_file: P1,
-    //cbz_file: Arc<impl AsRef<Path> + Send + Sync>,
-) -> io::Result<Vec<(String, Vec<u8>, PathBuf)>> {
+    name_encoding: NameEncoding,
+) -> Result<CbzEntries, CompressionError> {
     let mut entries = Vec::new();
-    let file = match AsyncFile::open(cbz_file.as_ref()).await {
-        Ok(f) => f,
-        Err(e) => return Err(e),
-    };
-    let file = file.into_std().await;
-    let file = io::BufReader::new(file);
+    let file = io::BufReader::new(File::open(cbz_file.as_ref())?);
 
-    let mut zip_file = ZipArchive::new(file)?;
-    let pb = ProgressBar::new(zip_file.len() as u64);
-    println!("Unpacking cbz files...");
-    //TODO: Well need to print out the name of the archive am unpacking.
+    let mut zip_file = ZipArchive::new(file)
+        .map_err(|err| CompressionError::from(err).in_archive(cbz_file.as_ref()))?;
+    let encoding = zip_name_encoding(&mut zip_file, name_encoding)
+        .map_err(|err| CompressionError::from(err).in_archive(cbz_file.as_ref()))?;
+    //NOTE: Runs on the worker pool, a name that isn't UTF-8 mustn't take it down.
+    let archive_file_name = cbz_file
+        .as_ref()
+        .file_name()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .to_string();
 
     for idx in 0..zip_file.len() {
-        let mut inner_file = zip_file.by_index(idx)?;
-        let file_name = inner_file.name().to_owned();
-        let file_path = Path::new(&file_name);
-        if file_path.is_dir() {
+        let mut inner_file = zip_file
+            .by_index(idx)
+            .map_err(|err| CompressionError::from(err).in_archive(cbz_file.as_ref()))?;
+        if inner_file.is_dir() {
             continue;
         }
+        let file_name = zip_entry_name(&inner_file, encoding);
         let mut file_contents = Vec::new();
-        pb.inc(1);
-        inner_file.read_to_end(&mut file_contents)?;
-        let archive_file_name = cbz_file
+        //NOTE: A bad CRC only shows up here, as an I/O error.
+        inner_file.read_to_end(&mut file_contents).map_err(|err| {
+            CompressionError::from(ZipError::Io(err))
+                .in_archive(cbz_file.as_ref())
+                .at_entry(&file_name)
+        })?;
+        entries.push((
+            archive_file_name.clone(),
+            file_contents,
+            PathBuf::from(file_name),
+        ));
+    }
+    Ok(entries)
+}
+
+/// Extract files from `.cbz` archive without blocking the async runtime.
+///
+/// Runs `read_dir_and_files_from_cbz` on tokio's blocking pool.
+/// * `cbz_file`: `.cbz file`
+/// * `name_encoding`: How to decode entry names without the UTF-8 flag.
+///
+/// Return `<Vec(Vec<u8>, PathBuf)>` | (file_data, file_path)
+pub async fn extract_dir_and_files_from_cbz<P1: AsRef<Path>>(
+    cbz_file: P1,
+    name_encoding: NameEncoding,
+) -> Result<CbzEntries, CompressionError> {
+    let cbz_file = cbz_file.as_ref().to_owned();
+    tokio::task::spawn_blocking(move || read_dir_and_files_from_cbz(cbz_file, name_encoding))
+        .await
+        .map_err(io::Error::other)?
+}
+
+/// File extensions treated as comic pages.
+pub const IMAGE_EXTENSIONS: [&str; 6] = ["jpg", "jpeg", "png", "webp", "gif", "bmp"];
+
+/// Check if a file looks like a comic page by its extension.
+pub fn is_image_file<P: AsRef<Path>>(file_path: P) -> bool {
+    file_path
+        .as_ref()
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| IMAGE_EXTENSIONS.contains(&ext.to_ascii_lowercase().as_str()))
+}
+
+/// Read a folder of loose images as if it was an unpacked `.cbz`.
+///
+/// Only images directly inside the folder are picked up, sorted by name.
+/// * `image_dir`: Folder with the chapter pages.
+///
+/// Return `<Vec(String, Vec<u8>, PathBuf)>` | (archive_name, file_data, file_path)
+/// where archive_name is the folder name with a `.cbz` extension.
+pub fn read_dir_and_files_from_folder<P: AsRef<Path>>(image_dir: P) -> io::Result<CbzEntries> {
+    let archive_name = format!(
+        "{}.cbz",
+        image_dir
             .as_ref()
             .file_name()
-            .unwrap()
-            .to_str()
-            .unwrap()
-            .to_string();
-        entries.push((archive_file_name, file_contents, file_path.to_owned()));
+            .unwrap_or_default()
+            .to_string_lossy()
+    );
+    let mut pages = Vec::new();
+    for entry in std::fs::read_dir(image_dir.as_ref())? {
+        let entry = entry?;
+        if entry.file_type()?.is_file() && is_image_file(entry.path()) {
+            pages.push(entry.path());
+        }
+    }
+    pages.sort();
+
+    let mut entries = Vec::new();
+    for page in pages {
+        let file_contents = std::fs::read(&page)?;
+        let file_path = PathBuf::from(page.file_name().unwrap());
+        entries.push((archive_name.clone(), file_contents, file_path));
     }
-    pb.finish_with_message("Done unpacking cbz files!");
     Ok(entries)
 }
 
+/// Read the dimensions of an image from its header, without decoding it.
+pub fn image_dimensions(image_data: &[u8]) -> Option<(u32, u32)> {
+    image::io::Reader::new(Cursor::new(image_data))
+        .with_guessed_format()
+        .ok()?
+        .into_dimensions()
+        .ok()
+}
+
+/// Name of the codec an image is encoded with, like `jpeg` or `png`.
+pub fn image_codec(image_data: &[u8]) -> Option<String> {
+    image::guess_format(image_data)
+        .ok()
+        .map(|format| format!("{:?}", format).to_lowercase())
+}
+
+/// Format pages are converted to by `convert_pages`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PageFormat {
+    /// Leave pages as they are.
+    #[default]
+    Keep,
+    Jpeg,
+    Png,
+}
+
+impl PageFormat {
+    /// File extension pages of this format get, `None` for `Keep`.
+    pub fn extension(&self) -> Option<&'static str> {
+        match self {
+            PageFormat::Keep => None,
+            PageFormat::Jpeg => Some("jpg"),
+            PageFormat::Png => Some("png"),
+        }
+    }
+}
+
+/// Re-encode every page of an archive into `format`.
+///
+/// Pages get the extension of their new format, anything that isn't
+/// an image is left alone.
+/// * `file_contents`: Entries of a single archive.
+/// * `format`: Format to convert the pages to.
+/// * `config`: Settings like the jpeg quality.
+pub fn convert_pages(
+    file_contents: &mut CbzEntries,
+    format: PageFormat,
+    config: &PipelineConfig,
+) -> Result<(), CompressionError> {
+    let (output_format, extension) = match format {
+        PageFormat::Keep => return Ok(()),
+        PageFormat::Jpeg => (ImageOutputFormat::Jpeg(config.jpeg_quality), "jpg"),
+        PageFormat::Png => (ImageOutputFormat::Png, "png"),
+    };
+    file_contents
+        .par_iter_mut()
+        .filter(|entry| is_image_file(&entry.2))
+        .try_for_each(|entry| -> Result<(), CompressionError> {
+            let img = image::load_from_memory(&entry.1)
+                .map_err(|err| CompressionError::from(err).at_entry(entry.2.to_string_lossy()))?;
+            let mut converted = Vec::new();
+            img.write_to(&mut Cursor::new(&mut converted), output_format.clone())
+                .map_err(|err| CompressionError::from(err).at_entry(entry.2.to_string_lossy()))?;
+            entry.1 = converted;
+            entry.2.set_extension(extension);
+            Ok(())
+        })
+}
+
+/// Unpack every entry of a `.cbz` archive into a folder.
+///
+/// Entry names that would end up outside `output_dir` are refused.
+/// * `cbz_file`: `.cbz` archive to unpack.
+/// * `output_dir`: Folder the entries are written to.
+/// * `name_encoding`: How to decode entry names without the UTF-8 flag.
+///
+/// Return `Vec<PathBuf>` paths of the unpacked files.
+pub fn unpack_cbz_to_dir<P1: AsRef<Path>, P2: AsRef<Path>>(
+    cbz_file: P1,
+    output_dir: P2,
+    name_encoding: NameEncoding,
+) -> Result<Vec<PathBuf>, CompressionError> {
+    let cbz_file = cbz_file.as_ref();
+    let file = io::BufReader::new(File::open(cbz_file)?);
+    let mut zip_file =
+        ZipArchive::new(file).map_err(|err| CompressionError::from(err).in_archive(cbz_file))?;
+    let encoding = zip_name_encoding(&mut zip_file, name_encoding)
+        .map_err(|err| CompressionError::from(err).in_archive(cbz_file))?;
+    let mut written = Vec::new();
+    for idx in 0..zip_file.len() {
+        let mut inner_file = zip_file
+            .by_index(idx)
+            .map_err(|err| CompressionError::from(err).in_archive(cbz_file))?;
+        let file_name = zip_entry_name(&inner_file, encoding);
+        if inner_file.is_dir() {
+            continue;
+        }
+        let relative = Path::new(&file_name);
+        if !relative
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)))
+        {
+            return Err(
+                CompressionError::container(format!("Unsafe entry name {:?}", file_name))
+                    .in_archive(cbz_file),
+            );
+        }
+        let target = output_dir.as_ref().join(relative);
+        if let Some(parent) = target.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let mut out = File::create(&target)?;
+        //NOTE: A bad CRC only shows up here, as an I/O error.
+        io::copy(&mut inner_file, &mut out).map_err(|err| {
+            CompressionError::from(ZipError::Io(err))
+                .in_archive(cbz_file)
+                .at_entry(&file_name)
+        })?;
+        written.push(target);
+    }
+    Ok(written)
+}
+
 // NOTE: This does not work well for images.
 // liblzma doesnt work well for image compression.
 pub fn compress_images_with_lzma(image_data: Vec<u8>) -> io::Result<Vec<u8>> {
@@ -72,18 +412,16 @@ pub fn compress_images_with_lzma(image_data: Vec<u8>) -> io::Result<Vec<u8>> {
 
 /// Compress Image with `image` crate.
 ///
-/// Compress image into a new file with 90% quality on Jpeg format.
-/// * `image_data` - Vec<u8> image data.
+/// Run the page through every stage of `chain`, like resize then encode.
+/// * `page` - Page with its source bytes & where it comes from.
+/// * `chain` - Stages the page goes through, see `ImageChain::from_config`.
+///
 /// Return `Vec<u8>` compressed image data
-pub fn compress_images_with_img(image_data: Vec<u8>) -> Result<Vec<u8>, CompressionError> {
-    let mut compressed_data = Vec::new();
-    let img = image::load_from_memory(&image_data).expect("Failed to load image!");
-    img.write_to(
-        &mut Cursor::new(&mut compressed_data),
-        ImageOutputFormat::Jpeg(90),
-    )
-    .expect("Failed to compress image!");
-    Ok(compressed_data)
+pub fn compress_images_with_img(
+    page: Page,
+    chain: &ImageChain,
+) -> Result<Vec<u8>, CompressionError> {
+    chain.run(page)
 }
 
 //NOTE: Will probably remove this later.
@@ -133,21 +471,22 @@ pub async fn decompress_images_with_lzma(image_data: Vec<u8>) -> io::Result<Vec<
 /// Compress directory and files to `.cbz` archive.
 ///
 /// * `file_contents`: `Vec<(Vec<u8>, PathBuf)>`
-/// file_contents = (file_data, file_path)
+///   file_contents = (file_data, file_path)
+/// * `metadata`: Timestamps, permissions & comment of the source archive.
+/// * `config`: Settings like the deflate level.
+///
 /// Return `Vec<u8>>` zip archive.
 pub fn compress_dir_and_files_to_cbz(
     file_contents: Vec<(String, Vec<u8>, PathBuf)>,
-) -> io::Result<(String, Vec<u8>)> {
+    metadata: &ArchiveMetadata,
+    config: &PipelineConfig,
+) -> Result<(String, Vec<u8>), CompressionError> {
     let mut zip_buffer = Vec::new();
     let mut archive_name: String = String::new();
-    let mut vec_length: usize = 0;
     if let Some(file_name) = file_contents.first() {
-        vec_length = file_name.1.len();
         archive_name = file_name.0.to_string();
     }
 
-    let pb = ProgressBar::new(vec_length as u64);
-    println!("Repacking files to cbz format...");
     /*
         let repacked_cbz = match std::fs::File::create(&file_contents[0].0) {
             Ok(f) => f,
@@ -164,15 +503,27 @@ pub fn compress_dir_and_files_to_cbz(
         //let mut zip_writer = ZipWriter::new(&repacked_cbz);
         let options = FileOptions::default()
             .compression_method(CompressionMethod::Deflated)
-            .compression_level(Some(9))
-            .unix_permissions(0o755);
+            .compression_level(Some(config.deflate_level));
+        zip_writer.set_raw_comment(metadata.comment.clone());
         for file_path in &file_contents {
-            zip_writer
-                .start_file(file_path.2.to_owned().to_string_lossy(), options)
-                .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
-            let _ = zip_writer.write_all(&file_path.1);
-            pb.inc(1);
+            let name = file_path.2.to_string_lossy();
+            write_entry(
+                &mut zip_writer,
+                &name,
+                &file_path.1,
+                options,
+                metadata.entries.get(name.as_ref()),
+            )
+            .map_err(|err| {
+                CompressionError::from(err)
+                    .in_archive(&archive_name)
+                    .at_entry(&name)
+            })?;
         }
+        //NOTE: Writes the central directory & Zip64 end records, dropping the writer would hide a failure.
+        zip_writer
+            .finish()
+            .map_err(|err| CompressionError::from(err).in_archive(&archive_name))?;
     }
     //NOTE: Adds another layer of compression not necessary.
     // NOTE: Will probably remove this later.
@@ -184,6 +535,340 @@ pub fn compress_dir_and_files_to_cbz(
         }
     */
     //zip_buffer.push(repacked_cbz);
-    pb.finish_with_message("Done repacking archives!");
     Ok((archive_name.to_string(), zip_buffer))
 }
+
+/// Repack an archive without recompressing what doesn't need it.
+///
+/// Images are already compressed, so they're written `Stored` instead of
+/// going through Deflate again. Entries that weren't modified at all are
+/// copied over as raw compressed bytes from the source archive, under
+/// their decoded name so it gets the UTF-8 flag. Only entries `can_copy_raw`
+/// allows are, the others are written like modified ones so every entry
+/// ends up with the same permissions & extra fields.
+/// * `source_cbz`: Archive the entries were extracted from, `None` writes every entry anew.
+/// * `file_contents`: Entries to write, in order.
+/// * `modified`: Per entry, whether its data changed since it was extracted.
+/// * `metadata`: Timestamps, permissions & comment of the source archive.
+/// * `config`: Settings like the deflate level.
+///
+/// Return `(String, Vec<u8>)` | (archive_name, zip archive)
+pub fn repack_cbz_passthrough<P: AsRef<Path>>(
+    source_cbz: Option<P>,
+    file_contents: &CbzEntries,
+    modified: &[bool],
+    metadata: &ArchiveMetadata,
+    config: &PipelineConfig,
+) -> Result<(String, Vec<u8>), CompressionError> {
+    let archive_name = file_contents
+        .first()
+        .map(|entry| entry.0.to_string())
+        .unwrap_or_default();
+    let mut zip_source = match &source_cbz {
+        Some(source_cbz) if modified.iter().any(|changed| !changed) => {
+            let file = io::BufReader::new(File::open(source_cbz.as_ref())?);
+            Some(
+                ZipArchive::new(file)
+                    .map_err(|err| CompressionError::from(err).in_archive(source_cbz))?,
+            )
+        }
+        _ => None,
+    };
+
+    let mut zip_buffer = Vec::new();
+    {
+        let mut zip_writer = ZipWriter::new(Cursor::new(&mut zip_buffer));
+        zip_writer.set_raw_comment(metadata.comment.clone());
+        for (file_path, changed) in file_contents.iter().zip(modified) {
+            let name = file_path.2.to_string_lossy();
+            let with_context = |err: ZipError| {
+                CompressionError::from(err)
+                    .in_archive(&archive_name)
+                    .at_entry(&name)
+            };
+            let entry_metadata = metadata.entries.get(name.as_ref());
+            if let (false, Some(zip_source), Some(entry_metadata)) = (
+                changed,
+                zip_source.as_mut(),
+                entry_metadata.filter(|entry| can_copy_raw(entry)),
+            ) {
+                let inner_file = zip_source
+                    .by_index_raw(entry_metadata.index)
+                    .map_err(with_context)?;
+                zip_writer
+                    .raw_copy_file_rename(inner_file, name.as_ref())
+                    .map_err(with_context)?;
+                continue;
+            }
+            let options = if is_image_file(&file_path.2) {
+                FileOptions::default().compression_method(CompressionMethod::Stored)
+            } else {
+                FileOptions::default()
+                    .compression_method(CompressionMethod::Deflated)
+                    .compression_level(Some(config.deflate_level))
+            };
+            write_entry(
+                &mut zip_writer,
+                &name,
+                &file_path.1,
+                options,
+                entry_metadata,
+            )
+            .map_err(with_context)?;
+        }
+        zip_writer
+            .finish()
+            .map_err(|err| CompressionError::from(err).in_archive(&archive_name))?;
+    }
+    Ok((archive_name, zip_buffer))
+}
+
+/// Check a freshly written `.cbz` archive.
+///
+/// Reopen the archive and make sure every entry can be read back.
+/// * `cbz_file`: Path of the written archive.
+/// * `expected_entries`: Number of entries that were packed into it.
+///
+/// Return `bool` whether the archive is readable and complete.
+pub fn verify_written_cbz<P: AsRef<Path>>(
+    cbz_file: P,
+    expected_entries: usize,
+) -> io::Result<bool> {
+    let file = io::BufReader::new(File::open(cbz_file.as_ref())?);
+    let mut zip_file = match ZipArchive::new(file) {
+        Ok(zip_file) => zip_file,
+        Err(_) => return Ok(false),
+    };
+    if zip_file.len() != expected_entries {
+        return Ok(false);
+    }
+    for idx in 0..zip_file.len() {
+        let mut inner_file = match zip_file.by_index(idx) {
+            Ok(inner_file) => inner_file,
+            Err(_) => return Ok(false),
+        };
+        //NOTE: Reading to the end makes the zip crate check the CRC.
+        if io::copy(&mut inner_file, &mut io::sink()).is_err() {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+/// Entries a repacked archive should end up with | (entry_name, page_dimensions)
+pub type PageManifest = Vec<(String, Option<(u32, u32)>)>;
+
+/// Note down what a repacked archive should look like.
+///
+/// Has to be taken before the pages are compressed, the dimensions
+/// are the ones of the source pages once through `chain`. Pages a stage
+/// like `crop` gives unknown dimensions aren't decoded by the check.
+/// * `file_contents`: Entries about to be repacked.
+/// * `chain`: Stages the pages go through.
+///
+/// Return `PageManifest` in the order the entries get written.
+pub fn page_manifest(file_contents: &CbzEntries, chain: &ImageChain) -> PageManifest {
+    file_contents
+        .iter()
+        .map(|entry| {
+            let dimensions = if is_image_file(&entry.2) {
+                image_dimensions(&entry.1)
+                    .and_then(|(width, height)| chain.dimensions(width, height))
+            } else {
+                None
+            };
+            (entry.2.to_string_lossy().to_string(), dimensions)
+        })
+        .collect()
+}
+
+/// Round-trip check of a freshly written `.cbz` archive.
+///
+/// Reopens the archive, makes sure the entries are the intended ones
+/// in the intended order, then decodes every page and compares its
+/// dimensions with the source page.
+/// * `cbz_file`: Path of the written archive.
+/// * `manifest`: What the archive should hold, from `page_manifest`.
+pub fn round_trip_cbz<P: AsRef<Path>>(
+    cbz_file: P,
+    manifest: &PageManifest,
+) -> Result<(), CompressionError> {
+    let cbz_file = cbz_file.as_ref();
+    let file = io::BufReader::new(File::open(cbz_file)?);
+    let mut zip_file =
+        ZipArchive::new(file).map_err(|err| CompressionError::from(err).in_archive(cbz_file))?;
+    if zip_file.len() != manifest.len() {
+        return Err(CompressionError::container(format!(
+            "Expected {} entries, found {}",
+            manifest.len(),
+            zip_file.len()
+        ))
+        .in_archive(cbz_file));
+    }
+
+    for (idx, (name, dimensions)) in manifest.iter().enumerate() {
+        let mut inner_file = zip_file.by_index(idx).map_err(|err| {
+            CompressionError::from(err)
+                .in_archive(cbz_file)
+                .at_entry(name)
+        })?;
+        if inner_file.name() != name {
+            return Err(CompressionError::container(format!(
+                "Entry {} is {}, expected {}",
+                idx,
+                inner_file.name(),
+                name
+            ))
+            .in_archive(cbz_file));
+        }
+        let mut file_contents = Vec::new();
+        inner_file.read_to_end(&mut file_contents).map_err(|err| {
+            CompressionError::from(ZipError::Io(err))
+                .in_archive(cbz_file)
+                .at_entry(name)
+        })?;
+
+        if let Some((width, height)) = dimensions {
+            let img = image::load_from_memory(&file_contents).map_err(|err| {
+                CompressionError::from(err)
+                    .in_archive(cbz_file)
+                    .at_entry(name)
+            })?;
+            if (img.width(), img.height()) != (*width, *height) {
+                return Err(CompressionError::container(format!(
+                    "{} is {}x{}, source page was {}x{}",
+                    name,
+                    img.width(),
+                    img.height(),
+                    width,
+                    height
+                ))
+                .in_archive(cbz_file));
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn extra_field(id: u16, data: &[u8]) -> Vec<u8> {
+        let mut field = id.to_le_bytes().to_vec();
+        field.extend_from_slice(&(data.len() as u16).to_le_bytes());
+        field.extend_from_slice(data);
+        field
+    }
+
+    #[test]
+    fn extra_fields_drop_zip64_only() {
+        let timestamp = extra_field(0x5455, &[1, 0, 0, 0, 0]);
+        let unix = extra_field(0x7875, &[1, 4, 0, 0, 0, 0, 4, 0, 0, 0, 0]);
+        let zip64 = extra_field(ZIP64_EXTRA_FIELD_ID, &[0; 16]);
+        let extra = [timestamp.clone(), zip64, unix.clone()].concat();
+        assert_eq!(carried_extra_fields(&extra), [timestamp, unix].concat());
+        assert!(carried_extra_fields(&[]).is_empty());
+    }
+
+    #[test]
+    fn malformed_extra_fields_are_dropped() {
+        let mut extra = extra_field(0x5455, &[1, 0, 0, 0, 0]);
+        //NOTE: Says 9 bytes follow, only 2 do.
+        extra.extend_from_slice(&[0x75, 0x78, 9, 0, 1, 2]);
+        assert!(carried_extra_fields(&extra).is_empty());
+        assert!(carried_extra_fields(&[0x55, 0x54, 1]).is_empty());
+    }
+
+    #[test]
+    fn entry_permissions_stay_readable() {
+        assert_eq!(entry_permissions(0o100755), 0o644);
+        assert_eq!(entry_permissions(0o000), 0o600);
+        assert_eq!(entry_permissions(0o200), 0o600);
+        assert_eq!(entry_permissions(0o444), 0o644);
+    }
+
+    #[test]
+    fn passthrough_entries_get_the_same_permissions_and_extra_fields() {
+        let dir = std::env::temp_dir().join(format!(
+            "comics_archiver_cbz_passthrough_{}",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let cbz_file = dir.join("Ch1.cbz");
+        let timestamp = extra_field(0x5455, &[1, 0, 0, 0, 0]);
+        let mut zip_writer = ZipWriter::new(File::create(&cbz_file).unwrap());
+        for (name, mode, extra) in [
+            ("executable.txt", 0o755, None),
+            ("plain.txt", 0o644, None),
+            ("stamped.txt", 0o644, Some(&timestamp)),
+        ] {
+            let options = FileOptions::default().unix_permissions(mode);
+            match extra {
+                Some(extra) => {
+                    zip_writer
+                        .start_file_with_extra_data(name, options)
+                        .unwrap();
+                    zip_writer.write_all(extra).unwrap();
+                    zip_writer.end_extra_data().unwrap();
+                }
+                None => zip_writer.start_file(name, options).unwrap(),
+            }
+            zip_writer.write_all(name.as_bytes()).unwrap();
+        }
+        zip_writer.finish().unwrap();
+
+        let metadata = read_archive_metadata(&cbz_file, NameEncoding::Auto).unwrap();
+        assert!(!can_copy_raw(&metadata.entries["executable.txt"]));
+        assert!(can_copy_raw(&metadata.entries["plain.txt"]));
+        assert!(!can_copy_raw(&metadata.entries["stamped.txt"]));
+        let entries = read_dir_and_files_from_cbz(&cbz_file, NameEncoding::Auto).unwrap();
+        let modified = vec![false; entries.len()];
+        let (_, repacked) = repack_cbz_passthrough(
+            Some(&cbz_file),
+            &entries,
+            &modified,
+            &metadata,
+            &PipelineConfig::default(),
+        )
+        .unwrap();
+
+        let mut zip_file = ZipArchive::new(Cursor::new(repacked)).unwrap();
+        for idx in 0..zip_file.len() {
+            let mut entry = zip_file.by_index(idx).unwrap();
+            assert_eq!(entry.unix_mode().map(|mode| mode & 0o777), Some(0o644));
+            let extra = carried_extra_fields(entry.extra_data());
+            let mut data = String::new();
+            entry.read_to_string(&mut data).unwrap();
+            assert_eq!(data, entry.name());
+            match entry.name() {
+                "stamped.txt" => assert_eq!(extra, timestamp),
+                _ => assert!(extra.is_empty()),
+            }
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn non_utf8_archive_names_are_read() {
+        use std::os::unix::ffi::OsStrExt;
+        let dir =
+            std::env::temp_dir().join(format!("comics_archiver_cbz_names_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let cbz_file = dir.join(std::ffi::OsStr::from_bytes(b"Ch \xff01.cbz"));
+        let mut zip_writer = ZipWriter::new(File::create(&cbz_file).unwrap());
+        zip_writer
+            .start_file("001.png", FileOptions::default())
+            .unwrap();
+        zip_writer.write_all(b"page").unwrap();
+        zip_writer.finish().unwrap();
+
+        let entries = read_dir_and_files_from_cbz(&cbz_file, NameEncoding::Auto).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].0, "Ch \u{fffd}01.cbz");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
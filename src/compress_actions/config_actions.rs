@@ -0,0 +1,560 @@
+use crate::bundle_actions::{BUNDLE_LEVEL, DICT_SIZE_RANGE};
+use crate::cbz_actions::{DEFLATE_LEVEL, JPEG_QUALITY};
+use crate::err_impl::CompressionError;
+use crate::stage_actions::{Resize, StageRegistry, DEFAULT_STAGES};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// Name of the project configuration file, looked up in the current directory.
+pub const PROJECT_CONFIG_NAME: &str = "comics_archiver.toml";
+
+/// Prefix of the environment variables that override the configuration,
+/// like `COMICS_ARCHIVER_JPEG_QUALITY`.
+pub const ENV_PREFIX: &str = "COMICS_ARCHIVER_";
+
+/// Profile used when none is picked anywhere.
+pub const DEFAULT_PROFILE: &str = "archive";
+
+/// Profiles that exist without any configuration file.
+pub const BUILTIN_PROFILES: [&str; 3] = ["archive", "ereader", "lossless"];
+
+/// Codec pages are written with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PageCodec {
+    #[default]
+    Jpeg,
+    Png,
+    /// Keep pages in the codec they came in, only re-encode them to resize.
+    Keep,
+}
+
+impl FromStr for PageCodec {
+    type Err = String;
+
+    fn from_str(codec: &str) -> Result<Self, Self::Err> {
+        match codec.to_ascii_lowercase().as_str() {
+            "jpeg" | "jpg" => Ok(PageCodec::Jpeg),
+            "png" => Ok(PageCodec::Png),
+            "keep" => Ok(PageCodec::Keep),
+            _ => Err(format!("Unknown codec {}, use jpeg, png or keep", codec)),
+        }
+    }
+}
+
+impl fmt::Display for PageCodec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PageCodec::Jpeg => write!(f, "jpeg"),
+            PageCodec::Png => write!(f, "png"),
+            PageCodec::Keep => write!(f, "keep"),
+        }
+    }
+}
+
+/// Settings of one configuration layer, anything left `None` falls through
+/// to the layer below.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ProfileSettings {
+    pub codec: Option<PageCodec>,
+    pub jpeg_quality: Option<u8>,
+    /// Pages wider than this are scaled down, keeping their aspect ratio.
+    pub max_width: Option<u32>,
+    /// Pages taller than this are scaled down, keeping their aspect ratio.
+    pub max_height: Option<u32>,
+    pub deflate_level: Option<i32>,
+    pub xz_level: Option<u32>,
+    /// Threads writing bundles, 0 uses every core.
+    pub xz_threads: Option<u32>,
+    /// Uncompressed bytes per xz block of a bundle, 0 lets liblzma pick.
+    pub xz_block_size: Option<u64>,
+    /// xz dictionary of a bundle in bytes, 0 keeps the one of `xz_level`.
+    pub xz_dict_size: Option<u32>,
+    /// Stages pages go through, in order, like `["grayscale", "resize", "encode"]`.
+    pub stages: Option<Vec<String>>,
+    /// How far from the background a pixel has to be for `crop` to keep it.
+    pub crop_tolerance: Option<u8>,
+    pub sharpen_sigma: Option<f32>,
+    pub sharpen_threshold: Option<i32>,
+}
+
+impl ProfileSettings {
+    /// Take every setting `other` has over the ones of `self`.
+    pub fn merge(&mut self, other: &ProfileSettings) {
+        self.codec = other.codec.or(self.codec);
+        self.jpeg_quality = other.jpeg_quality.or(self.jpeg_quality);
+        self.max_width = other.max_width.or(self.max_width);
+        self.max_height = other.max_height.or(self.max_height);
+        self.deflate_level = other.deflate_level.or(self.deflate_level);
+        self.xz_level = other.xz_level.or(self.xz_level);
+        self.xz_threads = other.xz_threads.or(self.xz_threads);
+        self.xz_block_size = other.xz_block_size.or(self.xz_block_size);
+        self.xz_dict_size = other.xz_dict_size.or(self.xz_dict_size);
+        self.stages = other.stages.clone().or(self.stages.take());
+        self.crop_tolerance = other.crop_tolerance.or(self.crop_tolerance);
+        self.sharpen_sigma = other.sharpen_sigma.or(self.sharpen_sigma);
+        self.sharpen_threshold = other.sharpen_threshold.or(self.sharpen_threshold);
+    }
+
+    /// Settings of a builtin profile, `None` if there is no such profile.
+    pub fn builtin(profile: &str) -> Option<Self> {
+        match profile {
+            "archive" => Some(Self::default()),
+            //NOTE: Fits the 6" & 7" e-ink readers, they can't show more than that anyway.
+            "ereader" => Some(Self {
+                jpeg_quality: Some(80),
+                max_width: Some(1264),
+                max_height: Some(1680),
+                stages: Some(stage_list("grayscale,resize,sharpen,encode")),
+                ..Default::default()
+            }),
+            "lossless" => Some(Self {
+                codec: Some(PageCodec::Keep),
+                ..Default::default()
+            }),
+            _ => None,
+        }
+    }
+
+    /// Read the overrides from `COMICS_ARCHIVER_*` environment variables.
+    pub fn from_env() -> Result<Self, CompressionError> {
+        Ok(Self {
+            codec: env_setting("CODEC")?,
+            jpeg_quality: env_setting("JPEG_QUALITY")?,
+            max_width: env_setting("MAX_WIDTH")?,
+            max_height: env_setting("MAX_HEIGHT")?,
+            deflate_level: env_setting("DEFLATE_LEVEL")?,
+            xz_level: env_setting("XZ_LEVEL")?,
+            xz_threads: env_setting("XZ_THREADS")?,
+            xz_block_size: env_setting("XZ_BLOCK_SIZE")?,
+            xz_dict_size: env_setting("XZ_DICT_SIZE")?,
+            stages: env_setting::<String>("STAGES")?.map(|stages| stage_list(&stages)),
+            crop_tolerance: env_setting("CROP_TOLERANCE")?,
+            sharpen_sigma: env_setting("SHARPEN_SIGMA")?,
+            sharpen_threshold: env_setting("SHARPEN_THRESHOLD")?,
+        })
+    }
+}
+
+/// Split a comma separated list of stage names, like `grayscale,resize,encode`.
+pub fn stage_list(stages: &str) -> Vec<String> {
+    stages
+        .split(',')
+        .map(str::trim)
+        .filter(|stage| !stage.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+/// Parse the `COMICS_ARCHIVER_<name>` environment variable, if it's set.
+fn env_setting<T: FromStr>(name: &str) -> Result<Option<T>, CompressionError>
+where
+    T::Err: fmt::Display,
+{
+    let var = format!("{}{}", ENV_PREFIX, name);
+    match std::env::var(&var) {
+        Ok(value) => value
+            .trim()
+            .parse()
+            .map(Some)
+            .map_err(|err| CompressionError::config(format!("{} = {:?}: {}", var, value, err))),
+        Err(_) => Ok(None),
+    }
+}
+
+/// Layout of a configuration file.
+///
+/// ```toml
+/// profile = "ereader"
+///
+/// [defaults]
+/// deflate_level = 6
+///
+/// [profiles.ereader]
+/// max_width = 1072
+/// max_height = 1448
+/// stages = ["grayscale", "crop", "resize", "sharpen", "encode"]
+/// ```
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigFile {
+    /// Profile to use when none is picked on the command line.
+    pub profile: Option<String>,
+    /// Settings applied under every profile.
+    #[serde(default)]
+    pub defaults: ProfileSettings,
+    /// New profiles, or changes to the builtin ones.
+    #[serde(default)]
+    pub profiles: HashMap<String, ProfileSettings>,
+}
+
+impl ConfigFile {
+    /// Read a configuration file.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, CompressionError> {
+        let path = path.as_ref();
+        let contents = std::fs::read_to_string(path)?;
+        toml::from_str(&contents)
+            .map_err(|err| CompressionError::config(err.message()).in_config(path))
+    }
+}
+
+/// User configuration file, `~/.config/comics_archiver/config.toml` on Linux.
+pub fn user_config_path() -> Option<PathBuf> {
+    let config_dir = std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("APPDATA").map(PathBuf::from))
+        .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".config")))?;
+    Some(config_dir.join("comics_archiver").join("config.toml"))
+}
+
+/// Settings the pipeline runs with, once every layer is applied.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PipelineConfig {
+    pub profile: String,
+    pub codec: PageCodec,
+    pub jpeg_quality: u8,
+    pub max_width: Option<u32>,
+    pub max_height: Option<u32>,
+    pub deflate_level: i32,
+    pub xz_level: u32,
+    pub xz_threads: u32,
+    pub xz_block_size: u64,
+    pub xz_dict_size: u32,
+    /// Names of the stages pages go through, see `StageRegistry`.
+    pub stages: Vec<String>,
+    pub crop_tolerance: u8,
+    pub sharpen_sigma: f32,
+    pub sharpen_threshold: i32,
+}
+
+impl Default for PipelineConfig {
+    fn default() -> Self {
+        Self {
+            profile: DEFAULT_PROFILE.to_string(),
+            codec: PageCodec::Jpeg,
+            jpeg_quality: JPEG_QUALITY,
+            max_width: None,
+            max_height: None,
+            deflate_level: DEFLATE_LEVEL,
+            xz_level: BUNDLE_LEVEL,
+            xz_threads: 0,
+            xz_block_size: 0,
+            xz_dict_size: 0,
+            stages: DEFAULT_STAGES
+                .iter()
+                .map(|stage| stage.to_string())
+                .collect(),
+            crop_tolerance: 16,
+            sharpen_sigma: 1.0,
+            sharpen_threshold: 4,
+        }
+    }
+}
+
+impl PipelineConfig {
+    /// Load the configuration every command runs with.
+    ///
+    /// Layers from lowest to highest: builtin profile, user file, project file,
+    /// `COMICS_ARCHIVER_*` environment variables, then `overrides` from the CLI.
+    /// Within a file `[defaults]` comes before the picked `[profiles.<name>]`.
+    /// * `profile`: Profile picked on the command line, if any.
+    /// * `overrides`: Settings given on the command line.
+    ///
+    /// Return `PipelineConfig`
+    pub fn load(
+        profile: Option<&str>,
+        overrides: &ProfileSettings,
+    ) -> Result<Self, CompressionError> {
+        let mut files = Vec::new();
+        let project_path = PathBuf::from(PROJECT_CONFIG_NAME);
+        for path in user_config_path().into_iter().chain([project_path]) {
+            if path.is_file() {
+                files.push(ConfigFile::load(&path)?);
+            }
+        }
+        let env_profile = std::env::var(format!("{}PROFILE", ENV_PREFIX)).ok();
+        let profile = profile
+            .map(str::to_string)
+            .or(env_profile)
+            .or_else(|| files.iter().rev().find_map(|file| file.profile.clone()))
+            .unwrap_or_else(|| DEFAULT_PROFILE.to_string());
+        Self::from_layers(&profile, &files, &ProfileSettings::from_env()?, overrides)
+    }
+
+    /// Apply configuration layers on top of each other.
+    ///
+    /// * `profile`: Profile to use.
+    /// * `files`: Configuration files, lowest priority first.
+    /// * `env`: Settings from the environment.
+    /// * `overrides`: Settings from the command line.
+    ///
+    /// Return `PipelineConfig`
+    pub fn from_layers(
+        profile: &str,
+        files: &[ConfigFile],
+        env: &ProfileSettings,
+        overrides: &ProfileSettings,
+    ) -> Result<Self, CompressionError> {
+        let builtin = ProfileSettings::builtin(profile);
+        if builtin.is_none() && !files.iter().any(|file| file.profiles.contains_key(profile)) {
+            return Err(CompressionError::config(format!(
+                "Unknown profile {}, the builtin ones are {}",
+                profile,
+                BUILTIN_PROFILES.join(", ")
+            )));
+        }
+        let mut settings = builtin.unwrap_or_default();
+        for file in files {
+            settings.merge(&file.defaults);
+            if let Some(profile_settings) = file.profiles.get(profile) {
+                settings.merge(profile_settings);
+            }
+        }
+        settings.merge(env);
+        settings.merge(overrides);
+
+        let defaults = Self::default();
+        let config = Self {
+            profile: profile.to_string(),
+            codec: settings.codec.unwrap_or(defaults.codec),
+            jpeg_quality: settings.jpeg_quality.unwrap_or(defaults.jpeg_quality),
+            max_width: settings.max_width,
+            max_height: settings.max_height,
+            deflate_level: settings.deflate_level.unwrap_or(defaults.deflate_level),
+            xz_level: settings.xz_level.unwrap_or(defaults.xz_level),
+            xz_threads: settings.xz_threads.unwrap_or(defaults.xz_threads),
+            xz_block_size: settings.xz_block_size.unwrap_or(defaults.xz_block_size),
+            xz_dict_size: settings.xz_dict_size.unwrap_or(defaults.xz_dict_size),
+            stages: settings.stages.unwrap_or(defaults.stages),
+            crop_tolerance: settings.crop_tolerance.unwrap_or(defaults.crop_tolerance),
+            sharpen_sigma: settings.sharpen_sigma.unwrap_or(defaults.sharpen_sigma),
+            sharpen_threshold: settings
+                .sharpen_threshold
+                .unwrap_or(defaults.sharpen_threshold),
+        };
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Check every setting is in the range its encoder accepts.
+    fn validate(&self) -> Result<(), CompressionError> {
+        if !(1..=100).contains(&self.jpeg_quality) {
+            return Err(CompressionError::config(format!(
+                "jpeg_quality {} is not in 1..=100",
+                self.jpeg_quality
+            )));
+        }
+        if !(0..=9).contains(&self.deflate_level) {
+            return Err(CompressionError::config(format!(
+                "deflate_level {} is not in 0..=9",
+                self.deflate_level
+            )));
+        }
+        if self.xz_level > 9 {
+            return Err(CompressionError::config(format!(
+                "xz_level {} is not in 0..=9",
+                self.xz_level
+            )));
+        }
+        if self.xz_dict_size != 0 && !DICT_SIZE_RANGE.contains(&self.xz_dict_size) {
+            return Err(CompressionError::config(format!(
+                "xz_dict_size {} is not 0 or in {}..={}",
+                self.xz_dict_size,
+                DICT_SIZE_RANGE.start(),
+                DICT_SIZE_RANGE.end()
+            )));
+        }
+        if self.max_width == Some(0) || self.max_height == Some(0) {
+            return Err(CompressionError::config(
+                "max_width & max_height can't be 0",
+            ));
+        }
+        if !(self.sharpen_sigma.is_finite() && self.sharpen_sigma > 0.0) {
+            return Err(CompressionError::config(format!(
+                "sharpen_sigma {} has to be above 0",
+                self.sharpen_sigma
+            )));
+        }
+        Ok(())
+    }
+
+    /// Dimensions a page of `width`x`height` ends up with once resized.
+    ///
+    /// Pages are only ever scaled down, into the `max_width`x`max_height` box.
+    pub fn fit_dimensions(&self, width: u32, height: u32) -> (u32, u32) {
+        Resize {
+            max_width: self.max_width,
+            max_height: self.max_height,
+        }
+        .fit(width, height)
+    }
+
+    /// Settings of the stages pages go through, like `ImageChain::settings`.
+    ///
+    /// Stages that aren't builtin are only known by their name here.
+    pub fn page_settings(&self) -> String {
+        let registry = StageRegistry::builtin();
+        self.stages
+            .iter()
+            .map(|name| {
+                registry
+                    .stage(name, self)
+                    .map_or_else(|| name.clone(), |stage| stage.name())
+            })
+            .filter(|name| !name.is_empty())
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+
+    /// Settings that change the repacked output, as part of the cache key.
+    ///
+    /// Return `String` like `max-1264x1680,jpeg-80,deflate-9`
+    pub fn settings(&self) -> String {
+        with_deflate(&self.page_settings(), self.deflate_level)
+    }
+}
+
+/// Append the deflate level to the page settings of a cache key.
+pub fn with_deflate(page_settings: &str, deflate_level: i32) -> String {
+    if page_settings.is_empty() {
+        return format!("deflate-{}", deflate_level);
+    }
+    format!("{},deflate-{}", page_settings, deflate_level)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG_FILE: &str = r#"
+        profile = "ereader"
+
+        [defaults]
+        deflate_level = 6
+        jpeg_quality = 85
+
+        [profiles.ereader]
+        max_width = 1072
+        jpeg_quality = 75
+        xz_level = 7
+
+        [profiles.comic]
+        codec = "png"
+    "#;
+
+    fn config_file() -> ConfigFile {
+        toml::from_str(CONFIG_FILE).unwrap()
+    }
+
+    #[test]
+    fn layers_apply_from_builtin_to_cli() {
+        let env = ProfileSettings {
+            jpeg_quality: Some(70),
+            xz_level: Some(5),
+            ..Default::default()
+        };
+        let cli = ProfileSettings {
+            jpeg_quality: Some(65),
+            ..Default::default()
+        };
+        let config = PipelineConfig::from_layers("ereader", &[config_file()], &env, &cli).unwrap();
+        //NOTE: Builtin profile, nothing above touches it.
+        assert_eq!(config.max_height, Some(1680));
+        //NOTE: File defaults, then the file profile.
+        assert_eq!(config.deflate_level, 6);
+        assert_eq!(config.max_width, Some(1072));
+        //NOTE: Environment over the file, the command line over everything.
+        assert_eq!(config.xz_level, 5);
+        assert_eq!(config.jpeg_quality, 65);
+
+        let config = PipelineConfig::from_layers(
+            "ereader",
+            &[config_file()],
+            &ProfileSettings::default(),
+            &ProfileSettings::default(),
+        )
+        .unwrap();
+        assert_eq!(config.jpeg_quality, 75);
+        assert_eq!(config.xz_level, 7);
+    }
+
+    #[test]
+    fn env_settings_are_read() {
+        std::env::set_var("COMICS_ARCHIVER_XZ_THREADS", "3");
+        std::env::set_var("COMICS_ARCHIVER_STAGES", "grayscale, encode");
+        let env = ProfileSettings::from_env();
+        std::env::set_var("COMICS_ARCHIVER_XZ_THREADS", "many");
+        let invalid = ProfileSettings::from_env();
+        std::env::remove_var("COMICS_ARCHIVER_XZ_THREADS");
+        std::env::remove_var("COMICS_ARCHIVER_STAGES");
+
+        let env = env.unwrap();
+        assert_eq!(env.xz_threads, Some(3));
+        assert_eq!(env.stages, Some(stage_list("grayscale,encode")));
+        let err = invalid.unwrap_err();
+        assert!(matches!(err, CompressionError::ConfigError { .. }));
+        assert!(err.to_string().contains("COMICS_ARCHIVER_XZ_THREADS"));
+    }
+
+    #[test]
+    fn unknown_profiles_are_rejected() {
+        let none = ProfileSettings::default();
+        let err =
+            PipelineConfig::from_layers("manhwa", &[config_file()], &none, &none).unwrap_err();
+        assert!(err.to_string().contains("Unknown profile manhwa"));
+        //NOTE: A profile only a file defines is fine.
+        let config = PipelineConfig::from_layers("comic", &[config_file()], &none, &none).unwrap();
+        assert_eq!(config.codec, PageCodec::Png);
+        assert!(PipelineConfig::from_layers("comic", &[], &none, &none).is_err());
+    }
+
+    #[test]
+    fn out_of_range_settings_are_rejected() {
+        let none = ProfileSettings::default();
+        let invalid = [
+            ProfileSettings {
+                jpeg_quality: Some(0),
+                ..Default::default()
+            },
+            ProfileSettings {
+                jpeg_quality: Some(101),
+                ..Default::default()
+            },
+            ProfileSettings {
+                deflate_level: Some(10),
+                ..Default::default()
+            },
+            ProfileSettings {
+                xz_level: Some(10),
+                ..Default::default()
+            },
+            ProfileSettings {
+                xz_dict_size: Some(1),
+                ..Default::default()
+            },
+            ProfileSettings {
+                max_width: Some(0),
+                ..Default::default()
+            },
+            ProfileSettings {
+                sharpen_sigma: Some(0.0),
+                ..Default::default()
+            },
+        ];
+        for settings in &invalid {
+            let err = PipelineConfig::from_layers(DEFAULT_PROFILE, &[], &none, settings)
+                .expect_err(&format!("{:?} was accepted", settings));
+            assert!(matches!(err, CompressionError::ConfigError { .. }));
+        }
+        assert!(PipelineConfig::from_layers(DEFAULT_PROFILE, &[], &none, &none).is_ok());
+    }
+
+    #[test]
+    fn unknown_file_settings_are_rejected() {
+        assert!(toml::from_str::<ConfigFile>("[defaults]\njpeg_qualty = 80").is_err());
+    }
+}
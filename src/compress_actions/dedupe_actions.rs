@@ -0,0 +1,407 @@
+use crate::cbz_actions::{image_dimensions, is_image_file, CbzEntries};
+use crate::encoding_actions::{zip_entry_name, zip_name_encoding, NameEncoding};
+use crate::err_impl::CompressionError;
+use crate::phash_actions::{dhash, hamming_distance, is_low_information, HashIndex};
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use zip::result::ZipError;
+use zip::ZipArchive;
+
+/// Share of pages two archives need in common to count as the same chapter.
+pub const DUPLICATE_PAGE_SHARE: f64 = 0.8;
+
+/// Pages found in more archives than this don't suggest duplicates,
+/// like a logo or credits page a group puts in every release.
+const RECURRING_PAGE_ARCHIVES: usize = 16;
+
+/// Hash the pages of an unpacked archive.
+///
+/// Only entry names & data go in, so the same pages repacked with other
+/// zip settings or under another archive name hash the same.
+/// * `file_contents`: Entries of a single archive.
+///
+/// Return `String` hex encoded blake3 hash.
+pub fn content_hash(file_contents: &CbzEntries) -> String {
+    let mut hasher = blake3::Hasher::new();
+    for entry in file_contents {
+        let name = entry.2.to_string_lossy();
+        hasher.update(&(name.len() as u64).to_le_bytes());
+        hasher.update(name.as_bytes());
+        hasher.update(&(entry.1.len() as u64).to_le_bytes());
+        hasher.update(&entry.1);
+    }
+    hasher.finalize().to_hex().to_string()
+}
+
+/// Scanlation group an archive was released by, taken from its name.
+///
+/// Looks for `[Group] Series Ch1.cbz` first, then `Series Ch1 (Group).cbz`.
+pub fn release_group<P: AsRef<Path>>(cbz_file: P) -> Option<String> {
+    let name = cbz_file.as_ref().file_stem()?.to_string_lossy().to_string();
+    let between = |open: char, close: char, from_end: bool| {
+        let start = if from_end {
+            name.rfind(open)?
+        } else {
+            name.find(open)?
+        };
+        let end = name[start..].find(close)? + start;
+        Some(name[start + 1..end].trim().to_string()).filter(|group| !group.is_empty())
+    };
+    between('[', ']', false).or_else(|| between('(', ')', true))
+}
+
+/// What an archive looks like, for spotting the same chapter twice.
+#[derive(Debug, Clone)]
+pub struct ArchiveFingerprint {
+    pub archive: PathBuf,
+    /// `content_hash` of the pages, equal for exact copies.
+    pub content_hash: String,
+    /// Perceptual hash per page, close for re-encoded or re-released pages.
+    /// Blank & flat pages are left out, they look alike in every chapter.
+    pub page_hashes: Vec<u64>,
+    /// Average pixels per page.
+    pub page_pixels: u64,
+    pub group: Option<String>,
+    pub modified: SystemTime,
+}
+
+impl ArchiveFingerprint {
+    /// Fingerprint the entries of an unpacked archive.
+    ///
+    /// Pages that can't be decoded are left out of the perceptual hashes.
+    /// * `cbz_file`: Path of the archive.
+    /// * `file_contents`: Entries of the archive.
+    pub fn from_entries<P: AsRef<Path>>(
+        cbz_file: P,
+        file_contents: &CbzEntries,
+    ) -> io::Result<Self> {
+        let cbz_file = cbz_file.as_ref();
+        let pages: Vec<&Vec<u8>> = file_contents
+            .iter()
+            .filter(|entry| is_image_file(&entry.2))
+            .map(|entry| &entry.1)
+            .collect();
+        let total_pixels: u64 = pages
+            .iter()
+            .filter_map(|data| image_dimensions(data))
+            .map(|(width, height)| width as u64 * height as u64)
+            .sum();
+        Ok(Self {
+            archive: cbz_file.to_owned(),
+            content_hash: content_hash(file_contents),
+            page_hashes: pages
+                .iter()
+                .filter_map(|data| dhash(data).ok())
+                .filter(|hash| !is_low_information(*hash))
+                .collect(),
+            page_pixels: total_pixels / pages.len().max(1) as u64,
+            group: release_group(cbz_file),
+            modified: cbz_file.metadata()?.modified()?,
+        })
+    }
+
+    /// Whether two archives are likely the same chapter.
+    ///
+    /// Either the pages are exactly the same, or both have about as many
+    /// pages and most pages of the shorter archive have a look-alike in
+    /// the other one.
+    /// * `other`: Archive to compare with.
+    /// * `max_distance`: Largest hash distance still counted as the same page.
+    pub fn is_duplicate_of(&self, other: &ArchiveFingerprint, max_distance: u32) -> bool {
+        if self.content_hash == other.content_hash {
+            return true;
+        }
+        let (shorter, longer) = if self.page_hashes.len() <= other.page_hashes.len() {
+            (&self.page_hashes, &other.page_hashes)
+        } else {
+            (&other.page_hashes, &self.page_hashes)
+        };
+        //NOTE: A few pages can't make a whole chapter, whatever they match.
+        if shorter.is_empty() || (shorter.len() as f64) < longer.len() as f64 * DUPLICATE_PAGE_SHARE
+        {
+            return false;
+        }
+        let shared = shorter
+            .iter()
+            .filter(|hash| {
+                longer
+                    .iter()
+                    .any(|other_hash| hamming_distance(**hash, *other_hash) <= max_distance)
+            })
+            .count();
+        shared as f64 >= shorter.len() as f64 * DUPLICATE_PAGE_SHARE
+    }
+}
+
+/// Fingerprint a `.cbz` archive on disk.
+///
+/// * `cbz_file`: `.cbz` archive to fingerprint.
+pub fn fingerprint_cbz<P: AsRef<Path>>(
+    cbz_file: P,
+) -> Result<ArchiveFingerprint, CompressionError> {
+    let cbz_file = cbz_file.as_ref();
+    let file = io::BufReader::new(File::open(cbz_file)?);
+    let mut zip_file =
+        ZipArchive::new(file).map_err(|err| CompressionError::from(err).in_archive(cbz_file))?;
+    let encoding = zip_name_encoding(&mut zip_file, NameEncoding::Auto)
+        .map_err(|err| CompressionError::from(err).in_archive(cbz_file))?;
+    let mut entries = Vec::new();
+    for idx in 0..zip_file.len() {
+        let mut inner_file = zip_file
+            .by_index(idx)
+            .map_err(|err| CompressionError::from(err).in_archive(cbz_file))?;
+        if inner_file.is_dir() {
+            continue;
+        }
+        let name = zip_entry_name(&inner_file, encoding);
+        let mut file_contents = Vec::new();
+        inner_file.read_to_end(&mut file_contents).map_err(|err| {
+            CompressionError::from(ZipError::Io(err))
+                .in_archive(cbz_file)
+                .at_entry(&name)
+        })?;
+        entries.push((String::new(), file_contents, PathBuf::from(name)));
+    }
+    Ok(ArchiveFingerprint::from_entries(cbz_file, &entries)?)
+}
+
+/// Archives that could be duplicates of `fingerprints[idx]`, to check with `is_duplicate_of`.
+///
+/// Exact copies come from `by_content`, the others share at least one page
+/// found through `index`, pages that recur in many archives don't count.
+fn duplicate_candidates(
+    fingerprints: &[ArchiveFingerprint],
+    idx: usize,
+    by_content: &HashMap<&str, Vec<usize>>,
+    index: &HashIndex<usize>,
+    max_distance: u32,
+) -> HashSet<usize> {
+    let fingerprint = &fingerprints[idx];
+    let mut candidates: HashSet<usize> = by_content[fingerprint.content_hash.as_str()]
+        .iter()
+        .copied()
+        .collect();
+    for hash in &fingerprint.page_hashes {
+        let archives: HashSet<usize> = index
+            .find(*hash, max_distance)
+            .into_iter()
+            .map(|(_, archive_idx)| *archive_idx)
+            .collect();
+        if archives.len() <= RECURRING_PAGE_ARCHIVES {
+            candidates.extend(archives);
+        }
+    }
+    candidates
+}
+
+/// Group archives that are likely the same chapter.
+///
+/// Archives aren't compared pairwise: exact copies are bucketed by their
+/// content hash & every page goes into a `HashIndex`, so only archives
+/// sharing pages are compared with `is_duplicate_of`.
+/// * `fingerprints`: Archives to compare.
+/// * `max_distance`: Largest hash distance still counted as the same page.
+///
+/// Return `Vec<Vec<&ArchiveFingerprint>>` groups with more than one archive.
+pub fn group_duplicates(
+    fingerprints: &[ArchiveFingerprint],
+    max_distance: u32,
+) -> Vec<Vec<&ArchiveFingerprint>> {
+    let mut by_content: HashMap<&str, Vec<usize>> = HashMap::new();
+    let mut index = HashIndex::new();
+    for (idx, fingerprint) in fingerprints.iter().enumerate() {
+        by_content
+            .entry(&fingerprint.content_hash)
+            .or_default()
+            .push(idx);
+        for hash in &fingerprint.page_hashes {
+            index.insert(*hash, idx);
+        }
+    }
+
+    let mut grouped = vec![false; fingerprints.len()];
+    let mut groups = Vec::new();
+    for (idx, fingerprint) in fingerprints.iter().enumerate() {
+        if grouped[idx] {
+            continue;
+        }
+        //NOTE: Same grouping as going through the archives in order, earlier archives start the groups.
+        let mut members: Vec<usize> =
+            duplicate_candidates(fingerprints, idx, &by_content, &index, max_distance)
+                .into_iter()
+                .filter(|other_idx| *other_idx > idx && !grouped[*other_idx])
+                .filter(|other_idx| {
+                    fingerprint.is_duplicate_of(&fingerprints[*other_idx], max_distance)
+                })
+                .collect();
+        if members.is_empty() {
+            continue;
+        }
+        members.sort_unstable();
+        let mut group = vec![fingerprint];
+        for other_idx in members {
+            grouped[other_idx] = true;
+            group.push(&fingerprints[other_idx]);
+        }
+        groups.push(group);
+    }
+    groups
+}
+
+/// Which archive of a duplicate group to keep.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeepRule {
+    /// Highest average page resolution.
+    LargestResolution,
+    /// First release group on the preferred list, largest resolution otherwise.
+    PreferredGroup,
+    /// Most recently modified archive.
+    Newest,
+}
+
+/// Pick the archive to keep from a duplicate group.
+///
+/// Ties go to the archive that comes first in the group.
+/// * `group`: Archives that are the same chapter.
+/// * `rule`: How to pick.
+/// * `preferred_groups`: Release groups in order of preference, for `KeepRule::PreferredGroup`.
+///
+/// Return `usize` index of the archive to keep.
+pub fn pick_keeper(
+    group: &[&ArchiveFingerprint],
+    rule: KeepRule,
+    preferred_groups: &[String],
+) -> usize {
+    let largest = || {
+        group
+            .iter()
+            .enumerate()
+            .rev()
+            .max_by_key(|(_, fingerprint)| fingerprint.page_pixels)
+            .map_or(0, |(idx, _)| idx)
+    };
+    match rule {
+        KeepRule::LargestResolution => largest(),
+        KeepRule::Newest => group
+            .iter()
+            .enumerate()
+            .rev()
+            .max_by_key(|(_, fingerprint)| fingerprint.modified)
+            .map_or(0, |(idx, _)| idx),
+        KeepRule::PreferredGroup => preferred_groups
+            .iter()
+            .find_map(|preferred| {
+                group.iter().position(|fingerprint| {
+                    fingerprint
+                        .group
+                        .as_ref()
+                        .is_some_and(|group| group.eq_ignore_ascii_case(preferred))
+                })
+            })
+            .unwrap_or_else(largest),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Spread out page hashes, a different set per `seed`.
+    fn page_hashes(seed: u64, count: usize) -> Vec<u64> {
+        (0..count as u64)
+            .map(|page| {
+                //NOTE: splitmix64, so hashes of other seeds & pages are far apart.
+                let mut hash = (seed << 32 | page).wrapping_add(0x9e37_79b9_7f4a_7c15);
+                hash = (hash ^ (hash >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+                hash = (hash ^ (hash >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+                hash ^ (hash >> 31)
+            })
+            .collect()
+    }
+
+    fn fingerprint(name: &str, content: &str, page_hashes: Vec<u64>) -> ArchiveFingerprint {
+        ArchiveFingerprint {
+            archive: PathBuf::from(name),
+            content_hash: content.to_string(),
+            page_hashes,
+            page_pixels: 0,
+            group: release_group(name),
+            modified: SystemTime::UNIX_EPOCH,
+        }
+    }
+
+    fn group_names(groups: &[Vec<&ArchiveFingerprint>]) -> Vec<Vec<String>> {
+        groups
+            .iter()
+            .map(|group| {
+                group
+                    .iter()
+                    .map(|fingerprint| fingerprint.archive.to_string_lossy().to_string())
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn re_releases_group_together() {
+        let chapter = page_hashes(1, 20);
+        let mut re_encoded: Vec<u64> = chapter.iter().map(|hash| hash ^ 0b101).collect();
+        re_encoded.push(page_hashes(9, 1)[0]);
+        let fingerprints = [
+            fingerprint("[A] Ch1.cbz", "a", chapter.clone()),
+            fingerprint("Ch2.cbz", "b", page_hashes(2, 20)),
+            fingerprint("[B] Ch1.cbz", "c", re_encoded),
+            fingerprint("Ch1 copy.cbz", "a", Vec::new()),
+        ];
+        let groups = group_duplicates(&fingerprints, DEFAULT_DISTANCE);
+        assert_eq!(
+            group_names(&groups),
+            [["[A] Ch1.cbz", "[B] Ch1.cbz", "Ch1 copy.cbz"]]
+        );
+    }
+
+    #[test]
+    fn short_archives_are_not_duplicates_of_chapters() {
+        let chapter = page_hashes(1, 20);
+        let fingerprints = [
+            fingerprint("Ch1.cbz", "a", chapter.clone()),
+            fingerprint("Extra.cbz", "b", chapter[..2].to_vec()),
+            //NOTE: Blank pages are dropped when fingerprinting, nothing is left.
+            fingerprint("Blank.cbz", "c", Vec::new()),
+            fingerprint("Blank 2.cbz", "d", Vec::new()),
+        ];
+        assert!(group_duplicates(&fingerprints, DEFAULT_DISTANCE).is_empty());
+    }
+
+    #[test]
+    fn recurring_pages_alone_make_no_duplicates() {
+        let logo = page_hashes(7, 1)[0];
+        let fingerprints: Vec<_> = (0..RECURRING_PAGE_ARCHIVES as u64 + 4)
+            .map(|chapter| {
+                let mut pages = page_hashes(chapter + 10, 4);
+                pages.push(logo);
+                fingerprint(&format!("Ch{}.cbz", chapter), &chapter.to_string(), pages)
+            })
+            .collect();
+        assert!(group_duplicates(&fingerprints, DEFAULT_DISTANCE).is_empty());
+    }
+
+    #[test]
+    fn release_group_from_name() {
+        assert_eq!(
+            release_group("[Group] Series Ch1.cbz").as_deref(),
+            Some("Group")
+        );
+        assert_eq!(
+            release_group("Series (2020) Ch1 (Group).cbz").as_deref(),
+            Some("Group")
+        );
+        assert_eq!(release_group("Series Ch1 [].cbz"), None);
+        assert_eq!(release_group("Series Ch1.cbz"), None);
+    }
+
+    const DEFAULT_DISTANCE: u32 = crate::phash_actions::DEFAULT_MAX_DISTANCE;
+}
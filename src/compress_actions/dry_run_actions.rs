@@ -0,0 +1,124 @@
+use crate::cbz_actions::{compress_images_with_img, is_image_file};
+use crate::err_impl::CompressionError;
+use crate::stage_actions::{ImageChain, Page};
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use zip::ZipArchive;
+
+/// Rough size of the zip headers written for every entry, without the name.
+const ZIP_ENTRY_OVERHEAD: u64 = 76;
+
+/// Projected outcome of running the pipeline over one archive.
+#[derive(Debug, Clone)]
+pub struct ArchiveProjection {
+    pub archive: PathBuf,
+    pub page_count: usize,
+    pub sampled_pages: usize,
+    pub original_size: u64,
+    /// `None` when the archive has pages but none were sampled, like with a sample size of 0.
+    pub projected_size: Option<u64>,
+    pub projected_time: Option<Duration>,
+}
+
+/// Pick `sample_size` indices spread evenly over `0..total`.
+fn sample_indices(total: usize, sample_size: usize) -> Vec<usize> {
+    if sample_size == 0 || total == 0 {
+        return Vec::new();
+    }
+    if sample_size >= total {
+        return (0..total).collect();
+    }
+    (0..sample_size)
+        .map(|idx| idx * total / sample_size + total / (2 * sample_size))
+        .collect()
+}
+
+/// Project the size & time of optimising an archive without writing anything.
+///
+/// A few pages spread over the archive are run through `compress_images_with_img`,
+/// the size ratio & time per page of that sample are scaled up to every page.
+/// Entries that aren't images are counted at their current size.
+/// * `cbz_file`: `.cbz` archive to project.
+/// * `sample_size`: Number of pages to actually compress, 0 only counts the pages.
+/// * `chain`: Stages the pages go through.
+///
+/// Return `ArchiveProjection`
+pub fn project_archive<P: AsRef<Path>>(
+    cbz_file: P,
+    sample_size: usize,
+    chain: &ImageChain,
+) -> Result<ArchiveProjection, CompressionError> {
+    let original_size = cbz_file.as_ref().metadata()?.len();
+    let file = io::BufReader::new(File::open(cbz_file.as_ref())?);
+    let mut zip_file = ZipArchive::new(file)
+        .map_err(|err| CompressionError::from(err).in_archive(cbz_file.as_ref()))?;
+
+    let mut pages = Vec::new();
+    let mut page_bytes: u64 = 0;
+    let mut projected_size: u64 = 0;
+    for idx in 0..zip_file.len() {
+        let inner_file = zip_file
+            .by_index(idx)
+            .map_err(|err| CompressionError::from(err).in_archive(cbz_file.as_ref()))?;
+        if inner_file.is_dir() {
+            continue;
+        }
+        projected_size += ZIP_ENTRY_OVERHEAD + inner_file.name().len() as u64;
+        if is_image_file(inner_file.name()) {
+            pages.push(idx);
+            page_bytes += inner_file.size();
+        } else {
+            projected_size += inner_file.compressed_size();
+        }
+    }
+
+    let mut sample_in: u64 = 0;
+    let mut sample_out: u64 = 0;
+    let mut sample_time = Duration::ZERO;
+    let sampled = sample_indices(pages.len(), sample_size);
+    for &page in &sampled {
+        let mut inner_file = zip_file
+            .by_index(pages[page])
+            .map_err(|err| CompressionError::from(err).in_archive(cbz_file.as_ref()))?;
+        let entry = inner_file.name().to_owned();
+        let mut file_contents = Vec::new();
+        inner_file.read_to_end(&mut file_contents).map_err(|err| {
+            CompressionError::from(zip::result::ZipError::Io(err))
+                .in_archive(cbz_file.as_ref())
+                .at_entry(&entry)
+        })?;
+        sample_in += file_contents.len() as u64;
+        let started = Instant::now();
+        let page = Page::new(cbz_file.as_ref(), &entry, file_contents);
+        let compressed = compress_images_with_img(page, chain)
+            .map_err(|err| err.in_archive(cbz_file.as_ref()).at_entry(&entry))?;
+        sample_time += started.elapsed();
+        sample_out += compressed.len() as u64;
+    }
+
+    //NOTE: Without a sample there's nothing to scale up, the sizes of the pages
+    //as they are would look like the pipeline saves nothing.
+    let (projected_size, projected_time) = if sampled.is_empty() && !pages.is_empty() {
+        (None, None)
+    } else if sample_in > 0 {
+        (
+            Some(
+                projected_size + (page_bytes as f64 * sample_out as f64 / sample_in as f64) as u64,
+            ),
+            Some(sample_time.mul_f64(pages.len() as f64 / sampled.len() as f64)),
+        )
+    } else {
+        (Some(projected_size + page_bytes), Some(Duration::ZERO))
+    };
+
+    Ok(ArchiveProjection {
+        archive: cbz_file.as_ref().to_owned(),
+        page_count: pages.len(),
+        sampled_pages: sampled.len(),
+        original_size,
+        projected_size,
+        projected_time,
+    })
+}
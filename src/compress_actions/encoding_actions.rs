@@ -0,0 +1,238 @@
+use chardetng::{EncodingDetector, Iso2022JpDetection, Utf8Detection};
+use encoding_rs::{Encoding, BIG5, EUC_JP, EUC_KR, GB18030, GBK, SHIFT_JIS};
+use std::fmt;
+use std::io::{Read, Seek};
+use std::str::FromStr;
+use zip::read::ZipFile;
+use zip::result::ZipError;
+use zip::ZipArchive;
+
+/// Code page 437 from `0x80` up, what zip tools fall back to without the UTF-8 flag.
+const CP437_HIGH: &str = "ÇüéâäàåçêëèïîìÄÅÉæÆôöòûùÿÖÜ¢£¥₧ƒáíóúñÑªº¿⌐¬½¼¡«»░▒▓│┤╡╢╖╕╣║╗╝╜╛┐└┴┬├─┼╞╟╚╔╩╦╠═╬╧╨╤╥╙╘╒╓╫╪┘┌█▄▌▐▀αßΓπΣσµτΦΘΩδ∞φε∩≡±≥≤⌠⌡÷≈°∙·√ⁿ²■\u{a0}";
+
+/// Multi byte encodings raw Japanese, Chinese & Korean releases are packed with.
+static CJK_ENCODINGS: [&Encoding; 6] = [SHIFT_JIS, GBK, GB18030, BIG5, EUC_JP, EUC_KR];
+
+/// How entry names without the UTF-8 flag are decoded.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum NameEncoding {
+    /// Guess per archive from all of its names.
+    #[default]
+    Auto,
+    /// The zip default, used by western tools.
+    Cp437,
+    /// A fixed encoding, like `shift_jis` or `gbk`.
+    Other(&'static Encoding),
+}
+
+/// Encoding the names of one archive are decoded with, from `NameEncoding::resolve`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ResolvedNameEncoding {
+    /// `None` means code page 437.
+    pub encoding: Option<&'static Encoding>,
+    /// Asked for by the user, so it applies to names that happen to be valid UTF-8 too.
+    pub forced: bool,
+}
+
+impl FromStr for NameEncoding {
+    type Err = String;
+
+    fn from_str(label: &str) -> Result<Self, Self::Err> {
+        match label.to_ascii_lowercase().as_str() {
+            "auto" => Ok(NameEncoding::Auto),
+            "cp437" | "ibm437" => Ok(NameEncoding::Cp437),
+            _ => Encoding::for_label(label.as_bytes())
+                .map(NameEncoding::Other)
+                .ok_or_else(|| format!("Unknown encoding {:?}", label)),
+        }
+    }
+}
+
+impl fmt::Display for NameEncoding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NameEncoding::Auto => write!(f, "auto"),
+            NameEncoding::Cp437 => write!(f, "cp437"),
+            NameEncoding::Other(encoding) => write!(f, "{}", encoding.name().to_lowercase()),
+        }
+    }
+}
+
+impl NameEncoding {
+    /// Settle on the encoding for the names of one archive.
+    ///
+    /// Names that are valid UTF-8 don't say anything, the rest are fed
+    /// to the detector together since single names are too short to go by.
+    /// * `raw_names`: Entry names as stored in the archive.
+    ///
+    /// Return `ResolvedNameEncoding`
+    pub fn resolve<'a, I>(self, raw_names: I) -> ResolvedNameEncoding
+    where
+        I: IntoIterator<Item = &'a [u8]>,
+    {
+        let encoding = match self {
+            NameEncoding::Cp437 => None,
+            NameEncoding::Other(encoding) => Some(encoding),
+            NameEncoding::Auto => {
+                let mut detector = EncodingDetector::new(Iso2022JpDetection::Deny);
+                let mut fed = false;
+                for name in raw_names {
+                    if std::str::from_utf8(name).is_err() {
+                        detector.feed(name, false);
+                        detector.feed(b"\n", false);
+                        fed = true;
+                    }
+                }
+                if !fed {
+                    return ResolvedNameEncoding::default();
+                }
+                detector.feed(b"", true);
+                let guess = detector.guess(None, Utf8Detection::Deny);
+                //NOTE: Western guesses are the zip default, not an actual code page.
+                CJK_ENCODINGS.contains(&guess).then_some(guess)
+            }
+        };
+        ResolvedNameEncoding {
+            encoding,
+            forced: self != NameEncoding::Auto,
+        }
+    }
+}
+
+/// Decode a raw entry name.
+///
+/// Names with the UTF-8 flag are kept as is. So are names without it
+/// that are valid UTF-8, unless the encoding was asked for by the user.
+/// * `raw_name`: Name as stored in the archive.
+/// * `utf8_flag`: Whether the entry has the UTF-8 flag set.
+/// * `encoding`: Encoding from `NameEncoding::resolve`.
+pub fn decode_entry_name(
+    raw_name: &[u8],
+    utf8_flag: bool,
+    encoding: ResolvedNameEncoding,
+) -> String {
+    if utf8_flag || !encoding.forced {
+        if let Ok(name) = std::str::from_utf8(raw_name) {
+            return name.to_string();
+        }
+    }
+    match encoding.encoding {
+        Some(encoding) => encoding
+            .decode_without_bom_handling(raw_name)
+            .0
+            .into_owned(),
+        None => raw_name
+            .iter()
+            .map(|byte| match byte {
+                0..=0x7f => *byte as char,
+                _ => CP437_HIGH.chars().nth(*byte as usize - 0x80).unwrap_or('?'),
+            })
+            .collect(),
+    }
+}
+
+/// Decode the name of an entry of an opened archive, see `decode_entry_name`.
+pub fn zip_entry_name(inner_file: &ZipFile, encoding: ResolvedNameEncoding) -> String {
+    //NOTE: The zip reader doesn't expose the UTF-8 flag. It decodes flagged
+    //names as UTF-8 & the others as code page 437, which only gives back the
+    //raw bytes for flagged names & plain ASCII ones, those decode the same either way.
+    let utf8_flag = inner_file.name().as_bytes() == inner_file.name_raw();
+    decode_entry_name(inner_file.name_raw(), utf8_flag, encoding)
+}
+
+/// Settle on the encoding for the entry names of an opened archive.
+///
+/// * `zip_file`: Archive to look at.
+/// * `name_encoding`: Encoding asked for by the user.
+pub fn zip_name_encoding<R: Read + Seek>(
+    zip_file: &mut ZipArchive<R>,
+    name_encoding: NameEncoding,
+) -> Result<ResolvedNameEncoding, ZipError> {
+    if name_encoding != NameEncoding::Auto {
+        return Ok(name_encoding.resolve([]));
+    }
+    let mut raw_names = Vec::with_capacity(zip_file.len());
+    for idx in 0..zip_file.len() {
+        raw_names.push(zip_file.by_index_raw(idx)?.name_raw().to_vec());
+    }
+    Ok(name_encoding.resolve(raw_names.iter().map(Vec::as_slice)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SHIFT_JIS_NAMES: [&[u8]; 4] = [
+        b"\x91\xe601\x98b/001.jpg",
+        b"\x91\xe601\x98b/002.jpg",
+        b"\x91\xe601\x98b/\x95\\\x8e\x86.jpg",
+        b"\x91\xe601\x98b/\x96\xda\x8e\x9f.jpg",
+    ];
+
+    const GBK_NAMES: [&[u8]; 4] = [
+        b"\xb5\xda\xd2\xbb\xbb\xb0/\xb5\xda001\xd2\xb3.jpg",
+        b"\xb5\xda\xd2\xbb\xbb\xb0/\xb5\xda002\xd2\xb3.jpg",
+        b"\xb5\xda\xd2\xbb\xbb\xb0/\xb7\xe2\xc3\xe6.jpg",
+        b"\xb5\xda\xd2\xbb\xbb\xb0/\xc4\xbf\xc2\xbc.jpg",
+    ];
+
+    #[test]
+    fn shift_jis_names_are_detected() {
+        let encoding = NameEncoding::Auto.resolve(SHIFT_JIS_NAMES);
+        assert_eq!(encoding.encoding, Some(SHIFT_JIS));
+        assert!(!encoding.forced);
+        assert_eq!(
+            decode_entry_name(SHIFT_JIS_NAMES[2], false, encoding),
+            "第01話/表紙.jpg"
+        );
+    }
+
+    #[test]
+    fn gbk_names_are_detected() {
+        let encoding = NameEncoding::Auto.resolve(GBK_NAMES);
+        assert_eq!(encoding.encoding, Some(GBK));
+        assert_eq!(
+            decode_entry_name(GBK_NAMES[2], false, encoding),
+            "第一话/封面.jpg"
+        );
+    }
+
+    #[test]
+    fn western_names_fall_back_to_cp437() {
+        let names: [&[u8]; 2] = [b"Caf\x82/001.png", b"Caf\x82/002.png"];
+        let encoding = NameEncoding::Auto.resolve(names);
+        assert_eq!(encoding, ResolvedNameEncoding::default());
+        assert_eq!(decode_entry_name(names[0], false, encoding), "Café/001.png");
+        //NOTE: Without any name to go by there is nothing to detect.
+        assert_eq!(
+            NameEncoding::Auto.resolve([b"001.png".as_slice()]),
+            ResolvedNameEncoding::default()
+        );
+    }
+
+    #[test]
+    fn utf8_names_are_kept_unless_overridden() {
+        let name = "é.png".as_bytes();
+        let auto = NameEncoding::Auto.resolve([name]);
+        assert_eq!(decode_entry_name(name, false, auto), "é.png");
+
+        let cp437 = "cp437".parse::<NameEncoding>().unwrap().resolve([]);
+        assert!(cp437.forced);
+        assert_eq!(decode_entry_name(name, false, cp437), "├⌐.png");
+        assert_eq!(decode_entry_name(name, true, cp437), "é.png");
+
+        let shift_jis = "Shift_JIS".parse::<NameEncoding>().unwrap().resolve([]);
+        assert_eq!(shift_jis.encoding, Some(SHIFT_JIS));
+        assert_eq!(decode_entry_name(name, false, shift_jis), "ﾃｩ.png");
+        assert_eq!(decode_entry_name(name, true, shift_jis), "é.png");
+    }
+
+    #[test]
+    fn override_labels_are_parsed() {
+        assert_eq!("auto".parse(), Ok(NameEncoding::Auto));
+        assert_eq!("IBM437".parse(), Ok(NameEncoding::Cp437));
+        assert_eq!("gbk".parse(), Ok(NameEncoding::Other(GBK)));
+        assert!("klingon".parse::<NameEncoding>().is_err());
+        assert_eq!(NameEncoding::Other(SHIFT_JIS).to_string(), "shift_jis");
+    }
+}
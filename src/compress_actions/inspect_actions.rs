@@ -0,0 +1,226 @@
+use crate::cbz_actions::{image_codec, image_dimensions, is_image_file};
+use crate::encoding_actions::{zip_entry_name, zip_name_encoding, NameEncoding};
+use crate::err_impl::CompressionError;
+use crate::verify_actions::{resolve_reference, COMIC_INFO_NAME};
+use quick_xml::events::Event;
+use quick_xml::Reader;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use zip::result::ZipError;
+use zip::ZipArchive;
+
+/// `ComicInfo.xml` fields shown by `archive_info`.
+const COMIC_INFO_SUMMARY_FIELDS: [&str; 7] = [
+    "Series",
+    "Number",
+    "Volume",
+    "Title",
+    "Writer",
+    "Year",
+    "PageCount",
+];
+
+/// One entry of an archive, as stored.
+#[derive(Debug, Clone)]
+pub struct EntryListing {
+    pub name: String,
+    pub size: u64,
+    pub compressed_size: u64,
+    pub method: String,
+    /// `YYYY-MM-DD HH:MM:SS` as stored in the zip header.
+    pub modified: String,
+}
+
+/// List the entries of a `.cbz` archive without unpacking anything.
+///
+/// * `cbz_file`: `.cbz` archive to list.
+/// * `name_encoding`: How to decode entry names without the UTF-8 flag.
+///
+/// Return `Vec<EntryListing>` in archive order.
+pub fn list_cbz<P: AsRef<Path>>(
+    cbz_file: P,
+    name_encoding: NameEncoding,
+) -> Result<Vec<EntryListing>, CompressionError> {
+    let cbz_file = cbz_file.as_ref();
+    let file = io::BufReader::new(File::open(cbz_file)?);
+    let mut zip_file =
+        ZipArchive::new(file).map_err(|err| CompressionError::from(err).in_archive(cbz_file))?;
+    let encoding = zip_name_encoding(&mut zip_file, name_encoding)
+        .map_err(|err| CompressionError::from(err).in_archive(cbz_file))?;
+    let mut listing = Vec::with_capacity(zip_file.len());
+    for idx in 0..zip_file.len() {
+        let inner_file = zip_file
+            .by_index_raw(idx)
+            .map_err(|err| CompressionError::from(err).in_archive(cbz_file))?;
+        let modified = inner_file.last_modified();
+        listing.push(EntryListing {
+            name: zip_entry_name(&inner_file, encoding),
+            size: inner_file.size(),
+            compressed_size: inner_file.compressed_size(),
+            method: inner_file.compression().to_string(),
+            modified: format!(
+                "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+                modified.year(),
+                modified.month(),
+                modified.day(),
+                modified.hour(),
+                modified.minute(),
+                modified.second()
+            ),
+        });
+    }
+    Ok(listing)
+}
+
+/// Overview of a `.cbz` archive.
+#[derive(Debug, Clone, Default)]
+pub struct ArchiveInfo {
+    pub archive: PathBuf,
+    pub size: u64,
+    pub entries: usize,
+    pub pages: usize,
+    /// Uncompressed size of every page together.
+    pub page_bytes: u64,
+    /// Pages per codec, like `jpeg: 20`.
+    pub codecs: BTreeMap<String, usize>,
+    pub min_dimensions: Option<(u32, u32)>,
+    pub max_dimensions: Option<(u32, u32)>,
+    /// Archive comment, where ComicBookInfo keeps its metadata.
+    pub comment: String,
+    /// Main `ComicInfo.xml` fields that are set | (field, value)
+    pub comic_info: Vec<(String, String)>,
+}
+
+/// Pull the summary fields out of a `ComicInfo.xml`.
+fn comic_info_summary(xml_data: &[u8]) -> Vec<(String, String)> {
+    let Ok(xml) = std::str::from_utf8(xml_data) else {
+        return Vec::new();
+    };
+    let mut reader = Reader::from_str(xml);
+    let mut path: Vec<String> = Vec::new();
+    let mut summary = Vec::new();
+    let mut value = String::new();
+    loop {
+        match reader.read_event() {
+            Ok(Event::Start(tag)) => {
+                path.push(tag.name().as_ref().to_string());
+                value.clear();
+            }
+            //NOTE: Text comes in pieces around every reference, like `Tom &amp; Jerry`.
+            Ok(Event::Text(text)) if path.len() == 2 => value.push_str(&text),
+            Ok(Event::GeneralRef(reference)) if path.len() == 2 => {
+                value.push_str(&resolve_reference(&reference));
+            }
+            Ok(Event::End(_)) if path.len() == 2 => {
+                let field = path.pop().unwrap_or_default();
+                let value = value.trim();
+                if COMIC_INFO_SUMMARY_FIELDS.contains(&field.as_str()) && !value.is_empty() {
+                    summary.push((field, value.to_string()));
+                }
+            }
+            Ok(Event::End(_)) => {
+                path.pop();
+            }
+            Ok(Event::Eof) | Err(_) => break,
+            _ => {}
+        }
+    }
+    summary
+}
+
+/// Gather an overview of a `.cbz` archive.
+///
+/// Page dimensions come from the image headers, pages aren't decoded.
+/// * `cbz_file`: `.cbz` archive to look at.
+pub fn archive_info<P: AsRef<Path>>(cbz_file: P) -> Result<ArchiveInfo, CompressionError> {
+    let cbz_file = cbz_file.as_ref();
+    let file = io::BufReader::new(File::open(cbz_file)?);
+    let mut zip_file =
+        ZipArchive::new(file).map_err(|err| CompressionError::from(err).in_archive(cbz_file))?;
+    let encoding = zip_name_encoding(&mut zip_file, NameEncoding::Auto)
+        .map_err(|err| CompressionError::from(err).in_archive(cbz_file))?;
+    let mut info = ArchiveInfo {
+        archive: cbz_file.to_owned(),
+        size: cbz_file.metadata()?.len(),
+        comment: String::from_utf8_lossy(zip_file.comment()).to_string(),
+        ..Default::default()
+    };
+    for idx in 0..zip_file.len() {
+        let mut inner_file = zip_file
+            .by_index(idx)
+            .map_err(|err| CompressionError::from(err).in_archive(cbz_file))?;
+        if inner_file.is_dir() {
+            continue;
+        }
+        info.entries += 1;
+        let name = zip_entry_name(&inner_file, encoding);
+        let is_comic_info = Path::new(&name)
+            .file_name()
+            .is_some_and(|file_name| file_name.eq_ignore_ascii_case(COMIC_INFO_NAME));
+        if !is_image_file(&name) && !is_comic_info {
+            continue;
+        }
+        let mut file_contents = Vec::new();
+        inner_file.read_to_end(&mut file_contents).map_err(|err| {
+            CompressionError::from(ZipError::Io(err))
+                .in_archive(cbz_file)
+                .at_entry(&name)
+        })?;
+        if is_comic_info {
+            info.comic_info = comic_info_summary(&file_contents);
+            continue;
+        }
+        info.pages += 1;
+        info.page_bytes += file_contents.len() as u64;
+        let codec = image_codec(&file_contents).unwrap_or_else(|| "unknown".to_string());
+        *info.codecs.entry(codec).or_default() += 1;
+        if let Some((width, height)) = image_dimensions(&file_contents) {
+            let area = |dimensions: &(u32, u32)| dimensions.0 as u64 * dimensions.1 as u64;
+            let area_now = width as u64 * height as u64;
+            if info
+                .min_dimensions
+                .as_ref()
+                .is_none_or(|min| area_now < area(min))
+            {
+                info.min_dimensions = Some((width, height));
+            }
+            if info
+                .max_dimensions
+                .as_ref()
+                .is_none_or(|max| area_now > area(max))
+            {
+                info.max_dimensions = Some((width, height));
+            }
+        }
+    }
+    Ok(info)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn summary_values_keep_their_references() {
+        let xml = br#"<?xml version="1.0"?>
+<ComicInfo>
+  <Series>Tom &amp; Jerry</Series>
+  <Title>&lt;Chase&gt; &#8212; &quot;Part&#x20;1&quot;</Title>
+  <Writer>Hanna&unknown;</Writer>
+  <Summary>Not shown &amp; skipped</Summary>
+  <Number> 1 </Number>
+  <Volume></Volume>
+</ComicInfo>"#;
+        assert_eq!(
+            comic_info_summary(xml),
+            [
+                ("Series".to_string(), "Tom & Jerry".to_string()),
+                ("Title".to_string(), "<Chase> — \"Part 1\"".to_string()),
+                ("Writer".to_string(), "Hanna&unknown;".to_string()),
+                ("Number".to_string(), "1".to_string()),
+            ]
+        );
+    }
+}
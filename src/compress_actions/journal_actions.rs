@@ -0,0 +1,298 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+/// Name of the journal file kept next to the optimised archives.
+pub const JOURNAL_FILE_NAME: &str = ".comics_archiver.journal";
+
+/// Where an archive is in the optimise-and-repack pipeline.
+///
+/// States only move forward, so a later state implies the earlier ones.
+/// `Duplicate` is the other way out of `Pending`, for archives with the same
+/// content as another one of the run, those get no output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum JobState {
+    Pending,
+    Optimised,
+    Written,
+    Verified,
+    Duplicate,
+}
+
+impl JobState {
+    fn as_str(&self) -> &'static str {
+        match self {
+            JobState::Pending => "pending",
+            JobState::Optimised => "optimised",
+            JobState::Written => "written",
+            JobState::Verified => "verified",
+            JobState::Duplicate => "duplicate",
+        }
+    }
+
+    fn parse(state: &str) -> Option<Self> {
+        match state {
+            "pending" => Some(JobState::Pending),
+            "optimised" => Some(JobState::Optimised),
+            "written" => Some(JobState::Written),
+            "verified" => Some(JobState::Verified),
+            "duplicate" => Some(JobState::Duplicate),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for JobState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+/// Checkpoint journal for a `Pipeline` run.
+///
+/// Every state change is appended as a `state:archive_path` line and flushed
+/// straight away, so the file survives a crash half way through a run.
+/// The content hash of an archive goes in a `content:hash:archive_path` line,
+/// so a resumed run still knows which archives duplicate the finished ones.
+/// Re-opening the journal replays those lines and keeps the last state per archive,
+/// then compacts the file down to those, so it doesn't grow run after run.
+pub struct JobJournal {
+    path: PathBuf,
+    file: File,
+    states: HashMap<PathBuf, JobState>,
+    contents: HashMap<PathBuf, String>,
+}
+
+impl JobJournal {
+    /// Open the journal inside `output_dir`, creating it if needed.
+    ///
+    /// * `output_dir`: Directory the optimised archives are written to.
+    ///
+    /// Return `JobJournal` with the states of any previous run loaded.
+    pub fn open<P: AsRef<Path>>(output_dir: P) -> io::Result<Self> {
+        let path = output_dir.as_ref().join(JOURNAL_FILE_NAME);
+        let mut states = HashMap::new();
+        let mut contents = HashMap::new();
+        if path.exists() {
+            let reader = BufReader::new(File::open(&path)?);
+            for line in reader.lines() {
+                //NOTE: A torn last line from a crash is just ignored.
+                match parse_line(&line?) {
+                    Some(JournalLine::State(state, archive)) => {
+                        states.insert(archive, state);
+                    }
+                    Some(JournalLine::Content(content_hash, archive)) => {
+                        contents.insert(archive, content_hash);
+                    }
+                    None => {}
+                }
+            }
+        }
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let mut journal = Self {
+            path,
+            file,
+            states,
+            contents,
+        };
+        journal.compact()?;
+        Ok(journal)
+    }
+
+    /// Path of the journal file on disk.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Last recorded state of `archive`, `None` if it was never seen.
+    pub fn state<P: AsRef<Path>>(&self, archive: P) -> Option<JobState> {
+        self.states.get(archive.as_ref()).copied()
+    }
+
+    /// Content hash recorded for `archive`, see `dedupe_actions::content_hash`.
+    pub fn content<P: AsRef<Path>>(&self, archive: P) -> Option<&str> {
+        self.contents.get(archive.as_ref()).map(String::as_str)
+    }
+
+    /// Whether `archive` made it all the way through the pipeline.
+    pub fn is_finished<P: AsRef<Path>>(&self, archive: P) -> bool {
+        self.state(archive) == Some(JobState::Verified)
+    }
+
+    /// Record a new state for `archive` and flush it to disk.
+    ///
+    /// * `archive`: Source `.cbz` path.
+    /// * `state`: State the archive just reached.
+    pub fn record<P: AsRef<Path>>(&mut self, archive: P, state: JobState) -> io::Result<()> {
+        let archive = archive.as_ref();
+        if self.state(archive) == Some(state) {
+            return Ok(());
+        }
+        self.file
+            .write_all(format_line(state, archive).as_bytes())?;
+        self.file.sync_data()?;
+        self.states.insert(archive.to_owned(), state);
+        Ok(())
+    }
+
+    /// Record the content hash of `archive` and flush it to disk.
+    ///
+    /// * `archive`: Source `.cbz` path.
+    /// * `content_hash`: Hash of its unpacked entries.
+    pub fn record_content<P: AsRef<Path>>(
+        &mut self,
+        archive: P,
+        content_hash: &str,
+    ) -> io::Result<()> {
+        let archive = archive.as_ref();
+        if self.content(archive) == Some(content_hash) {
+            return Ok(());
+        }
+        self.file
+            .write_all(format_content_line(content_hash, archive).as_bytes())?;
+        self.file.sync_data()?;
+        self.contents
+            .insert(archive.to_owned(), content_hash.to_string());
+        Ok(())
+    }
+
+    /// Rewrite the file with only the last state & content hash of every archive.
+    ///
+    /// The compacted journal is written next to the old one then renamed
+    /// over it, so a crash in between leaves one of the two whole.
+    pub fn compact(&mut self) -> io::Result<()> {
+        let mut archives: Vec<_> = self.states.iter().collect();
+        archives.sort();
+        let mut compacted = String::new();
+        for (archive, state) in archives {
+            compacted.push_str(&format_line(*state, archive));
+        }
+        let mut contents: Vec<_> = self.contents.iter().collect();
+        contents.sort();
+        for (archive, content_hash) in contents {
+            compacted.push_str(&format_content_line(content_hash, archive));
+        }
+        let tmp_path = self.path.with_extension("journal.tmp");
+        let mut tmp_file = File::create(&tmp_path)?;
+        tmp_file.write_all(compacted.as_bytes())?;
+        tmp_file.sync_data()?;
+        std::fs::rename(&tmp_path, &self.path)?;
+        self.file = OpenOptions::new().append(true).open(&self.path)?;
+        Ok(())
+    }
+
+    /// Forget every recorded state so the next run starts from scratch.
+    pub fn clear(&mut self) -> io::Result<()> {
+        self.file.set_len(0)?;
+        self.file.sync_data()?;
+        self.states.clear();
+        self.contents.clear();
+        Ok(())
+    }
+}
+
+/// Journal line of `archive` reaching `state`, newline included.
+fn format_line(state: JobState, archive: &Path) -> String {
+    format!("{}:{}\n", state, archive.to_string_lossy())
+}
+
+/// Journal line with the content hash of `archive`, newline included.
+fn format_content_line(content_hash: &str, archive: &Path) -> String {
+    format!("content:{}:{}\n", content_hash, archive.to_string_lossy())
+}
+
+/// One line of the journal file.
+#[derive(Debug, PartialEq, Eq)]
+enum JournalLine {
+    State(JobState, PathBuf),
+    Content(String, PathBuf),
+}
+
+/// Read a `state:archive_path` or `content:hash:archive_path` journal line.
+///
+/// Return `None` for lines that aren't whole, like one torn by a crash.
+fn parse_line(line: &str) -> Option<JournalLine> {
+    let (kind, rest) = line.split_once(':')?;
+    if kind == "content" {
+        let (content_hash, archive) = rest.split_once(':')?;
+        if content_hash.is_empty() || archive.is_empty() {
+            return None;
+        }
+        return Some(JournalLine::Content(
+            content_hash.to_string(),
+            PathBuf::from(archive),
+        ));
+    }
+    if rest.is_empty() {
+        return None;
+    }
+    Some(JournalLine::State(
+        JobState::parse(kind)?,
+        PathBuf::from(rest),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "comics_archiver_journal_{}_{}",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn parse_line_round_trips() {
+        let archive = Path::new("Series: Part 1/Ch 01.cbz");
+        let line = format_line(JobState::Written, archive);
+        assert_eq!(
+            parse_line(line.trim_end()),
+            Some(JournalLine::State(JobState::Written, archive.to_owned()))
+        );
+        let line = format_content_line("abc", archive);
+        assert_eq!(
+            parse_line(line.trim_end()),
+            Some(JournalLine::Content("abc".to_string(), archive.to_owned()))
+        );
+    }
+
+    #[test]
+    fn parse_line_skips_torn_lines() {
+        assert_eq!(parse_line("verif"), None);
+        assert_eq!(parse_line("verified:"), None);
+        assert_eq!(parse_line("done:a.cbz"), None);
+        assert_eq!(parse_line("content:abc"), None);
+        assert_eq!(parse_line("content::a.cbz"), None);
+    }
+
+    #[test]
+    fn reopen_keeps_last_state_and_compacts() {
+        let dir = scratch_dir("compact");
+        for _ in 0..3 {
+            let mut journal = JobJournal::open(&dir).unwrap();
+            journal.record("a.cbz", JobState::Pending).unwrap();
+            journal.record("a.cbz", JobState::Pending).unwrap();
+            journal.record("a.cbz", JobState::Verified).unwrap();
+            journal.record("b.cbz", JobState::Written).unwrap();
+            journal.record_content("a.cbz", "abc").unwrap();
+        }
+        let journal = JobJournal::open(&dir).unwrap();
+        assert!(journal.is_finished("a.cbz"));
+        assert_eq!(journal.state("b.cbz"), Some(JobState::Written));
+        assert_eq!(journal.content("a.cbz"), Some("abc"));
+        let contents = std::fs::read_to_string(journal.path()).unwrap();
+        assert_eq!(
+            contents,
+            "verified:a.cbz\nwritten:b.cbz\ncontent:abc:a.cbz\n"
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,2 +1,18 @@
+pub mod bundle_actions;
+pub mod cache_actions;
 pub mod cbz_actions;
+pub mod config_actions;
+pub mod dedupe_actions;
+pub mod dry_run_actions;
+pub mod encoding_actions;
+pub mod inspect_actions;
+pub mod journal_actions;
+pub mod phash_actions;
+pub mod pipeline_actions;
+pub mod progress_actions;
+pub mod report_actions;
+pub mod salvage_actions;
+pub mod stage_actions;
+pub mod verify_actions;
+pub mod watch_actions;
 //pub mod xz_actions;
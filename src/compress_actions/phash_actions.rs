@@ -0,0 +1,395 @@
+use crate::cbz_actions::{is_image_file, CbzEntries};
+use crate::encoding_actions::{zip_entry_name, zip_name_encoding, NameEncoding};
+use crate::err_impl::CompressionError;
+use image::imageops::FilterType;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read};
+use std::path::{Path, PathBuf};
+use zip::result::ZipError;
+use zip::ZipArchive;
+
+/// Hamming distance up to which two pages count as the same page.
+///
+/// Re-encodes, resizes & slight crops of a page stay well inside this.
+pub const DEFAULT_MAX_DISTANCE: u32 = 4;
+
+/// Fewest bits a hash needs set, and unset, to say anything about a page.
+///
+/// Blank & flat pages, like white separators or a mostly black credits
+/// page, hash to (almost) all zeros or all ones and would match each other.
+pub const MIN_HASH_BITS: u32 = 8;
+
+/// Perceptual difference hash (dHash) of an image.
+///
+/// The page is shrunk to 9x8 grayscale pixels and every bit records
+/// whether a pixel is brighter than its right neighbour, so the hash
+/// survives re-encoding, rescaling & small colour shifts.
+/// * `image_data`: Encoded image.
+///
+/// Return `u64` hash of the image.
+pub fn dhash(image_data: &[u8]) -> Result<u64, CompressionError> {
+    let img = image::load_from_memory(image_data)?;
+    let small = img
+        .grayscale()
+        .resize_exact(9, 8, FilterType::Triangle)
+        .to_luma8();
+    let mut hash = 0u64;
+    for y in 0..8 {
+        for x in 0..8 {
+            hash <<= 1;
+            if small.get_pixel(x, y)[0] > small.get_pixel(x + 1, y)[0] {
+                hash |= 1;
+            }
+        }
+    }
+    Ok(hash)
+}
+
+/// Number of bits two hashes differ in.
+pub fn hamming_distance(first: u64, second: u64) -> u32 {
+    (first ^ second).count_ones()
+}
+
+/// Whether a hash comes from a blank or flat page, see `MIN_HASH_BITS`.
+pub fn is_low_information(hash: u64) -> bool {
+    !(MIN_HASH_BITS..=u64::BITS - MIN_HASH_BITS).contains(&hash.count_ones())
+}
+
+/// Write a hash the way it shows up in reports & blocklists.
+pub fn format_hash(hash: u64) -> String {
+    format!("{:016x}", hash)
+}
+
+/// Node of a `HashIndex`, children are keyed by their distance to it.
+#[derive(Debug, Clone)]
+struct HashNode<T> {
+    hash: u64,
+    value: T,
+    children: Vec<(u32, usize)>,
+}
+
+/// BK-tree over page hashes.
+///
+/// Finds every hash within a hamming distance without comparing against
+/// all of them: a node's children are sorted by their distance to it, so
+/// by the triangle inequality only the children within `max_distance`
+/// of the query's own distance can hold a match.
+#[derive(Debug, Clone)]
+pub struct HashIndex<T> {
+    nodes: Vec<HashNode<T>>,
+}
+
+impl<T> Default for HashIndex<T> {
+    fn default() -> Self {
+        Self { nodes: Vec::new() }
+    }
+}
+
+impl<T> HashIndex<T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    /// Add a hash with the value it stands for, like the index of a page.
+    pub fn insert(&mut self, hash: u64, value: T) {
+        let new_idx = self.nodes.len();
+        let mut idx = 0;
+        while idx < new_idx {
+            let distance = hamming_distance(self.nodes[idx].hash, hash);
+            match self.nodes[idx]
+                .children
+                .iter()
+                .find(|(child_distance, _)| *child_distance == distance)
+            {
+                Some((_, child)) => idx = *child,
+                None => {
+                    self.nodes[idx].children.push((distance, new_idx));
+                    break;
+                }
+            }
+        }
+        self.nodes.push(HashNode {
+            hash,
+            value,
+            children: Vec::new(),
+        });
+    }
+
+    /// Every value whose hash is within `max_distance` of `hash`.
+    ///
+    /// Return `Vec<(u32, &T)>` | (distance, value) in no particular order.
+    pub fn find(&self, hash: u64, max_distance: u32) -> Vec<(u32, &T)> {
+        let mut found = Vec::new();
+        if self.nodes.is_empty() {
+            return found;
+        }
+        let mut pending = vec![0];
+        while let Some(idx) = pending.pop() {
+            let node = &self.nodes[idx];
+            let distance = hamming_distance(node.hash, hash);
+            if distance <= max_distance {
+                found.push((distance, &node.value));
+            }
+            pending.extend(
+                node.children
+                    .iter()
+                    .filter(|(child_distance, _)| child_distance.abs_diff(distance) <= max_distance)
+                    .map(|(_, child)| *child),
+            );
+        }
+        found
+    }
+}
+
+/// Hash of a single page.
+#[derive(Debug, Clone)]
+pub struct PageHash {
+    pub archive: PathBuf,
+    pub entry: String,
+    pub hash: u64,
+}
+
+/// Hash every page of a `.cbz` archive.
+///
+/// * `cbz_file`: `.cbz` archive to hash.
+///
+/// Return `Vec<PageHash>` in archive order.
+pub fn hash_cbz_pages<P: AsRef<Path>>(cbz_file: P) -> Result<Vec<PageHash>, CompressionError> {
+    let cbz_file = cbz_file.as_ref();
+    let file = io::BufReader::new(File::open(cbz_file)?);
+    let mut zip_file =
+        ZipArchive::new(file).map_err(|err| CompressionError::from(err).in_archive(cbz_file))?;
+    let encoding = zip_name_encoding(&mut zip_file, NameEncoding::Auto)
+        .map_err(|err| CompressionError::from(err).in_archive(cbz_file))?;
+    let mut hashes = Vec::new();
+    for idx in 0..zip_file.len() {
+        let mut inner_file = zip_file
+            .by_index(idx)
+            .map_err(|err| CompressionError::from(err).in_archive(cbz_file))?;
+        let name = zip_entry_name(&inner_file, encoding);
+        if inner_file.is_dir() || !is_image_file(&name) {
+            continue;
+        }
+        let mut file_contents = Vec::new();
+        inner_file.read_to_end(&mut file_contents).map_err(|err| {
+            CompressionError::from(ZipError::Io(err))
+                .in_archive(cbz_file)
+                .at_entry(&name)
+        })?;
+        let hash = dhash(&file_contents).map_err(|err| err.in_archive(cbz_file).at_entry(&name))?;
+        hashes.push(PageHash {
+            archive: cbz_file.to_owned(),
+            entry: name,
+            hash,
+        });
+    }
+    Ok(hashes)
+}
+
+/// Group pages that look the same.
+///
+/// Pages join a group when they are within `max_distance` of its first page,
+/// no matter if they come from the same archive or another one. Pages are
+/// looked up in a `HashIndex` instead of compared pairwise, blank & flat
+/// pages (`is_low_information`) are left out since they all look alike.
+/// * `pages`: Hashed pages of one or more archives.
+/// * `max_distance`: Largest hamming distance still counted as a duplicate.
+///
+/// Return `Vec<Vec<&PageHash>>` groups with more than one page.
+pub fn find_duplicate_pages(pages: &[PageHash], max_distance: u32) -> Vec<Vec<&PageHash>> {
+    let mut index = HashIndex::new();
+    for (idx, page) in pages.iter().enumerate() {
+        if !is_low_information(page.hash) {
+            index.insert(page.hash, idx);
+        }
+    }
+    let mut grouped = vec![false; pages.len()];
+    let mut groups = Vec::new();
+    for (idx, page) in pages.iter().enumerate() {
+        if grouped[idx] || is_low_information(page.hash) {
+            continue;
+        }
+        //NOTE: Same grouping as going through the pages in order, earlier pages start the groups.
+        let mut matches: Vec<usize> = index
+            .find(page.hash, max_distance)
+            .into_iter()
+            .map(|(_, other_idx)| *other_idx)
+            .filter(|other_idx| *other_idx > idx && !grouped[*other_idx])
+            .collect();
+        if matches.is_empty() {
+            continue;
+        }
+        matches.sort_unstable();
+        let mut group = vec![page];
+        for other_idx in matches {
+            grouped[other_idx] = true;
+            group.push(&pages[other_idx]);
+        }
+        groups.push(group);
+    }
+    groups
+}
+
+/// Page hashes the user never wants to keep, like credit pages & ads.
+#[derive(Debug, Clone, Default)]
+pub struct HashBlocklist {
+    hashes: Vec<u64>,
+    max_distance: u32,
+}
+
+impl HashBlocklist {
+    /// Load a blocklist file.
+    ///
+    /// One hex hash per line, as printed by the `duplicates` report.
+    /// Blank lines & anything after a `#` are ignored.
+    /// * `path`: Blocklist file.
+    /// * `max_distance`: Largest hamming distance still counted as a match.
+    pub fn load<P: AsRef<Path>>(path: P, max_distance: u32) -> io::Result<Self> {
+        let mut hashes = Vec::new();
+        for line in BufReader::new(File::open(path)?).lines() {
+            let line = line?;
+            let hash = line.split('#').next().unwrap_or_default().trim();
+            if hash.is_empty() {
+                continue;
+            }
+            let hash = u64::from_str_radix(hash, 16).map_err(|err| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Invalid page hash {:?}: {}", hash, err),
+                )
+            })?;
+            hashes.push(hash);
+        }
+        Ok(Self {
+            hashes,
+            max_distance,
+        })
+    }
+
+    /// Whether a page hash matches any hash on the list.
+    pub fn matches(&self, hash: u64) -> bool {
+        self.hashes
+            .iter()
+            .any(|blocked| hamming_distance(*blocked, hash) <= self.max_distance)
+    }
+
+    /// Cache settings part for this blocklist, like `strip-1a2b3c4d5e6f7a8b`.
+    ///
+    /// Stripping changes the repacked output, so a different list can't reuse cached archives.
+    pub fn settings(&self) -> String {
+        let mut hasher = blake3::Hasher::new();
+        hasher.update(&self.max_distance.to_le_bytes());
+        for hash in &self.hashes {
+            hasher.update(&hash.to_le_bytes());
+        }
+        format!("strip-{}", &hasher.finalize().to_hex()[..16])
+    }
+
+    /// Drop every page matching the blocklist from an archive.
+    ///
+    /// Pages that can't be decoded are kept, the optimiser reports them.
+    /// * `file_contents`: Entries of a single archive.
+    ///
+    /// Return `Vec<String>` names of the stripped pages.
+    pub fn strip_pages(&self, file_contents: &mut CbzEntries) -> Vec<String> {
+        let mut stripped = Vec::new();
+        file_contents.retain(|entry| {
+            let blocked =
+                is_image_file(&entry.2) && dhash(&entry.1).is_ok_and(|hash| self.matches(hash));
+            if blocked {
+                stripped.push(entry.2.to_string_lossy().to_string());
+            }
+            !blocked
+        });
+        stripped
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Spread out test hashes, without pulling in a random number crate.
+    fn test_hashes(count: usize) -> Vec<u64> {
+        let mut state = 0x9e37_79b9_7f4a_7c15u64;
+        (0..count)
+            .map(|_| {
+                state = state
+                    .wrapping_mul(6364136223846793005)
+                    .wrapping_add(1442695040888963407);
+                state
+            })
+            .collect()
+    }
+
+    fn page(hash: u64) -> PageHash {
+        PageHash {
+            archive: PathBuf::from("a.cbz"),
+            entry: format_hash(hash),
+            hash,
+        }
+    }
+
+    #[test]
+    fn index_finds_what_a_full_scan_finds() {
+        let mut hashes = test_hashes(500);
+        //NOTE: Near copies, so there is something within the distance.
+        let near_copies: Vec<u64> = hashes[..50].iter().map(|hash| hash ^ 0b1011).collect();
+        hashes.extend(near_copies);
+        let mut index = HashIndex::new();
+        for (idx, hash) in hashes.iter().enumerate() {
+            index.insert(*hash, idx);
+        }
+        assert_eq!(index.len(), hashes.len());
+        for max_distance in [0, 3, 12] {
+            for (query_idx, query) in hashes.iter().enumerate().step_by(7) {
+                let mut found: Vec<usize> = index
+                    .find(*query, max_distance)
+                    .into_iter()
+                    .map(|(_, idx)| *idx)
+                    .collect();
+                found.sort_unstable();
+                let expected: Vec<usize> = (0..hashes.len())
+                    .filter(|idx| hamming_distance(hashes[*idx], *query) <= max_distance)
+                    .collect();
+                assert_eq!(found, expected, "query {}", query_idx);
+            }
+        }
+    }
+
+    #[test]
+    fn blank_pages_are_not_duplicates() {
+        assert!(is_low_information(0));
+        assert!(is_low_information(u64::MAX));
+        assert!(is_low_information(0b1010));
+        let pages = [page(0), page(0b1), page(u64::MAX), page(u64::MAX << 2)];
+        assert!(find_duplicate_pages(&pages, DEFAULT_MAX_DISTANCE).is_empty());
+    }
+
+    #[test]
+    fn pages_group_around_the_first_one() {
+        let hashes = test_hashes(3);
+        let pages = [
+            page(hashes[0]),
+            page(hashes[1]),
+            page(hashes[0] ^ 0b11),
+            page(0),
+            page(hashes[0] ^ 0b1),
+            page(hashes[2]),
+        ];
+        let groups = find_duplicate_pages(&pages, DEFAULT_MAX_DISTANCE);
+        let groups: Vec<Vec<u64>> = groups
+            .iter()
+            .map(|group| group.iter().map(|page| page.hash).collect())
+            .collect();
+        assert_eq!(groups, [vec![hashes[0], hashes[0] ^ 0b11, hashes[0] ^ 0b1]]);
+    }
+}
@@ -0,0 +1,849 @@
+use crate::cache_actions::{hash_archive, CacheHit, ContentCache};
+use crate::cbz_actions::{
+    can_copy_raw, compress_dir_and_files_to_cbz, compress_images_with_img, image_codec,
+    image_dimensions, is_image_file, page_manifest, read_archive_metadata,
+    read_dir_and_files_from_cbz, repack_cbz_passthrough, round_trip_cbz, verify_written_cbz,
+    ArchiveMetadata, CbzEntries,
+};
+use crate::config_actions::{with_deflate, PipelineConfig};
+use crate::dedupe_actions::content_hash;
+use crate::encoding_actions::NameEncoding;
+use crate::err_impl::CompressionError;
+use crate::journal_actions::{JobJournal, JobState};
+use crate::phash_actions::HashBlocklist;
+use crate::progress_actions::{silent, Progress, ProgressEvent};
+use crate::report_actions::{ArchiveReport, ArchiveStatus, PageReport, RunReport};
+use crate::salvage_actions::{salvage_cbz, SalvageReport};
+use crate::stage_actions::{ImageChain, Page};
+use rayon::prelude::*;
+use rayon::ThreadPoolBuilder;
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use walkdir::WalkDir;
+
+/// Name of the folder the optimised archives go to when no output is set.
+pub const DEFAULT_OUTPUT_DIR_NAME: &str = "tmp";
+
+/// Find every `.cbz` archive under a folder.
+///
+/// * `file_list`: Folder to walk, or a single `.cbz` file.
+///
+/// Return `Vec<PathBuf>` in walk order.
+pub fn cbz_file_list(
+    file_list: Arc<impl AsRef<Path> + Send + Sync>,
+) -> Result<Vec<PathBuf>, CompressionError> {
+    let mut discovered_entries = Vec::new();
+
+    for entry in WalkDir::new(file_list.as_ref())
+        .into_iter()
+        .filter_map(|e| e.ok())
+    {
+        if let Some(ext) = entry.path().extension() {
+            if ext == "cbz" {
+                discovered_entries.push(entry.path().to_owned());
+            }
+        }
+    }
+    Ok(discovered_entries)
+}
+
+/// Find every `.cbz` archive under a folder, except in its default output folder.
+///
+/// What a run wrote to `<folder>/tmp` isn't picked up again as input.
+/// * `dir_path`: Folder to walk.
+///
+/// Return `Vec<PathBuf>` in walk order.
+pub fn input_cbz_files(
+    dir_path: Arc<impl AsRef<Path> + Send + Sync>,
+) -> Result<Vec<PathBuf>, CompressionError> {
+    let output_dir = dir_path.as_ref().as_ref().join(DEFAULT_OUTPUT_DIR_NAME);
+    Ok(cbz_file_list(dir_path)?
+        .into_iter()
+        .filter(|filez| !filez.starts_with(&output_dir))
+        .collect())
+}
+
+/// Optimise-and-repack run over a set of archives.
+///
+/// Built up with the setters below then started with `run`. Every archive
+/// found under the inputs goes through the stages in order: extract (or
+/// salvage), strip blocklisted pages, run the pages through the stages of
+/// `config` (or a custom `image_chain`),
+/// repack, then verify. A job journal & content-hash cache in the output
+/// folder let an interrupted or repeated run skip what is already done.
+/// Nothing is printed, progress goes to the `progress` sink instead.
+#[derive(Debug, Clone)]
+pub struct Pipeline {
+    inputs: Vec<PathBuf>,
+    output_dir: Option<PathBuf>,
+    config: PipelineConfig,
+    image_chain: Option<ImageChain>,
+    threads: usize,
+    parallel_archives: usize,
+    restart: bool,
+    use_cache: bool,
+    keep_going: bool,
+    salvage: bool,
+    verify_output: bool,
+    passthrough: bool,
+    blocklist: Option<HashBlocklist>,
+    name_encoding: NameEncoding,
+    progress: Progress,
+}
+
+impl Default for Pipeline {
+    fn default() -> Self {
+        Self {
+            inputs: Vec::new(),
+            output_dir: None,
+            config: PipelineConfig::default(),
+            image_chain: None,
+            threads: 0,
+            parallel_archives: 0,
+            restart: false,
+            use_cache: true,
+            keep_going: false,
+            salvage: false,
+            verify_output: false,
+            passthrough: false,
+            blocklist: None,
+            name_encoding: NameEncoding::Auto,
+            progress: silent(),
+        }
+    }
+}
+
+impl Pipeline {
+    /// Pipeline with the default `archive` settings and no inputs yet.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a folder to scan for `.cbz` archives, or a single `.cbz` file.
+    pub fn input<P: AsRef<Path>>(mut self, path: P) -> Self {
+        self.inputs.push(path.as_ref().to_owned());
+        self
+    }
+
+    /// Folder the optimised archives are written to.
+    ///
+    /// Defaults to `tmp` inside the first input folder.
+    pub fn output_dir<P: AsRef<Path>>(mut self, path: P) -> Self {
+        self.output_dir = Some(path.as_ref().to_owned());
+        self
+    }
+
+    /// Settings the pages & archives are written with.
+    pub fn config(mut self, config: PipelineConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Stages the pages go through, instead of the ones named in `config`.
+    ///
+    /// Lets stages from other crates run, see `StageRegistry` to have
+    /// them picked by name from the configuration instead.
+    pub fn image_chain(mut self, chain: ImageChain) -> Self {
+        self.image_chain = Some(chain);
+        self
+    }
+
+    /// Worker threads for unpacking, page compression & repacking, 0 uses every core.
+    pub fn threads(mut self, threads: usize) -> Self {
+        self.threads = threads;
+        self
+    }
+
+    /// Archives unpacked & held in memory at once, 0 matches the thread count.
+    ///
+    /// Their pages share the worker threads, so fewer archives mostly saves
+    /// memory, more helps when archives only have a few pages each.
+    pub fn parallel_archives(mut self, parallel_archives: usize) -> Self {
+        self.parallel_archives = parallel_archives;
+        self
+    }
+
+    /// Ignore the job journal of a previous run and reprocess every archive.
+    pub fn restart(mut self, restart: bool) -> Self {
+        self.restart = restart;
+        self
+    }
+
+    /// Skip archives already optimised with the same settings, on by default.
+    pub fn use_cache(mut self, use_cache: bool) -> Self {
+        self.use_cache = use_cache;
+        self
+    }
+
+    /// Record failing archives & pages in the report instead of stopping.
+    ///
+    /// A failed archive gets no output, a failed page is repacked as is.
+    pub fn keep_going(mut self, keep_going: bool) -> Self {
+        self.keep_going = keep_going;
+        self
+    }
+
+    /// Repack the intact entries of archives the zip reader can't open.
+    pub fn salvage(mut self, salvage: bool) -> Self {
+        self.salvage = salvage;
+        self
+    }
+
+    /// Round-trip check every written archive against its source pages.
+    pub fn verify_output(mut self, verify_output: bool) -> Self {
+        self.verify_output = verify_output;
+        self
+    }
+
+    /// Store pages without Deflate & copy unmodified entries raw from the source.
+    pub fn passthrough(mut self, passthrough: bool) -> Self {
+        self.passthrough = passthrough;
+        self
+    }
+
+    /// Strip pages matching these hashes before repacking.
+    pub fn strip_blocklist(mut self, blocklist: HashBlocklist) -> Self {
+        self.blocklist = Some(blocklist);
+        self
+    }
+
+    /// How to decode entry names without the UTF-8 flag.
+    pub fn name_encoding(mut self, name_encoding: NameEncoding) -> Self {
+        self.name_encoding = name_encoding;
+        self
+    }
+
+    /// Where progress events go, they're dropped by default.
+    pub fn progress(mut self, progress: Progress) -> Self {
+        self.progress = progress;
+        self
+    }
+
+    /// Folder the optimised archives end up in.
+    pub fn resolved_output_dir(&self) -> Option<PathBuf> {
+        self.output_dir.clone().or_else(|| {
+            self.inputs
+                .first()
+                .map(|input| input.join(DEFAULT_OUTPUT_DIR_NAME))
+        })
+    }
+
+    /// Settings that change the repacked output, as part of the cache key.
+    ///
+    /// Return `String` like `jpeg-90,deflate-9,passthrough`
+    pub fn settings(&self) -> String {
+        let mut settings = match &self.image_chain {
+            Some(chain) => with_deflate(&chain.settings(), self.config.deflate_level),
+            None => self.config.settings(),
+        };
+        if let Some(blocklist) = &self.blocklist {
+            settings = format!("{},{}", settings, blocklist.settings());
+        }
+        if self.passthrough {
+            settings.push_str(",passthrough");
+        }
+        if self.name_encoding != NameEncoding::Auto {
+            settings = format!("{},names-{}", settings, self.name_encoding);
+        }
+        settings
+    }
+
+    /// Run the pipeline over every input.
+    ///
+    /// The work all blocks, on zip I/O or on the worker pool, so it's handed
+    /// to tokio's blocking pool and the async runtime stays responsive.
+    ///
+    /// Return `RunReport` of every archive & page that was handled.
+    pub async fn run(self) -> Result<RunReport, CompressionError> {
+        tokio::task::spawn_blocking(move || self.run_blocking())
+            .await
+            .map_err(io::Error::other)?
+    }
+
+    /// Run the pipeline over every input on the calling thread.
+    ///
+    /// Hashing, unpacking, page compression & repacking all run on one
+    /// worker pool of `threads` threads. Archives go through in batches of
+    /// `parallel_archives`, and the pages of every archive in a batch are
+    /// spread over the whole pool, so a batch of big archives keeps every
+    /// core busy without holding the whole library in memory.
+    ///
+    /// Return `RunReport` of every archive & page that was handled.
+    pub fn run_blocking(self) -> Result<RunReport, CompressionError> {
+        let run_time = Instant::now();
+        let chain = match &self.image_chain {
+            Some(chain) => chain.clone(),
+            None => ImageChain::from_config(&self.config)?,
+        };
+        let tmp_output_path = self
+            .resolved_output_dir()
+            .ok_or_else(|| CompressionError::config("The pipeline has no inputs"))?;
+        let mut archives = Vec::new();
+        for input in &self.inputs {
+            //NOTE: Output can live inside the input dir, don't pick it up again.
+            archives.extend(
+                cbz_file_list(Arc::new(input))?
+                    .into_iter()
+                    .filter(|filez| !filez.starts_with(&tmp_output_path))
+                    .map(|filez| {
+                        let output = self.output_path(&tmp_output_path, input, &filez);
+                        (filez, output)
+                    }),
+            );
+        }
+        let pool = ThreadPoolBuilder::new()
+            .num_threads(self.threads)
+            .thread_name(|idx| format!("comics-archiver-{}", idx))
+            .build()
+            .map_err(io::Error::other)?;
+        let total_files = archives.len() as u64;
+        let progress = &self.progress;
+        progress.event(ProgressEvent::RunStarted {
+            archives: total_files,
+        });
+        let mut report = RunReport::default();
+        std::fs::create_dir_all(&tmp_output_path)?;
+
+        //NOTE: The journal lets an interrupted run pick up where it left off.
+        let mut journal = JobJournal::open(&tmp_output_path)?;
+        if self.restart {
+            journal.clear()?;
+        }
+        //NOTE: With the cache on it decides what gets skipped, since unlike the
+        //journal it notices changed archives & changed settings.
+        let cache = if self.use_cache {
+            Some(ContentCache::open(&tmp_output_path)?)
+        } else {
+            None
+        };
+        let settings = self.settings();
+        //NOTE: Hashing reads every archive in full, so it's spread over the pool too.
+        let input_hashes: Vec<Option<io::Result<String>>> = match &cache {
+            Some(_) => pool.install(|| {
+                archives
+                    .par_iter()
+                    .map(|(filez, _)| Some(hash_archive(filez)))
+                    .collect()
+            }),
+            None => archives.iter().map(|_| None).collect(),
+        };
+        let mut archive_hashes: HashMap<PathBuf, String> = HashMap::new();
+        let mut raw_file_list = Vec::new();
+        //NOTE: Content of every archive that has an output, skipped ones included,
+        //so a duplicate is found whether or not the archive it copies was done before.
+        let mut seen_content = HashSet::new();
+        let mut known_duplicates = Vec::new();
+        for ((filez, tmp_file_path), input_hash) in archives.into_iter().zip(input_hashes) {
+            //NOTE: An archive that can't even be read fails like any other.
+            let input_hash = match input_hash.transpose() {
+                Ok(input_hash) => input_hash,
+                Err(err) => {
+                    let err = CompressionError::from(err).in_archive(&filez);
+                    if !self.keep_going {
+                        return Err(err);
+                    }
+                    report.push(failed_archive_report(filez, err));
+                    finished(progress, &report);
+                    continue;
+                }
+            };
+            if let (Some(cache), Some(input_hash)) = (&cache, &input_hash) {
+                match cache.lookup(input_hash, &settings) {
+                    Some(CacheHit {
+                        output: Some(cached),
+                        content,
+                    }) if seen_content.insert(content.to_string()) => {
+                        //NOTE: Same content under another name, reuse the earlier output.
+                        if cached != tmp_file_path {
+                            create_parent_dir(&tmp_file_path)?;
+                            std::fs::copy(cached, &tmp_file_path)?;
+                        }
+                        report.push(skipped_archive_report(
+                            &filez,
+                            &tmp_file_path,
+                            ArchiveStatus::Cached,
+                        )?);
+                        finished(progress, &report);
+                        continue;
+                    }
+                    //NOTE: Only a duplicate if what it copies still has an output, known once
+                    //every archive went through here.
+                    Some(CacheHit { content, .. }) => {
+                        known_duplicates.push((
+                            filez,
+                            tmp_file_path,
+                            Some(input_hash.clone()),
+                            content.to_string(),
+                        ));
+                        continue;
+                    }
+                    None => {}
+                }
+            } else if journal.is_finished(&filez) && tmp_file_path.exists() {
+                if let Some(content) = journal.content(&filez) {
+                    seen_content.insert(content.to_string());
+                }
+                report.push(skipped_archive_report(
+                    &filez,
+                    &tmp_file_path,
+                    ArchiveStatus::Resumed,
+                )?);
+                finished(progress, &report);
+                continue;
+            } else if journal.state(&filez) == Some(JobState::Duplicate) {
+                if let Some(content) = journal.content(&filez) {
+                    known_duplicates.push((filez, tmp_file_path, None, content.to_string()));
+                    continue;
+                }
+            }
+            if let Some(input_hash) = input_hash {
+                archive_hashes.insert(filez.clone(), input_hash);
+            }
+            journal.record(&filez, JobState::Pending)?;
+            raw_file_list.push((filez, tmp_file_path));
+        }
+        for (filez, tmp_file_path, input_hash, content) in known_duplicates {
+            if seen_content.contains(&content) {
+                journal.record(&filez, JobState::Duplicate)?;
+                report.push(duplicate_archive_report(&filez)?);
+                finished(progress, &report);
+                continue;
+            }
+            //NOTE: The archive it copied is gone or changed, so this one is done after all.
+            if let Some(input_hash) = input_hash {
+                archive_hashes.insert(filez.clone(), input_hash);
+            }
+            journal.record(&filez, JobState::Pending)?;
+            raw_file_list.push((filez, tmp_file_path));
+        }
+        if total_files > 0 && raw_file_list.is_empty() {
+            progress.event(ProgressEvent::Message(format!(
+                "Every archive is already done, see {}",
+                journal.path().display()
+            )));
+        }
+
+        let run = RunState {
+            chain: &chain,
+            settings: &settings,
+            archive_hashes: &archive_hashes,
+            journal: Mutex::new(journal),
+            cache: cache.map(Mutex::new),
+        };
+        let parallel_archives = match self.parallel_archives {
+            0 => pool.current_num_threads(),
+            parallel_archives => parallel_archives,
+        };
+        for batch in raw_file_list.chunks(parallel_archives) {
+            let extracted: Vec<_> = pool.install(|| {
+                batch
+                    .par_iter()
+                    .map(|(filez, _)| self.extract_archive(filez))
+                    .collect()
+            });
+            let mut batch_data = Vec::with_capacity(batch.len());
+            for ((filez, output_path), extracted) in batch.iter().zip(extracted) {
+                match extracted {
+                    Ok((data, salvage_report)) => {
+                        let content = content_hash(&data);
+                        //NOTE: Near duplicates from other release groups are left to the `dedupe` command.
+                        if seen_content.insert(content.clone()) {
+                            batch_data.push((filez, output_path, data, salvage_report, content));
+                            continue;
+                        }
+                        {
+                            let mut journal = run.journal.lock().unwrap();
+                            journal.record_content(filez, &content)?;
+                            journal.record(filez, JobState::Duplicate)?;
+                        }
+                        if let (Some(cache), Some(input_hash)) =
+                            (&run.cache, run.archive_hashes.get(filez))
+                        {
+                            cache.lock().unwrap().insert_duplicate(
+                                input_hash,
+                                run.settings,
+                                &content,
+                            )?;
+                        }
+                        report.push(duplicate_archive_report(filez)?);
+                        finished(progress, &report);
+                    }
+                    Err(err) if self.keep_going => {
+                        report.push(failed_archive_report(filez.to_owned(), err));
+                        finished(progress, &report);
+                    }
+                    Err(err) => return Err(err),
+                }
+            }
+
+            let results: Vec<_> = pool.install(|| {
+                batch_data
+                    .par_iter_mut()
+                    .map(
+                        |(source_path, output_path, imgs, salvage_report, content)| {
+                            self.process_archive(
+                                source_path,
+                                output_path,
+                                imgs,
+                                salvage_report.as_ref(),
+                                content,
+                                &run,
+                            )
+                        },
+                    )
+                    .collect()
+            });
+            for (result, (source_path, ..)) in results.into_iter().zip(&batch_data) {
+                match result {
+                    Ok(archive_report) => report.push(archive_report),
+                    Err(err) if self.keep_going => {
+                        report.push(failed_archive_report(source_path.to_path_buf(), err));
+                        finished(progress, &report);
+                    }
+                    Err(err) => return Err(err),
+                }
+            }
+        }
+        run.journal.into_inner().unwrap().compact()?;
+        progress.event(ProgressEvent::RunFinished);
+        report.time_ms = run_time.elapsed().as_millis() as u64;
+        Ok(report)
+    }
+
+    /// Where the output of `archive` goes inside `output_dir`.
+    ///
+    /// Archives keep their path relative to the input they were found
+    /// under, so chapters with the same file name in different series
+    /// folders don't overwrite each other. With several inputs that path
+    /// starts with the name of the input.
+    fn output_path(&self, output_dir: &Path, input: &Path, archive: &Path) -> PathBuf {
+        let relative = match archive.strip_prefix(input) {
+            Ok(relative) if !relative.as_os_str().is_empty() => relative,
+            //NOTE: The input is the archive itself.
+            _ => Path::new(archive.file_name().unwrap_or_default()),
+        };
+        match input.file_name() {
+            Some(input_name) if self.inputs.len() > 1 && input.is_dir() => {
+                output_dir.join(input_name).join(relative)
+            }
+            _ => output_dir.join(relative),
+        }
+    }
+
+    /// Unpack one archive, salvaging what's left of it if that's on.
+    ///
+    /// Return `(CbzEntries, Option<SalvageReport>)`, the report only for salvaged archives.
+    fn extract_archive(
+        &self,
+        filez: &Path,
+    ) -> Result<(CbzEntries, Option<SalvageReport>), CompressionError> {
+        match read_dir_and_files_from_cbz(filez, self.name_encoding) {
+            Ok(data) => Ok((data, None)),
+            //NOTE: Half downloaded chapters have no central directory, so
+            //fall back to scanning the local headers.
+            Err(CompressionError::ZipError { .. }) if self.salvage => {
+                let (entries, salvage_report) = salvage_cbz(filez, self.name_encoding)?;
+                if entries.is_empty() {
+                    return Err(
+                        CompressionError::container("Nothing could be salvaged").in_archive(filez)
+                    );
+                }
+                self.progress.event(ProgressEvent::Warning {
+                    archive: Some(filez.to_owned()),
+                    message: format!(
+                        "Salvaged {} entries, lost {}",
+                        salvage_report.recovered.len(),
+                        salvage_report.lost.len()
+                    ),
+                });
+                Ok((entries, Some(salvage_report)))
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Optimise, repack, write & verify one unpacked archive.
+    ///
+    /// Each archive goes through this on its own so the journal always
+    /// reflects what is safely on disk, its pages are compressed in parallel.
+    /// * `source_path`: Source `.cbz` archive.
+    /// * `output_path`: Where the optimised archive is written.
+    /// * `imgs`: Its entries, pages get replaced by their compressed version.
+    /// * `salvage_report`: What was lost, if the archive had to be salvaged.
+    /// * `content`: Hash of its unpacked entries, from `content_hash`.
+    /// * `run`: State shared by every archive of the run.
+    ///
+    /// Return `ArchiveReport`
+    fn process_archive(
+        &self,
+        source_path: &Path,
+        output_path: &Path,
+        imgs: &mut CbzEntries,
+        salvage_report: Option<&SalvageReport>,
+        content: &str,
+        run: &RunState,
+    ) -> Result<ArchiveReport, CompressionError> {
+        let progress = &self.progress;
+        let archive_time = Instant::now();
+        let mut archive_report = ArchiveReport {
+            archive: source_path.to_owned(),
+            status: ArchiveStatus::Optimised,
+            original_size: source_path.metadata()?.len(),
+            ..Default::default()
+        };
+        if let Some(salvage_report) = salvage_report {
+            archive_report.status = ArchiveStatus::Salvaged;
+            for (entry, reason) in &salvage_report.lost {
+                archive_report
+                    .warnings
+                    .push(format!("Lost entry {}: {}", entry, reason));
+            }
+        }
+        progress.event(ProgressEvent::ArchiveStarted {
+            archive: source_path.to_owned(),
+            pages: imgs.iter().filter(|entry| is_image_file(&entry.2)).count() as u64,
+        });
+        if let Some(blocklist) = &self.blocklist {
+            for stripped in blocklist.strip_pages(imgs) {
+                archive_report
+                    .warnings
+                    .push(format!("Stripped blocklisted page {}", stripped));
+            }
+        }
+        let manifest = self.verify_output.then(|| page_manifest(imgs, run.chain));
+        //NOTE: A salvaged source has no readable central directory to take this from.
+        let metadata = if salvage_report.is_some() {
+            ArchiveMetadata::default()
+        } else {
+            read_archive_metadata(source_path, self.name_encoding)?
+        };
+        //NOTE: Decided before any page is decoded, pages copied raw skip the chain
+        //since whatever it hands back is thrown away.
+        let copy_raw: Vec<bool> = imgs
+            .iter()
+            .map(|entry| {
+                self.passthrough
+                    && salvage_report.is_none()
+                    && run.chain.keeps_pages()
+                    && metadata
+                        .entries
+                        .get(entry.2.to_string_lossy().as_ref())
+                        .is_some_and(can_copy_raw)
+            })
+            .collect();
+        let page_reports: Vec<Option<PageReport>> = imgs
+            .par_iter_mut()
+            .zip(&copy_raw)
+            .map(|(inner_items, copy_raw)| {
+                let chain = (!copy_raw).then_some(run.chain);
+                self.process_page(source_path, inner_items, chain)
+            })
+            .collect::<Result<_, _>>()?;
+        //NOTE: Pages that failed or came back the same keep their original data,
+        //like anything that isn't a page.
+        let modified: Vec<bool> = page_reports
+            .iter()
+            .map(|page_report| page_report.as_ref().is_some_and(|page| page.modified))
+            .collect();
+        archive_report
+            .pages
+            .extend(page_reports.into_iter().flatten());
+        run.journal
+            .lock()
+            .unwrap()
+            .record(source_path, JobState::Optimised)?;
+
+        let entry_count = imgs.len();
+        let item = if self.passthrough {
+            //NOTE: A salvaged source can't be read by the zip reader, so nothing is copied raw.
+            let source_cbz = salvage_report.is_none().then_some(source_path);
+            repack_cbz_passthrough(source_cbz, imgs, &modified, &metadata, &self.config)
+        } else {
+            compress_dir_and_files_to_cbz(std::mem::take(imgs), &metadata, &self.config)
+        }
+        .map_err(|err| err.in_archive(source_path))?;
+        let tmp_file_path = output_path.to_owned();
+        let bytes = item.1.len() as u64;
+        create_parent_dir(&tmp_file_path)?;
+        std::fs::write(&tmp_file_path, item.1)?;
+        progress.event(ProgressEvent::BytesWritten {
+            path: tmp_file_path.clone(),
+            bytes,
+        });
+        run.journal
+            .lock()
+            .unwrap()
+            .record(source_path, JobState::Written)?;
+
+        if let Some(manifest) = &manifest {
+            if let Err(err) = round_trip_cbz(&tmp_file_path, manifest) {
+                //NOTE: Don't leave a broken archive behind.
+                let _ = std::fs::remove_file(&tmp_file_path);
+                return Err(err);
+            }
+        }
+        if verify_written_cbz(&tmp_file_path, entry_count)? {
+            {
+                let mut journal = run.journal.lock().unwrap();
+                journal.record_content(source_path, content)?;
+                journal.record(source_path, JobState::Verified)?;
+            }
+            if let (Some(cache), Some(input_hash)) =
+                (&run.cache, run.archive_hashes.get(source_path))
+            {
+                cache
+                    .lock()
+                    .unwrap()
+                    .insert(input_hash, run.settings, content, &tmp_file_path)?;
+            }
+        } else {
+            //NOTE: Don't leave a broken archive behind.
+            let _ = std::fs::remove_file(&tmp_file_path);
+            return Err(
+                CompressionError::container("Repacked archive failed verification")
+                    .in_archive(&tmp_file_path),
+            );
+        }
+        archive_report.new_size = tmp_file_path.metadata()?.len();
+        archive_report.output = Some(tmp_file_path);
+        archive_report.time_ms = archive_time.elapsed().as_millis() as u64;
+        progress.event(ProgressEvent::ArchiveFinished {
+            archive: archive_report.archive.clone(),
+            output: archive_report.output.clone(),
+            status: archive_report.status,
+        });
+        Ok(archive_report)
+    }
+
+    /// Run one entry through the image chain.
+    ///
+    /// * `chain`: Stages the page goes through, `None` keeps it as it is.
+    ///
+    /// Return `Option<PageReport>`, `None` for entries that aren't pages
+    /// (like `ComicInfo.xml`), those are repacked as is.
+    fn process_page(
+        &self,
+        source_path: &Path,
+        inner_items: &mut (String, Vec<u8>, PathBuf),
+        chain: Option<&ImageChain>,
+    ) -> Result<Option<PageReport>, CompressionError> {
+        if !is_image_file(&inner_items.2) {
+            return Ok(None);
+        }
+        let page_time = Instant::now();
+        let dimensions = image_dimensions(&inner_items.1);
+        let mut page_report = PageReport {
+            name: inner_items.2.clone(),
+            original_size: inner_items.1.len() as u64,
+            width: dimensions.map(|d| d.0),
+            height: dimensions.map(|d| d.1),
+            ..Default::default()
+        };
+        if let Some(chain) = chain {
+            let page = Page::new(source_path, &inner_items.2, inner_items.1.clone());
+            let compressed = compress_images_with_img(page, chain).map_err(|err| {
+                err.in_archive(source_path)
+                    .at_entry(inner_items.2.to_string_lossy())
+            });
+            match compressed {
+                //NOTE: Like with the `keep` codec, a page can come back as it went in.
+                Ok(compressed) if compressed == inner_items.1 => {}
+                Ok(compressed) => {
+                    inner_items.1 = compressed;
+                    page_report.modified = true;
+                }
+                //NOTE: The page keeps its original data.
+                Err(err) if self.keep_going => page_report.errors.push(err.to_string()),
+                Err(err) => return Err(err),
+            }
+        }
+        page_report.new_size = inner_items.1.len() as u64;
+        page_report.codec = image_codec(&inner_items.1).unwrap_or_default();
+        page_report.time_ms = page_time.elapsed().as_millis() as u64;
+        if page_report.new_size > page_report.original_size {
+            page_report.warnings.push(format!(
+                "Page grew from {} to {} bytes",
+                page_report.original_size, page_report.new_size
+            ));
+        }
+        self.progress.event(ProgressEvent::PageProcessed {
+            archive: source_path.to_owned(),
+            page: page_report.name.clone(),
+            original_size: page_report.original_size,
+            new_size: page_report.new_size,
+        });
+        Ok(Some(page_report))
+    }
+}
+
+/// State shared by the archives of one run.
+struct RunState<'a> {
+    chain: &'a ImageChain,
+    settings: &'a str,
+    archive_hashes: &'a HashMap<PathBuf, String>,
+    journal: Mutex<JobJournal>,
+    cache: Option<Mutex<ContentCache>>,
+}
+
+/// Make the folder an output file goes in, series folders are mirrored from the input.
+fn create_parent_dir(path: &Path) -> io::Result<()> {
+    match path.parent() {
+        Some(parent) => std::fs::create_dir_all(parent),
+        None => Ok(()),
+    }
+}
+
+/// Tell `progress` the archive last added to `report` is done.
+fn finished(progress: &Progress, report: &RunReport) {
+    if let Some(archive_report) = report.archives.last() {
+        progress.event(ProgressEvent::ArchiveFinished {
+            archive: archive_report.archive.clone(),
+            output: archive_report.output.clone(),
+            status: archive_report.status,
+        });
+    }
+}
+
+/// Report entry for an archive that failed, it gets no output.
+fn failed_archive_report(source_path: PathBuf, err: CompressionError) -> ArchiveReport {
+    ArchiveReport {
+        original_size: source_path.metadata().map(|meta| meta.len()).unwrap_or(0),
+        archive: source_path,
+        status: ArchiveStatus::Failed,
+        errors: vec![err.to_string()],
+        ..Default::default()
+    }
+}
+
+/// Report entry for an archive with the same content as another one of the run, it gets no output.
+fn duplicate_archive_report(source_path: &Path) -> io::Result<ArchiveReport> {
+    Ok(ArchiveReport {
+        original_size: source_path.metadata()?.len(),
+        archive: source_path.to_owned(),
+        status: ArchiveStatus::Duplicate,
+        warnings: vec!["Same content as another archive, not repacked".to_string()],
+        ..Default::default()
+    })
+}
+
+/// Report entry for an archive that was skipped since its output is already there.
+/// * `source_path`: Source `.cbz` archive.
+/// * `output_path`: Existing optimised archive.
+/// * `status`: Why it was skipped, `Cached` or `Resumed`.
+fn skipped_archive_report<P: AsRef<Path>>(
+    source_path: &Path,
+    output_path: P,
+    status: ArchiveStatus,
+) -> io::Result<ArchiveReport> {
+    Ok(ArchiveReport {
+        archive: source_path.to_owned(),
+        output: Some(output_path.as_ref().to_owned()),
+        status,
+        original_size: source_path.metadata()?.len(),
+        new_size: output_path.as_ref().metadata()?.len(),
+        ..Default::default()
+    })
+}
@@ -0,0 +1,118 @@
+use crate::report_actions::ArchiveStatus;
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::{mpsc, Arc};
+
+/// Something that happened during a run, for whoever is watching it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProgressEvent {
+    /// A run over `archives` archives started.
+    RunStarted { archives: u64 },
+    /// An archive was unpacked & its `pages` pages are about to be processed.
+    ArchiveStarted { archive: PathBuf, pages: u64 },
+    /// A page of `archive` went through the pipeline.
+    PageProcessed {
+        archive: PathBuf,
+        page: PathBuf,
+        original_size: u64,
+        new_size: u64,
+    },
+    /// `bytes` were written to `path`.
+    BytesWritten { path: PathBuf, bytes: u64 },
+    /// An archive is done with.
+    ArchiveFinished {
+        archive: PathBuf,
+        output: Option<PathBuf>,
+        status: ArchiveStatus,
+    },
+    /// Something went wrong without stopping the run.
+    Warning {
+        archive: Option<PathBuf>,
+        message: String,
+    },
+    /// Anything else worth telling the user.
+    Message(String),
+    /// The run is over.
+    RunFinished,
+}
+
+impl fmt::Display for ProgressEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProgressEvent::RunStarted { archives } => write!(f, "Processing {} archives", archives),
+            ProgressEvent::ArchiveStarted { archive, pages } => {
+                write!(f, "Started {} ({} pages)", archive.display(), pages)
+            }
+            ProgressEvent::PageProcessed { archive, page, .. } => {
+                write!(f, "Processed {}:{}", archive.display(), page.display())
+            }
+            ProgressEvent::BytesWritten { path, bytes } => {
+                write!(f, "Wrote {} bytes to {}", bytes, path.display())
+            }
+            ProgressEvent::ArchiveFinished {
+                archive,
+                output: Some(output),
+                status,
+            } => write!(
+                f,
+                "{} {} -> {}",
+                status,
+                archive.display(),
+                output.display()
+            ),
+            ProgressEvent::ArchiveFinished {
+                archive, status, ..
+            } => write!(f, "{} {}", status, archive.display()),
+            ProgressEvent::Warning {
+                archive: Some(archive),
+                message,
+            } => write!(f, "{}: {}", archive.display(), message),
+            ProgressEvent::Warning { message, .. } | ProgressEvent::Message(message) => {
+                write!(f, "{}", message)
+            }
+            ProgressEvent::RunFinished => write!(f, "Done"),
+        }
+    }
+}
+
+/// Receives the progress events of a run.
+///
+/// Called from the worker threads, so it has to be cheap & thread safe.
+pub trait ProgressSink: Send + Sync {
+    fn event(&self, event: ProgressEvent);
+}
+
+impl fmt::Debug for dyn ProgressSink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "ProgressSink")
+    }
+}
+
+/// Shared handle to a `ProgressSink`.
+pub type Progress = Arc<dyn ProgressSink>;
+
+/// Drops every event, the default for library users.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Silent;
+
+impl ProgressSink for Silent {
+    fn event(&self, _event: ProgressEvent) {}
+}
+
+/// Sink that does nothing, for builders that don't get one.
+pub fn silent() -> Progress {
+    Arc::new(Silent)
+}
+
+//NOTE: A closed receiver just means nobody listens anymore, so send errors are ignored.
+impl ProgressSink for mpsc::Sender<ProgressEvent> {
+    fn event(&self, event: ProgressEvent) {
+        let _ = self.send(event);
+    }
+}
+
+impl ProgressSink for tokio::sync::mpsc::UnboundedSender<ProgressEvent> {
+    fn event(&self, event: ProgressEvent) {
+        let _ = self.send(event);
+    }
+}
@@ -0,0 +1,269 @@
+use humantime::format_duration;
+use indicatif::HumanBytes;
+use serde::Serialize;
+use std::fmt::{self, Write as _};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// What happened to a single page of an archive.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct PageReport {
+    pub name: PathBuf,
+    pub original_size: u64,
+    pub new_size: u64,
+    pub codec: String,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub time_ms: u64,
+    /// Whether the page came out with other bytes than it went in,
+    /// unmodified pages are copied raw by a passthrough repack.
+    pub modified: bool,
+    pub warnings: Vec<String>,
+    pub errors: Vec<String>,
+}
+
+/// What a run did with an archive.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ArchiveStatus {
+    /// Pages were compressed & the archive repacked.
+    #[default]
+    Optimised,
+    /// Repacked from what could be read of a damaged archive.
+    Salvaged,
+    /// The output of an earlier run with the same settings was kept.
+    Cached,
+    /// An interrupted run had already finished it.
+    Resumed,
+    /// Same content as another archive, not repacked.
+    Duplicate,
+    Failed,
+}
+
+impl fmt::Display for ArchiveStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad(match self {
+            ArchiveStatus::Optimised => "optimised",
+            ArchiveStatus::Salvaged => "salvaged",
+            ArchiveStatus::Cached => "cached",
+            ArchiveStatus::Resumed => "resumed",
+            ArchiveStatus::Duplicate => "duplicate",
+            ArchiveStatus::Failed => "failed",
+        })
+    }
+}
+
+/// What happened to a single archive of the run.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ArchiveReport {
+    pub archive: PathBuf,
+    pub output: Option<PathBuf>,
+    pub status: ArchiveStatus,
+    pub original_size: u64,
+    pub new_size: u64,
+    pub time_ms: u64,
+    pub pages: Vec<PageReport>,
+    pub warnings: Vec<String>,
+    pub errors: Vec<String>,
+}
+
+/// Report of a whole `Pipeline` run.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct RunReport {
+    pub archives: Vec<ArchiveReport>,
+    pub total_original_size: u64,
+    pub total_new_size: u64,
+    pub time_ms: u64,
+}
+
+/// Percentage saved going from `original` to `new` bytes.
+pub fn saving_percent(original: u64, new: u64) -> f64 {
+    if original == 0 {
+        return 0.0;
+    }
+    (1.0 - new as f64 / original as f64) * 100.0
+}
+
+/// Quote a CSV field if it needs it.
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+impl RunReport {
+    /// Add an archive to the report & update the totals.
+    ///
+    /// Only archives with an output count towards the totals.
+    pub fn push(&mut self, archive: ArchiveReport) {
+        if archive.output.is_some() {
+            self.total_original_size += archive.original_size;
+            self.total_new_size += archive.new_size;
+        }
+        self.archives.push(archive);
+    }
+
+    /// Every error of the run as `archive: error` or `archive: page: error` lines.
+    pub fn failures(&self) -> Vec<String> {
+        let mut failures = Vec::new();
+        for archive in &self.archives {
+            for err in &archive.errors {
+                failures.push(format!("{}: {}", archive.archive.display(), err));
+            }
+            for page in &archive.pages {
+                for err in &page.errors {
+                    failures.push(format!(
+                        "{}: {}: {}",
+                        archive.archive.display(),
+                        page.name.display(),
+                        err
+                    ));
+                }
+            }
+        }
+        failures
+    }
+
+    /// Write the report as pretty printed JSON.
+    ///
+    /// `writer` is flushed at the end, so a buffered writer can't lose the last write.
+    pub fn write_json<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        serde_json::to_writer_pretty(&mut writer, self).map_err(io::Error::other)?;
+        writer.flush()
+    }
+
+    /// Write the report as CSV, one row per page.
+    ///
+    /// Archive level warnings & errors are joined with `; ` on every row
+    /// of that archive, archives without pages still get a single row.
+    /// `writer` is flushed at the end, like with `write_json`.
+    pub fn write_csv<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        writeln!(
+            writer,
+            "archive,status,archive_original_size,archive_new_size,archive_time_ms,archive_warnings,archive_errors,\
+             page,original_size,new_size,codec,width,height,time_ms,warnings,errors"
+        )?;
+        for archive in &self.archives {
+            let archive_cols = [
+                csv_field(&archive.archive.to_string_lossy()),
+                archive.status.to_string(),
+                archive.original_size.to_string(),
+                archive.new_size.to_string(),
+                archive.time_ms.to_string(),
+                csv_field(&archive.warnings.join("; ")),
+                csv_field(&archive.errors.join("; ")),
+            ]
+            .join(",");
+            if archive.pages.is_empty() {
+                writeln!(writer, "{},,,,,,,,,", archive_cols)?;
+            }
+            for page in &archive.pages {
+                writeln!(
+                    writer,
+                    "{},{},{},{},{},{},{},{},{},{}",
+                    archive_cols,
+                    csv_field(&page.name.to_string_lossy()),
+                    page.original_size,
+                    page.new_size,
+                    csv_field(&page.codec),
+                    page.width.map(|w| w.to_string()).unwrap_or_default(),
+                    page.height.map(|h| h.to_string()).unwrap_or_default(),
+                    page.time_ms,
+                    csv_field(&page.warnings.join("; ")),
+                    csv_field(&page.errors.join("; ")),
+                )?;
+            }
+        }
+        writer.flush()
+    }
+
+    /// Human readable table of every archive with a totals row.
+    pub fn summary_table(&self) -> String {
+        let mut table = String::new();
+        let _ = writeln!(
+            table,
+            "{:<40} {:<10} {:>6} {:>12} {:>12} {:>8} {:>16}",
+            "Archive", "Status", "Pages", "Original", "New", "Saving", "Time"
+        );
+        let mut warnings = 0;
+        let mut errors = 0;
+        for archive in &self.archives {
+            warnings += archive.warnings.len()
+                + archive
+                    .pages
+                    .iter()
+                    .map(|p| p.warnings.len())
+                    .sum::<usize>();
+            errors +=
+                archive.errors.len() + archive.pages.iter().map(|p| p.errors.len()).sum::<usize>();
+            let (new_size, saving) = match archive.output {
+                Some(_) => (
+                    HumanBytes(archive.new_size).to_string(),
+                    format!(
+                        "{:.1}%",
+                        saving_percent(archive.original_size, archive.new_size)
+                    ),
+                ),
+                None => ("-".to_string(), "-".to_string()),
+            };
+            let _ = writeln!(
+                table,
+                "{:<40} {:<10} {:>6} {:>12} {:>12} {:>8} {:>16}",
+                archive
+                    .archive
+                    .file_name()
+                    .unwrap_or_default()
+                    .to_string_lossy(),
+                archive.status,
+                archive.pages.len(),
+                HumanBytes(archive.original_size).to_string(),
+                new_size,
+                saving,
+                format_duration(Duration::from_millis(archive.time_ms)).to_string()
+            );
+        }
+        let _ = writeln!(
+            table,
+            "{:<40} {:<10} {:>6} {:>12} {:>12} {:>7.1}% {:>16}",
+            "Total",
+            "",
+            self.archives.iter().map(|a| a.pages.len()).sum::<usize>(),
+            HumanBytes(self.total_original_size).to_string(),
+            HumanBytes(self.total_new_size).to_string(),
+            saving_percent(self.total_original_size, self.total_new_size),
+            format_duration(Duration::from_millis(self.time_ms)).to_string()
+        );
+        let _ = write!(table, "Warnings: {}, Errors: {}", warnings, errors);
+        table
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn status_is_written_in_lowercase() {
+        let report = RunReport {
+            archives: vec![ArchiveReport {
+                archive: PathBuf::from("Ch1.cbz"),
+                status: ArchiveStatus::Failed,
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let mut json = Vec::new();
+        report.write_json(&mut json).unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&json).unwrap();
+        assert_eq!(json["archives"][0]["status"], "failed");
+
+        let mut csv = Vec::new();
+        report.write_csv(&mut csv).unwrap();
+        let csv = String::from_utf8(csv).unwrap();
+        assert!(csv.lines().nth(1).unwrap().starts_with("Ch1.cbz,failed,"));
+        assert_eq!(format!("{:<10}|", ArchiveStatus::Duplicate), "duplicate |");
+    }
+}
@@ -0,0 +1,535 @@
+use crate::cbz_actions::CbzEntries;
+use crate::encoding_actions::{decode_entry_name, NameEncoding};
+use crate::err_impl::CompressionError;
+use flate2::read::DeflateDecoder;
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{self, BufReader, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+/// Signature starting every local file header.
+const LOCAL_HEADER_SIGNATURE: [u8; 4] = [0x50, 0x4b, 0x03, 0x04];
+/// Signature starting every central directory header.
+const CENTRAL_HEADER_SIGNATURE: [u8; 4] = [0x50, 0x4b, 0x01, 0x02];
+/// Optional signature in front of a data descriptor.
+const DATA_DESCRIPTOR_SIGNATURE: [u8; 4] = [0x50, 0x4b, 0x07, 0x08];
+/// Size of a local file header without the name & extra field.
+const LOCAL_HEADER_LEN: usize = 30;
+/// Flag bit telling the crc & sizes follow the data instead of the header.
+const FLAG_DATA_DESCRIPTOR: u16 = 0x08;
+/// Flag bit telling the entry name is UTF-8.
+const FLAG_UTF8: u16 = 0x800;
+/// Zip64 extra field id, holding the 64 bit sizes of large entries.
+const ZIP64_EXTRA_FIELD_ID: u16 = 0x0001;
+/// Size field value telling the real size is in the Zip64 extra field.
+const ZIP64_SIZE_MARKER: u32 = u32::MAX;
+/// Bytes read at once while scanning for signatures.
+const SCAN_CHUNK_LEN: usize = 64 * 1024;
+const METHOD_STORED: u16 = 0;
+const METHOD_DEFLATED: u16 = 8;
+
+/// What came out of salvaging a damaged archive.
+#[derive(Debug, Clone, Default)]
+pub struct SalvageReport {
+    /// Entries that were recovered with a matching CRC.
+    pub recovered: Vec<String>,
+    /// Entries that were found but couldn't be recovered | (entry_name, reason)
+    pub lost: Vec<(String, String)>,
+}
+
+fn read_u16(data: &[u8], pos: usize) -> u16 {
+    u16::from_le_bytes([data[pos], data[pos + 1]])
+}
+
+fn read_u32(data: &[u8], pos: usize) -> u32 {
+    u32::from_le_bytes([data[pos], data[pos + 1], data[pos + 2], data[pos + 3]])
+}
+
+fn read_u64(data: &[u8], pos: usize) -> u64 {
+    u64::from_le_bytes(data[pos..pos + 8].try_into().unwrap())
+}
+
+/// Read `len` bytes at `pos`.
+///
+/// Lengths come from damaged headers, so nothing is allocated before
+/// `pos + len` is known to be inside the data.
+/// Return `Option<Vec<u8>>`, `None` when the data ends first.
+fn read_at<R: Read + Seek>(reader: &mut R, pos: u64, len: u64) -> io::Result<Option<Vec<u8>>> {
+    let data_len = reader.seek(SeekFrom::End(0))?;
+    let Some(len) = pos
+        .checked_add(len)
+        .filter(|end| *end <= data_len)
+        .and_then(|_| usize::try_from(len).ok())
+    else {
+        return Ok(None);
+    };
+    reader.seek(SeekFrom::Start(pos))?;
+    let mut buf = vec![0; len];
+    match reader.read_exact(&mut buf) {
+        Ok(()) => Ok(Some(buf)),
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+/// Find the next of `signatures` at or after `from`.
+///
+/// The data is scanned a chunk at a time, so only one chunk is in memory.
+fn find_signature<R: Read + Seek>(
+    reader: &mut R,
+    from: u64,
+    signatures: &[[u8; 4]],
+) -> io::Result<Option<u64>> {
+    reader.seek(SeekFrom::Start(from))?;
+    let mut buf = vec![0; SCAN_CHUNK_LEN];
+    let mut buf_start = from;
+    let mut filled = 0;
+    loop {
+        let read = reader.read(&mut buf[filled..])?;
+        if read == 0 {
+            return Ok(None);
+        }
+        filled += read;
+        if let Some(pos) = buf[..filled]
+            .windows(4)
+            .position(|window| signatures.iter().any(|signature| window == signature))
+        {
+            return Ok(Some(buf_start + pos as u64));
+        }
+        //NOTE: A signature can straddle two chunks, keep the tail around.
+        let keep = filled.min(3);
+        buf.copy_within(filled - keep..filled, 0);
+        buf_start += (filled - keep) as u64;
+        filled = keep;
+    }
+}
+
+/// Find the Zip64 extra field of a local header.
+///
+/// Return `Option<&[u8]>` the field data, uncompressed & compressed size when present.
+fn zip64_extra_field(extra: &[u8]) -> Option<&[u8]> {
+    let mut rest = extra;
+    while rest.len() >= 4 {
+        let id = read_u16(rest, 0);
+        let size = read_u16(rest, 2) as usize;
+        let field = rest.get(4..4 + size)?;
+        if id == ZIP64_EXTRA_FIELD_ID {
+            return Some(field);
+        }
+        rest = &rest[4 + size..];
+    }
+    None
+}
+
+/// Local file header fields the salvage needs.
+struct LocalHeader {
+    flags: u16,
+    method: u16,
+    crc: u32,
+    compressed_size: u64,
+    zip64: bool,
+    data_start: u64,
+}
+
+/// Unpack one entry.
+///
+/// Zip64 entries (`zip64`) have 64 bit sizes in their data descriptor.
+/// Return `(Vec<u8>, u64)` | (file_data, end of the entry) or the reason it was lost.
+fn read_entry<R: Read + Seek>(
+    reader: &mut R,
+    data_len: u64,
+    header: &LocalHeader,
+) -> Result<(Vec<u8>, u64), String> {
+    let io_reason = |err: io::Error| err.to_string();
+    let data_start = header.data_start;
+    let has_descriptor = header.flags & FLAG_DATA_DESCRIPTOR != 0;
+    //NOTE: crc followed by the compressed & uncompressed size.
+    let descriptor_len: u64 = if header.zip64 { 20 } else { 12 };
+    let bytes_left = data_len.saturating_sub(data_start);
+    //NOTE: A size from a damaged header can point far past the end of the data.
+    if !has_descriptor && header.compressed_size > bytes_left {
+        return Err("truncated".to_string());
+    }
+    let (file_contents, data_end) = match header.method {
+        METHOD_DEFLATED => {
+            let limit = if has_descriptor {
+                bytes_left
+            } else {
+                header.compressed_size
+            };
+            reader
+                .seek(SeekFrom::Start(data_start))
+                .map_err(io_reason)?;
+            let mut decoder = DeflateDecoder::new(reader.by_ref().take(limit));
+            let mut file_contents = Vec::new();
+            decoder
+                .read_to_end(&mut file_contents)
+                .map_err(|_| "truncated or corrupt deflate stream")?;
+            (file_contents, data_start + decoder.total_in())
+        }
+        METHOD_STORED if !has_descriptor => {
+            let stored = read_at(reader, data_start, header.compressed_size)
+                .map_err(io_reason)?
+                .ok_or("truncated")?;
+            (stored, data_start + header.compressed_size)
+        }
+        METHOD_STORED => {
+            //NOTE: No size to go on, the entry & its descriptor end where the next record starts.
+            let next_record = find_signature(
+                reader,
+                data_start,
+                &[LOCAL_HEADER_SIGNATURE, CENTRAL_HEADER_SIGNATURE],
+            )
+            .map_err(io_reason)?
+            .ok_or("truncated")?;
+            let descriptor_len = match next_record.checked_sub(descriptor_len + 4) {
+                Some(pos)
+                    if read_at(reader, pos, 4).map_err(io_reason)?.as_deref()
+                        == Some(&DATA_DESCRIPTOR_SIGNATURE) =>
+                {
+                    descriptor_len + 4
+                }
+                _ => descriptor_len,
+            };
+            let data_end = next_record
+                .checked_sub(descriptor_len)
+                .filter(|data_end| *data_end >= data_start)
+                .ok_or("truncated")?;
+            let stored = read_at(reader, data_start, data_end - data_start)
+                .map_err(io_reason)?
+                .ok_or("truncated")?;
+            (stored, data_end)
+        }
+        other => return Err(format!("unsupported compression method {}", other)),
+    };
+
+    let (expected_crc, entry_end) = if has_descriptor {
+        let mut pos = data_end;
+        if read_at(reader, pos, 4).map_err(io_reason)?.as_deref()
+            == Some(&DATA_DESCRIPTOR_SIGNATURE)
+        {
+            pos += 4;
+        }
+        let descriptor = read_at(reader, pos, descriptor_len)
+            .map_err(io_reason)?
+            .ok_or("truncated")?;
+        (read_u32(&descriptor, 0), pos + descriptor_len)
+    } else {
+        (header.crc, data_end)
+    };
+    if crc32fast::hash(&file_contents) != expected_crc {
+        return Err("CRC mismatch".to_string());
+    }
+    Ok((file_contents, entry_end))
+}
+
+/// Names of every local file header in the archive data.
+///
+/// Names are all the encoding detection needs, so nothing is decompressed.
+fn local_header_names<R: Read + Seek>(reader: &mut R) -> io::Result<Vec<Vec<u8>>> {
+    let mut names = Vec::new();
+    let mut pos = 0;
+    while let Some(header) = find_signature(reader, pos, &[LOCAL_HEADER_SIGNATURE])? {
+        let Some(fixed) = read_at(reader, header, LOCAL_HEADER_LEN as u64)? else {
+            break;
+        };
+        let name_len = read_u16(&fixed, 26) as u64;
+        if let Some(name) = read_at(reader, header + LOCAL_HEADER_LEN as u64, name_len)? {
+            names.push(name);
+        }
+        pos = header + LOCAL_HEADER_SIGNATURE.len() as u64;
+    }
+    Ok(names)
+}
+
+/// Recover what is still readable from a damaged `.cbz` archive.
+///
+/// Doesn't need the central directory, instead it scans for local file
+/// headers to rebuild the entry list. Only entries whose CRC checks out are
+/// kept, everything else ends up in the `SalvageReport`.
+/// * `cbz_file`: Damaged or truncated `.cbz` archive.
+/// * `name_encoding`: How to decode entry names without the UTF-8 flag.
+///
+/// Return `(CbzEntries, SalvageReport)` with entries in the same shape as
+/// `read_dir_and_files_from_cbz`.
+pub fn salvage_cbz<P: AsRef<Path>>(
+    cbz_file: P,
+    name_encoding: NameEncoding,
+) -> Result<(CbzEntries, SalvageReport), CompressionError> {
+    let mut reader = BufReader::new(File::open(cbz_file.as_ref())?);
+    let archive_name = cbz_file
+        .as_ref()
+        .file_name()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .to_string();
+    Ok(salvage_entries(&mut reader, &archive_name, name_encoding)?)
+}
+
+/// Scan `reader` for local file headers, see `salvage_cbz`.
+///
+/// Entries are read one at a time, the archive itself is never held in memory.
+fn salvage_entries<R: Read + Seek>(
+    reader: &mut R,
+    archive_name: &str,
+    name_encoding: NameEncoding,
+) -> io::Result<(CbzEntries, SalvageReport)> {
+    let data_len = reader.seek(SeekFrom::End(0))?;
+    let names = local_header_names(reader)?;
+    let encoding = name_encoding.resolve(names.iter().map(Vec::as_slice));
+    let mut entries = Vec::new();
+    let mut report = SalvageReport::default();
+    let mut seen = HashSet::new();
+
+    let mut pos = 0;
+    while let Some(header) = find_signature(reader, pos, &[LOCAL_HEADER_SIGNATURE])? {
+        let Some(fixed) = read_at(reader, header, LOCAL_HEADER_LEN as u64)? else {
+            break;
+        };
+        let compressed_size = read_u32(&fixed, 18);
+        let name_len = read_u16(&fixed, 26) as u64;
+        let extra_len = read_u16(&fixed, 28) as u64;
+        let name_start = header + LOCAL_HEADER_LEN as u64;
+        let Some(name) = read_at(reader, name_start, name_len)? else {
+            break;
+        };
+        //NOTE: Signature bytes inside compressed data, not a real header.
+        if name_len == 0 {
+            pos = header + LOCAL_HEADER_SIGNATURE.len() as u64;
+            continue;
+        }
+        let utf8_flag = read_u16(&fixed, 6) & FLAG_UTF8 != 0;
+        let name = decode_entry_name(&name, utf8_flag, encoding);
+        let Some(extra) = read_at(reader, name_start + name_len, extra_len)? else {
+            report.lost.push((name, "truncated".to_string()));
+            break;
+        };
+
+        let data_start = name_start + name_len + extra_len;
+        let zip64 = zip64_extra_field(&extra);
+        let (compressed_size, oversized) = match zip64 {
+            Some(field) if compressed_size == ZIP64_SIZE_MARKER && field.len() >= 16 => {
+                //NOTE: A full 64 bit size from a damaged header, it can't be past the end of the file.
+                let size = read_u64(field, 8);
+                (size, size > data_len.saturating_sub(data_start))
+            }
+            _ => (compressed_size as u64, false),
+        };
+        let local_header = LocalHeader {
+            flags: read_u16(&fixed, 6),
+            method: read_u16(&fixed, 8),
+            crc: read_u32(&fixed, 14),
+            compressed_size,
+            zip64: zip64.is_some(),
+            data_start,
+        };
+
+        let entry = if oversized {
+            Err("Zip64 size past the end of the file".to_string())
+        } else {
+            read_entry(reader, data_len, &local_header)
+        };
+        match entry {
+            Ok((file_contents, entry_end)) => {
+                pos = entry_end;
+                if name.ends_with('/') || !seen.insert(name.clone()) {
+                    continue;
+                }
+                report.recovered.push(name.clone());
+                entries.push((archive_name.to_string(), file_contents, PathBuf::from(name)));
+            }
+            Err(reason) => {
+                //NOTE: Keep scanning right after this header, the next entry may be fine.
+                pos = header + LOCAL_HEADER_SIGNATURE.len() as u64;
+                if !name.ends_with('/') && !seen.contains(&name) {
+                    report.lost.push((name, reason));
+                }
+            }
+        }
+    }
+
+    //NOTE: An entry lost once but found intact later isn't lost.
+    report.lost.retain(|(name, _)| !seen.contains(name));
+    Ok((entries, report))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::DeflateEncoder;
+    use flate2::Compression;
+    use std::io::{Cursor, Write};
+
+    /// Local header, data & optional data descriptor of one entry.
+    fn local_entry(name: &str, contents: &[u8], method: u16, descriptor: bool) -> Vec<u8> {
+        let data = match method {
+            METHOD_DEFLATED => {
+                let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+                encoder.write_all(contents).unwrap();
+                encoder.finish().unwrap()
+            }
+            _ => contents.to_vec(),
+        };
+        let crc = crc32fast::hash(contents);
+        let (flags, header_crc, header_size) = if descriptor {
+            (FLAG_DATA_DESCRIPTOR, 0, 0)
+        } else {
+            (0, crc, data.len() as u32)
+        };
+        let mut entry = LOCAL_HEADER_SIGNATURE.to_vec();
+        entry.extend_from_slice(&20u16.to_le_bytes());
+        entry.extend_from_slice(&flags.to_le_bytes());
+        entry.extend_from_slice(&method.to_le_bytes());
+        entry.extend_from_slice(&[0; 4]);
+        entry.extend_from_slice(&header_crc.to_le_bytes());
+        entry.extend_from_slice(&header_size.to_le_bytes());
+        entry.extend_from_slice(&header_size.to_le_bytes());
+        entry.extend_from_slice(&(name.len() as u16).to_le_bytes());
+        entry.extend_from_slice(&0u16.to_le_bytes());
+        entry.extend_from_slice(name.as_bytes());
+        entry.extend_from_slice(&data);
+        if descriptor {
+            entry.extend_from_slice(&DATA_DESCRIPTOR_SIGNATURE);
+            entry.extend_from_slice(&crc.to_le_bytes());
+            entry.extend_from_slice(&(data.len() as u32).to_le_bytes());
+            entry.extend_from_slice(&(contents.len() as u32).to_le_bytes());
+        }
+        entry
+    }
+
+    fn salvage(data: Vec<u8>) -> (CbzEntries, SalvageReport) {
+        salvage_entries(&mut Cursor::new(data), "test.cbz", NameEncoding::Auto).unwrap()
+    }
+
+    fn names(entries: &CbzEntries) -> Vec<String> {
+        entries
+            .iter()
+            .map(|entry| entry.2.to_string_lossy().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn truncated_central_directory_keeps_whole_entries() {
+        let page = vec![7u8; 4000];
+        let mut zip_writer = zip::ZipWriter::new(Cursor::new(Vec::new()));
+        for name in ["01.png", "02.png", "03.png"] {
+            zip_writer
+                .start_file(name, zip::write::FileOptions::default())
+                .unwrap();
+            zip_writer.write_all(&page).unwrap();
+        }
+        let mut data = zip_writer.finish().unwrap().into_inner();
+        //NOTE: Cut inside the central directory.
+        let central = data
+            .windows(4)
+            .position(|window| window == CENTRAL_HEADER_SIGNATURE)
+            .unwrap();
+        data.truncate(central + 10);
+        assert!(zip::ZipArchive::new(Cursor::new(data.clone())).is_err());
+
+        let (entries, report) = salvage(data);
+        assert_eq!(names(&entries), ["01.png", "02.png", "03.png"]);
+        assert!(entries.iter().all(|entry| entry.1 == page));
+        assert!(report.lost.is_empty());
+    }
+
+    #[test]
+    fn truncated_entry_is_lost() {
+        let mut data = local_entry("01.png", b"first page", METHOD_STORED, false);
+        let second = local_entry("02.png", &[3; 200], METHOD_DEFLATED, false);
+        data.extend_from_slice(&second[..second.len() - 4]);
+
+        let (entries, report) = salvage(data);
+        assert_eq!(names(&entries), ["01.png"]);
+        assert_eq!(report.lost.len(), 1);
+        assert_eq!(report.lost[0].0, "02.png");
+    }
+
+    #[test]
+    fn data_descriptor_entries_are_recovered() {
+        let mut data = local_entry("01.png", b"stored page", METHOD_STORED, true);
+        data.extend(local_entry("02.png", &[5; 300], METHOD_DEFLATED, true));
+        data.extend(local_entry("03.png", b"last page", METHOD_STORED, true));
+        data.extend_from_slice(&CENTRAL_HEADER_SIGNATURE);
+
+        let (entries, report) = salvage(data);
+        assert_eq!(names(&entries), ["01.png", "02.png", "03.png"]);
+        assert_eq!(entries[0].1, b"stored page");
+        assert_eq!(entries[1].1, vec![5; 300]);
+        assert_eq!(entries[2].1, b"last page");
+        assert!(report.lost.is_empty());
+    }
+
+    #[test]
+    fn crc_mismatch_is_lost() {
+        let mut data = local_entry("01.png", b"first page", METHOD_STORED, false);
+        let damaged_at = data.len() - 1;
+        data[damaged_at] ^= 0xff;
+        data.extend(local_entry("02.png", b"second page", METHOD_STORED, false));
+
+        let (entries, report) = salvage(data);
+        assert_eq!(names(&entries), ["02.png"]);
+        assert_eq!(
+            report.lost,
+            [("01.png".to_string(), "CRC mismatch".to_string())]
+        );
+    }
+
+    #[test]
+    fn oversized_stored_entry_is_lost_without_allocating() {
+        let mut data = local_entry("01.png", b"first page", METHOD_STORED, false);
+        //NOTE: Claims a 3.75 GiB stored entry.
+        data[18..22].copy_from_slice(&0xf000_0000u32.to_le_bytes());
+        data.extend(local_entry("02.png", b"second page", METHOD_STORED, false));
+
+        let (entries, report) = salvage(data);
+        assert_eq!(names(&entries), ["02.png"]);
+        assert_eq!(
+            report.lost,
+            [("01.png".to_string(), "truncated".to_string())]
+        );
+    }
+
+    #[test]
+    fn oversized_zip64_entry_is_lost() {
+        let mut data = local_entry("01.png", b"first page", METHOD_STORED, false);
+        data[18..22].copy_from_slice(&ZIP64_SIZE_MARKER.to_le_bytes());
+        data[22..26].copy_from_slice(&ZIP64_SIZE_MARKER.to_le_bytes());
+        //NOTE: Zip64 extra field claiming an entry of almost 2^64 bytes.
+        let mut zip64 = ZIP64_EXTRA_FIELD_ID.to_le_bytes().to_vec();
+        zip64.extend_from_slice(&16u16.to_le_bytes());
+        zip64.extend_from_slice(&10u64.to_le_bytes());
+        zip64.extend_from_slice(&(u64::MAX - 8).to_le_bytes());
+        data[28..30].copy_from_slice(&(zip64.len() as u16).to_le_bytes());
+        let name_end = LOCAL_HEADER_LEN + "01.png".len();
+        data.splice(name_end..name_end, zip64);
+        data.extend(local_entry("02.png", b"second page", METHOD_STORED, false));
+
+        let (entries, report) = salvage(data);
+        assert_eq!(names(&entries), ["02.png"]);
+        assert_eq!(
+            report.lost,
+            [(
+                "01.png".to_string(),
+                "Zip64 size past the end of the file".to_string()
+            )]
+        );
+    }
+
+    #[test]
+    fn read_at_stops_at_the_end_of_the_data() {
+        let mut reader = Cursor::new(vec![1u8; 16]);
+        assert_eq!(read_at(&mut reader, 8, 8).unwrap(), Some(vec![1; 8]));
+        assert_eq!(read_at(&mut reader, 8, 9).unwrap(), None);
+        assert_eq!(read_at(&mut reader, 8, u64::MAX).unwrap(), None);
+    }
+
+    #[test]
+    fn signature_across_scan_chunks_is_found() {
+        let mut data = vec![0; SCAN_CHUNK_LEN - 2];
+        data.extend(local_entry("01.png", b"page", METHOD_STORED, false));
+
+        let (entries, _) = salvage(data);
+        assert_eq!(names(&entries), ["01.png"]);
+    }
+}
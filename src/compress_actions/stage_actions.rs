@@ -0,0 +1,636 @@
+use crate::cbz_actions::{image_dimensions, JPEG_QUALITY};
+use crate::config_actions::{PageCodec, PipelineConfig};
+use crate::err_impl::CompressionError;
+use image::imageops::FilterType;
+use image::{DynamicImage, ImageFormat, ImageOutputFormat};
+use std::collections::HashMap;
+use std::fmt;
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// Stages pages go through when the configuration names none.
+pub const DEFAULT_STAGES: [&str; 2] = ["resize", "encode"];
+
+/// A page on its way through an `ImageChain`.
+///
+/// The page is only decoded once a stage asks for its pixels, so a chain
+/// that changes nothing leaves the source bytes untouched.
+#[derive(Debug)]
+pub struct Page {
+    /// Archive the page comes from, empty when there is none.
+    pub archive: PathBuf,
+    /// Entry name of the page inside the archive.
+    pub entry: PathBuf,
+    source: Vec<u8>,
+    image: Option<DynamicImage>,
+    modified: bool,
+    encoded: Option<Vec<u8>>,
+}
+
+impl Page {
+    /// Page with its source bytes, not decoded yet.
+    pub fn new<P1: AsRef<Path>, P2: AsRef<Path>>(archive: P1, entry: P2, source: Vec<u8>) -> Self {
+        Self {
+            archive: archive.as_ref().to_owned(),
+            entry: entry.as_ref().to_owned(),
+            source,
+            image: None,
+            modified: false,
+            encoded: None,
+        }
+    }
+
+    /// Bytes the page came in with.
+    pub fn source(&self) -> &[u8] {
+        &self.source
+    }
+
+    /// Format the page came in with, if it's a known one.
+    pub fn source_format(&self) -> Option<ImageFormat> {
+        image::guess_format(&self.source).ok()
+    }
+
+    /// Current dimensions of the page, read from the header while it isn't decoded.
+    pub fn dimensions(&mut self) -> Result<(u32, u32), CompressionError> {
+        if self.image.is_none() {
+            if let Some(dimensions) = image_dimensions(&self.source) {
+                return Ok(dimensions);
+            }
+        }
+        let image = self.image()?;
+        Ok((image.width(), image.height()))
+    }
+
+    /// Current pixels of the page, decoding it on first use.
+    pub fn image(&mut self) -> Result<&DynamicImage, CompressionError> {
+        if self.image.is_none() {
+            self.image = Some(image::load_from_memory(&self.source)?);
+        }
+        Ok(self.image.as_ref().expect("decoded above"))
+    }
+
+    /// Replace the pixels of the page, whatever was encoded before is dropped.
+    pub fn set_image(&mut self, image: DynamicImage) {
+        self.image = Some(image);
+        self.modified = true;
+        self.encoded = None;
+    }
+
+    /// Whether a stage changed the pixels of the page.
+    pub fn is_modified(&self) -> bool {
+        self.modified
+    }
+
+    /// Bytes an encode stage wrote the current pixels to.
+    pub fn encoded(&self) -> Option<&[u8]> {
+        self.encoded.as_deref()
+    }
+
+    /// Store the encoded page, it's what the chain hands back.
+    pub fn set_encoded(&mut self, encoded: Vec<u8>) {
+        self.encoded = Some(encoded);
+    }
+
+    /// Whether the pixels changed since the page was last encoded.
+    fn needs_encoding(&self) -> bool {
+        self.modified && self.encoded.is_none()
+    }
+
+    /// Bytes the page ends up with.
+    fn into_data(self) -> Vec<u8> {
+        self.encoded.unwrap_or(self.source)
+    }
+}
+
+/// One step of the page processing, like resizing or encoding.
+///
+/// Stages get the page with whatever the previous ones did to it and
+/// leave the page alone when there is nothing to do, so untouched pages
+/// can be kept byte for byte. Implement it to plug your own stage into
+/// a chain, either with `ImageChain::then` or through a `StageRegistry`.
+pub trait ImageStage: Send + Sync {
+    /// Name & settings of the stage, like `sharpen-1-4`, as part of the cache key.
+    ///
+    /// Empty for a stage that never changes a page with these settings.
+    fn name(&self) -> String;
+
+    /// Work on a page.
+    fn apply(&self, page: &mut Page) -> Result<(), CompressionError>;
+
+    /// Whether the stage leaves every page no earlier stage changed as it is.
+    ///
+    /// Lets pages skip a chain of such stages without being decoded.
+    fn keeps_pages(&self) -> bool {
+        self.name().is_empty()
+    }
+
+    /// Dimensions a page of `width`x`height` ends up with.
+    ///
+    /// `None` when that can't be known without looking at the pixels.
+    fn dimensions(&self, width: u32, height: u32) -> Option<(u32, u32)> {
+        Some((width, height))
+    }
+}
+
+impl fmt::Debug for dyn ImageStage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "ImageStage({})", self.name())
+    }
+}
+
+/// Turn pages into grayscale, e-ink readers have no colours anyway.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Grayscale;
+
+impl ImageStage for Grayscale {
+    fn name(&self) -> String {
+        "grayscale".to_string()
+    }
+
+    fn apply(&self, page: &mut Page) -> Result<(), CompressionError> {
+        let image = page.image()?;
+        if !image.color().has_color() {
+            return Ok(());
+        }
+        let gray = image.grayscale();
+        page.set_image(gray);
+        Ok(())
+    }
+}
+
+/// Trim the blank margins around a page.
+///
+/// The top left pixel is taken as the background, rows & columns that
+/// stay within `tolerance` of it on the luma channel are cut off.
+#[derive(Debug, Clone, Copy)]
+pub struct Crop {
+    pub tolerance: u8,
+}
+
+impl ImageStage for Crop {
+    fn name(&self) -> String {
+        format!("crop-{}", self.tolerance)
+    }
+
+    fn apply(&self, page: &mut Page) -> Result<(), CompressionError> {
+        let luma = page.image()?.to_luma8();
+        let (width, height) = luma.dimensions();
+        if width == 0 || height == 0 {
+            return Ok(());
+        }
+        let background = luma.get_pixel(0, 0)[0];
+        let is_content =
+            |x: u32, y: u32| luma.get_pixel(x, y)[0].abs_diff(background) > self.tolerance;
+        let row_has_content = |y: u32| (0..width).any(|x| is_content(x, y));
+        //NOTE: A blank page has nothing to crop to.
+        let Some(top) = (0..height).find(|&y| row_has_content(y)) else {
+            return Ok(());
+        };
+        let bottom = (top..height)
+            .rev()
+            .find(|&y| row_has_content(y))
+            .unwrap_or(top);
+        let column_has_content = |x: u32| (top..=bottom).any(|y| is_content(x, y));
+        let left = (0..width).find(|&x| column_has_content(x)).unwrap_or(0);
+        let right = (left..width)
+            .rev()
+            .find(|&x| column_has_content(x))
+            .unwrap_or(left);
+        if (left, top, right, bottom) == (0, 0, width - 1, height - 1) {
+            return Ok(());
+        }
+        let cropped = page
+            .image()?
+            .crop_imm(left, top, right - left + 1, bottom - top + 1);
+        page.set_image(cropped);
+        Ok(())
+    }
+
+    fn dimensions(&self, _width: u32, _height: u32) -> Option<(u32, u32)> {
+        None
+    }
+}
+
+/// Scale pages down into a `max_width`x`max_height` box, keeping their aspect ratio.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Resize {
+    pub max_width: Option<u32>,
+    pub max_height: Option<u32>,
+}
+
+impl Resize {
+    /// Dimensions a page of `width`x`height` is scaled down to, pages are never scaled up.
+    pub fn fit(&self, width: u32, height: u32) -> (u32, u32) {
+        let max_width = self.max_width.unwrap_or(u32::MAX).min(width);
+        let max_height = self.max_height.unwrap_or(u32::MAX).min(height);
+        if (max_width, max_height) == (width, height) {
+            return (width, height);
+        }
+        //NOTE: Same rounding as `DynamicImage::resize`, so the round-trip check agrees.
+        let ratio = f64::min(
+            max_width as f64 / width as f64,
+            max_height as f64 / height as f64,
+        );
+        (
+            ((width as f64 * ratio).round() as u32).max(1),
+            ((height as f64 * ratio).round() as u32).max(1),
+        )
+    }
+}
+
+impl ImageStage for Resize {
+    fn name(&self) -> String {
+        if self.max_width.is_none() && self.max_height.is_none() {
+            return String::new();
+        }
+        format!(
+            "max-{}x{}",
+            self.max_width.map_or("any".to_string(), |w| w.to_string()),
+            self.max_height.map_or("any".to_string(), |h| h.to_string())
+        )
+    }
+
+    fn apply(&self, page: &mut Page) -> Result<(), CompressionError> {
+        let (width, height) = page.dimensions()?;
+        let (new_width, new_height) = self.fit(width, height);
+        if (new_width, new_height) == (width, height) {
+            return Ok(());
+        }
+        let resized = page
+            .image()?
+            .resize_exact(new_width, new_height, FilterType::Lanczos3);
+        page.set_image(resized);
+        Ok(())
+    }
+
+    fn dimensions(&self, width: u32, height: u32) -> Option<(u32, u32)> {
+        Some(self.fit(width, height))
+    }
+}
+
+/// Unsharp mask, brings back the line art a downscale softens.
+#[derive(Debug, Clone, Copy)]
+pub struct Sharpen {
+    /// Blur radius of the mask.
+    pub sigma: f32,
+    /// Smallest difference that gets sharpened, keeps flat areas free of noise.
+    pub threshold: i32,
+}
+
+impl ImageStage for Sharpen {
+    fn name(&self) -> String {
+        format!("sharpen-{}-{}", self.sigma, self.threshold)
+    }
+
+    fn apply(&self, page: &mut Page) -> Result<(), CompressionError> {
+        let sharpened = page.image()?.unsharpen(self.sigma, self.threshold);
+        page.set_image(sharpened);
+        Ok(())
+    }
+}
+
+/// Encode pages with a codec.
+///
+/// With `PageCodec::Keep` a page no stage changed keeps its source bytes,
+/// the others are encoded in the format they came in.
+#[derive(Debug, Clone, Copy)]
+pub struct Encode {
+    pub codec: PageCodec,
+    pub jpeg_quality: u8,
+}
+
+impl ImageStage for Encode {
+    fn name(&self) -> String {
+        match self.codec {
+            PageCodec::Jpeg => format!("jpeg-{}", self.jpeg_quality),
+            codec => codec.to_string(),
+        }
+    }
+
+    fn keeps_pages(&self) -> bool {
+        matches!(self.codec, PageCodec::Keep)
+    }
+
+    fn apply(&self, page: &mut Page) -> Result<(), CompressionError> {
+        let output_format = match self.codec {
+            PageCodec::Jpeg => ImageOutputFormat::Jpeg(self.jpeg_quality),
+            PageCodec::Png => ImageOutputFormat::Png,
+            //NOTE: Nothing to do, so the page stays byte for byte the same.
+            PageCodec::Keep if !page.is_modified() => return Ok(()),
+            PageCodec::Keep => match page.source_format() {
+                Some(ImageFormat::Jpeg) => ImageOutputFormat::Jpeg(self.jpeg_quality),
+                Some(ImageFormat::Gif) => ImageOutputFormat::Gif,
+                Some(ImageFormat::Bmp) => ImageOutputFormat::Bmp,
+                //NOTE: There's no WebP encoder, PNG keeps it lossless at least.
+                _ => ImageOutputFormat::Png,
+            },
+        };
+        let image = page.image()?;
+        let mut encoded = Vec::new();
+        //NOTE: Jpeg has no alpha channel, the encoder refuses images that have one.
+        if matches!(output_format, ImageOutputFormat::Jpeg(_)) && image.color().has_alpha() {
+            let opaque = if image.color().has_color() {
+                DynamicImage::ImageRgb8(image.to_rgb8())
+            } else {
+                DynamicImage::ImageLuma8(image.to_luma8())
+            };
+            opaque.write_to(&mut Cursor::new(&mut encoded), output_format)?;
+        } else {
+            image.write_to(&mut Cursor::new(&mut encoded), output_format)?;
+        }
+        page.set_encoded(encoded);
+        Ok(())
+    }
+}
+
+/// Stages a page goes through, in order.
+#[derive(Debug, Clone, Default)]
+pub struct ImageChain {
+    stages: Vec<Arc<dyn ImageStage>>,
+}
+
+impl ImageChain {
+    /// Chain without any stage, pages go through it untouched.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Chain of the builtin stages named in `config`.
+    pub fn from_config(config: &PipelineConfig) -> Result<Self, CompressionError> {
+        StageRegistry::builtin().chain(config)
+    }
+
+    /// Add a stage at the end of the chain.
+    pub fn then<S: ImageStage + 'static>(self, stage: S) -> Self {
+        self.then_shared(Arc::new(stage))
+    }
+
+    /// Add a shared stage at the end of the chain.
+    pub fn then_shared(mut self, stage: Arc<dyn ImageStage>) -> Self {
+        self.stages.push(stage);
+        self
+    }
+
+    /// Stages of the chain, in order.
+    pub fn stages(&self) -> &[Arc<dyn ImageStage>] {
+        &self.stages
+    }
+
+    /// Settings of every stage that changes pages, as part of the cache key.
+    ///
+    /// Return `String` like `grayscale,max-1264x1680,jpeg-80`
+    pub fn settings(&self) -> String {
+        self.stages
+            .iter()
+            .map(|stage| stage.name())
+            .filter(|name| !name.is_empty())
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+
+    /// Whether every page comes out of the chain as it went in.
+    pub fn keeps_pages(&self) -> bool {
+        self.stages.iter().all(|stage| stage.keeps_pages())
+    }
+
+    /// Dimensions a page of `width`x`height` comes out of the chain with.
+    ///
+    /// Return `None` when a stage can't tell before seeing the pixels.
+    pub fn dimensions(&self, width: u32, height: u32) -> Option<(u32, u32)> {
+        self.stages
+            .iter()
+            .try_fold((width, height), |(width, height), stage| {
+                stage.dimensions(width, height)
+            })
+    }
+
+    /// Run a page through every stage.
+    ///
+    /// A page whose pixels changed after the last encode stage, if any,
+    /// is encoded in the format it came in.
+    /// * `page`: Page to process.
+    ///
+    /// Return `Vec<u8>` encoded page.
+    pub fn run(&self, mut page: Page) -> Result<Vec<u8>, CompressionError> {
+        for stage in &self.stages {
+            stage.apply(&mut page)?;
+        }
+        if page.needs_encoding() {
+            Encode {
+                codec: PageCodec::Keep,
+                jpeg_quality: JPEG_QUALITY,
+            }
+            .apply(&mut page)?;
+        }
+        Ok(page.into_data())
+    }
+}
+
+/// Builds a stage from the settings of a run.
+pub type StageFactory = Arc<dyn Fn(&PipelineConfig) -> Arc<dyn ImageStage> + Send + Sync>;
+
+/// Stages the `stages` setting can name, with how to build them.
+#[derive(Clone, Default)]
+pub struct StageRegistry {
+    factories: HashMap<String, StageFactory>,
+}
+
+impl fmt::Debug for StageRegistry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_set().entries(self.names()).finish()
+    }
+}
+
+impl StageRegistry {
+    /// Registry of `grayscale`, `crop`, `resize`, `sharpen` & `encode`.
+    pub fn builtin() -> Self {
+        Self::default()
+            .register("grayscale", |_| Arc::new(Grayscale))
+            .register("crop", |config| {
+                Arc::new(Crop {
+                    tolerance: config.crop_tolerance,
+                })
+            })
+            .register("resize", |config| {
+                Arc::new(Resize {
+                    max_width: config.max_width,
+                    max_height: config.max_height,
+                })
+            })
+            .register("sharpen", |config| {
+                Arc::new(Sharpen {
+                    sigma: config.sharpen_sigma,
+                    threshold: config.sharpen_threshold,
+                })
+            })
+            .register("encode", |config| {
+                Arc::new(Encode {
+                    codec: config.codec,
+                    jpeg_quality: config.jpeg_quality,
+                })
+            })
+    }
+
+    /// Make a stage available under `name`, replacing any stage of that name.
+    pub fn register<F>(mut self, name: &str, factory: F) -> Self
+    where
+        F: Fn(&PipelineConfig) -> Arc<dyn ImageStage> + Send + Sync + 'static,
+    {
+        self.factories.insert(name.to_string(), Arc::new(factory));
+        self
+    }
+
+    /// Names of the registered stages, sorted.
+    pub fn names(&self) -> Vec<&str> {
+        let mut names: Vec<&str> = self.factories.keys().map(String::as_str).collect();
+        names.sort_unstable();
+        names
+    }
+
+    /// Build the stage registered under `name`, `None` if there is none.
+    pub fn stage(&self, name: &str, config: &PipelineConfig) -> Option<Arc<dyn ImageStage>> {
+        self.factories.get(name).map(|factory| factory(config))
+    }
+
+    /// Build the chain of stages named in `config`, in that order.
+    pub fn chain(&self, config: &PipelineConfig) -> Result<ImageChain, CompressionError> {
+        let mut chain = ImageChain::new();
+        for name in &config.stages {
+            let stage = self.stage(name, config).ok_or_else(|| {
+                CompressionError::config(format!(
+                    "Unknown stage {}, use {}",
+                    name,
+                    self.names().join(", ")
+                ))
+            })?;
+            chain = chain.then_shared(stage);
+        }
+        Ok(chain)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{Rgb, RgbImage};
+    use std::sync::Mutex;
+
+    /// Stage that only notes down that it ran.
+    struct Record {
+        name: &'static str,
+        ran: Arc<Mutex<Vec<&'static str>>>,
+    }
+
+    impl ImageStage for Record {
+        fn name(&self) -> String {
+            self.name.to_string()
+        }
+
+        fn apply(&self, _page: &mut Page) -> Result<(), CompressionError> {
+            self.ran.lock().unwrap().push(self.name);
+            Ok(())
+        }
+    }
+
+    /// White PNG page with a black box at `x`,`y` of `box_width`x`box_height`.
+    fn png_page(width: u32, height: u32, x: u32, y: u32, box_width: u32, box_height: u32) -> Page {
+        let image = RgbImage::from_fn(width, height, |px, py| {
+            let inside = (x..x + box_width).contains(&px) && (y..y + box_height).contains(&py);
+            if inside {
+                Rgb([0, 0, 0])
+            } else {
+                Rgb([255, 255, 255])
+            }
+        });
+        let mut source = Vec::new();
+        DynamicImage::ImageRgb8(image)
+            .write_to(&mut Cursor::new(&mut source), ImageOutputFormat::Png)
+            .unwrap();
+        Page::new("Ch1.cbz", "001.png", source)
+    }
+
+    fn config_with_stages(stages: &[&str]) -> PipelineConfig {
+        PipelineConfig {
+            stages: stages.iter().map(|stage| stage.to_string()).collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn stages_run_in_chain_order() {
+        let ran = Arc::new(Mutex::new(Vec::new()));
+        let chain =
+            ["first", "second", "third"]
+                .into_iter()
+                .fold(ImageChain::new(), |chain, name| {
+                    chain.then(Record {
+                        name,
+                        ran: ran.clone(),
+                    })
+                });
+        chain.run(png_page(4, 4, 0, 0, 1, 1)).unwrap();
+        assert_eq!(*ran.lock().unwrap(), ["first", "second", "third"]);
+    }
+
+    #[test]
+    fn registry_chains_stages_in_config_order() {
+        let config = PipelineConfig {
+            max_width: Some(100),
+            ..config_with_stages(&["encode", "crop", "resize"])
+        };
+        let chain = StageRegistry::builtin().chain(&config).unwrap();
+        assert_eq!(chain.settings(), "jpeg-90,crop-16,max-100xany");
+    }
+
+    #[test]
+    fn unknown_stages_are_rejected() {
+        let err = StageRegistry::builtin()
+            .chain(&config_with_stages(&["resize", "blur"]))
+            .unwrap_err();
+        assert!(matches!(err, CompressionError::ConfigError { .. }));
+        assert!(err.to_string().contains("Unknown stage blur"));
+    }
+
+    #[test]
+    fn crop_trims_to_the_content() {
+        let mut page = png_page(100, 60, 20, 10, 30, 25);
+        Crop { tolerance: 16 }.apply(&mut page).unwrap();
+        assert!(page.is_modified());
+        assert_eq!(page.dimensions().unwrap(), (30, 25));
+
+        //NOTE: A blank page is left alone.
+        let mut blank = png_page(100, 60, 0, 0, 0, 0);
+        Crop { tolerance: 16 }.apply(&mut blank).unwrap();
+        assert!(!blank.is_modified());
+    }
+
+    #[test]
+    fn resize_fits_the_box_and_never_scales_up() {
+        let resize = Resize {
+            max_width: Some(50),
+            max_height: Some(50),
+        };
+        let mut page = png_page(100, 60, 0, 0, 1, 1);
+        resize.apply(&mut page).unwrap();
+        assert_eq!(page.dimensions().unwrap(), (50, 30));
+        assert_eq!(resize.dimensions(100, 60), Some((50, 30)));
+
+        let mut small = png_page(40, 20, 0, 0, 1, 1);
+        resize.apply(&mut small).unwrap();
+        assert!(!small.is_modified());
+        assert_eq!(small.dimensions().unwrap(), (40, 20));
+    }
+
+    #[test]
+    fn chain_output_has_the_expected_dimensions() {
+        let config = PipelineConfig {
+            codec: PageCodec::Png,
+            max_width: Some(15),
+            ..config_with_stages(&["crop", "resize", "encode"])
+        };
+        let chain = StageRegistry::builtin().chain(&config).unwrap();
+        let encoded = chain.run(png_page(100, 60, 20, 10, 30, 20)).unwrap();
+        assert_eq!(image_dimensions(&encoded), Some((15, 10)));
+    }
+}
@@ -0,0 +1,311 @@
+use crate::cbz_actions::is_image_file;
+use crate::encoding_actions::{zip_entry_name, zip_name_encoding, NameEncoding};
+use crate::err_impl::CompressionError;
+use quick_xml::escape::resolve_predefined_entity;
+use quick_xml::events::{BytesRef, Event};
+use quick_xml::Reader;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use zip::ZipArchive;
+
+/// Name of the ComicRack metadata entry.
+pub const COMIC_INFO_NAME: &str = "ComicInfo.xml";
+
+/// `ComicInfo.xml` fields that have to hold a whole number.
+const COMIC_INFO_NUMBER_FIELDS: [&str; 6] =
+    ["Count", "Volume", "Year", "Month", "Day", "PageCount"];
+
+/// Outcome of checking a single archive.
+#[derive(Debug, Clone, Default)]
+pub struct ArchiveVerification {
+    pub archive: PathBuf,
+    pub entries: usize,
+    pub pages: usize,
+    /// Everything wrong with the archive, empty when it passed.
+    pub problems: Vec<String>,
+}
+
+impl ArchiveVerification {
+    pub fn passed(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+/// Check if an image ends the way its format says it should.
+///
+/// Decoders are happy to fill in the missing bottom of a cut off page,
+/// so this looks for the end marker of the format instead.
+/// Return `bool` true when the end marker is missing.
+pub fn is_truncated_image(image_data: &[u8]) -> bool {
+    match image::guess_format(image_data) {
+        Ok(image::ImageFormat::Jpeg) => {
+            //NOTE: Some encoders pad the file with zeros after the end marker.
+            let end = image_data
+                .iter()
+                .rposition(|byte| *byte != 0)
+                .map_or(0, |pos| pos + 1);
+            !image_data[..end].ends_with(&[0xff, 0xd9])
+        }
+        Ok(image::ImageFormat::Png) => !image_data
+            .get(image_data.len().saturating_sub(12)..)
+            .is_some_and(|tail| tail.starts_with(&[0, 0, 0, 0]) && &tail[4..8] == b"IEND"),
+        Ok(image::ImageFormat::Gif) => image_data.last() != Some(&0x3b),
+        Ok(image::ImageFormat::WebP) => {
+            image_data.len() < 8
+                || u32::from_le_bytes([image_data[4], image_data[5], image_data[6], image_data[7]])
+                    as usize
+                    + 8
+                    > image_data.len()
+        }
+        _ => false,
+    }
+}
+
+/// Text a character or entity reference in `ComicInfo.xml` stands for, like `&amp;`.
+///
+/// Unknown entities are kept as they were written.
+pub(crate) fn resolve_reference(reference: &BytesRef) -> String {
+    if let Ok(Some(ch)) = reference.resolve_char_ref() {
+        return ch.to_string();
+    }
+    match resolve_predefined_entity(reference) {
+        Some(text) => text.to_string(),
+        None => format!("&{};", &**reference),
+    }
+}
+
+/// Validate the contents of a `ComicInfo.xml`.
+///
+/// It has to be well formed with a `ComicInfo` root, number fields have
+/// to hold numbers and `PageCount` has to match the pages in the archive.
+/// * `xml_data`: Raw `ComicInfo.xml` bytes.
+/// * `page_count`: Number of pages in the archive.
+pub fn validate_comic_info(xml_data: &[u8], page_count: usize) -> Result<(), CompressionError> {
+    let xml = std::str::from_utf8(xml_data)
+        .map_err(|err| CompressionError::metadata(format!("not UTF-8: {}", err)))?;
+    let mut reader = Reader::from_str(xml);
+    let mut path: Vec<String> = Vec::new();
+    let mut has_root = false;
+    let mut value = String::new();
+    loop {
+        let event = reader
+            .read_event()
+            .map_err(|err| CompressionError::metadata(err.to_string()))?;
+        match event {
+            Event::Start(tag) => {
+                let name = tag.name().as_ref().to_string();
+                if path.is_empty() {
+                    if name != "ComicInfo" {
+                        return Err(CompressionError::metadata(format!(
+                            "root element is <{}>, expected <ComicInfo>",
+                            name
+                        )));
+                    }
+                    has_root = true;
+                }
+                path.push(name);
+                value.clear();
+            }
+            Event::Empty(tag) if path.is_empty() => {
+                has_root = tag.name().as_ref() == "ComicInfo";
+            }
+            //NOTE: Text comes in pieces around every reference, like `1&#48;`.
+            Event::Text(text) if path.len() == 2 => value.push_str(&text),
+            Event::GeneralRef(reference) if path.len() == 2 => {
+                value.push_str(&resolve_reference(&reference));
+            }
+            Event::End(_) if path.len() == 2 => {
+                let field = path.pop().unwrap_or_default();
+                let field = field.as_str();
+                let value = value.trim();
+                if COMIC_INFO_NUMBER_FIELDS.contains(&field) && value.parse::<i64>().is_err() {
+                    return Err(CompressionError::metadata(format!(
+                        "<{}> should be a number, found {:?}",
+                        field, value
+                    )));
+                }
+                if field == "PageCount" && value.parse::<usize>() != Ok(page_count) {
+                    return Err(CompressionError::metadata(format!(
+                        "<PageCount> says {} but the archive has {} pages",
+                        value, page_count
+                    )));
+                }
+            }
+            Event::End(_) => {
+                path.pop();
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+    if !has_root {
+        return Err(CompressionError::metadata(
+            "missing <ComicInfo> root element",
+        ));
+    }
+    Ok(())
+}
+
+/// Check the integrity of a `.cbz` archive without changing it.
+///
+/// Every entry is read back so the zip crate checks its CRC, every page
+/// is fully decoded & checked for being empty or cut off, and a
+/// `ComicInfo.xml` if there is one gets validated.
+/// * `cbz_file`: `.cbz` archive to check.
+///
+/// Return `ArchiveVerification` listing every problem found.
+pub fn verify_cbz<P: AsRef<Path>>(cbz_file: P) -> ArchiveVerification {
+    let mut verification = ArchiveVerification {
+        archive: cbz_file.as_ref().to_owned(),
+        ..Default::default()
+    };
+    let mut zip_file = match File::open(cbz_file.as_ref())
+        .map_err(CompressionError::from)
+        .and_then(|file| Ok(ZipArchive::new(io::BufReader::new(file))?))
+    {
+        Ok(zip_file) => zip_file,
+        Err(err) => {
+            verification.problems.push(err.to_string());
+            return verification;
+        }
+    };
+    let encoding = match zip_name_encoding(&mut zip_file, NameEncoding::Auto) {
+        Ok(encoding) => encoding,
+        Err(err) => {
+            verification.problems.push(err.to_string());
+            return verification;
+        }
+    };
+
+    let mut comic_info = None;
+    for idx in 0..zip_file.len() {
+        let mut inner_file = match zip_file.by_index(idx) {
+            Ok(inner_file) => inner_file,
+            Err(err) => {
+                verification
+                    .problems
+                    .push(format!("Entry {}: {}", idx, err));
+                continue;
+            }
+        };
+        if inner_file.is_dir() {
+            continue;
+        }
+        verification.entries += 1;
+        let name = zip_entry_name(&inner_file, encoding);
+        let mut file_contents = Vec::new();
+        //NOTE: The zip crate checks the CRC once the entry is read to the end.
+        if let Err(err) = inner_file.read_to_end(&mut file_contents) {
+            verification.problems.push(format!("{}: {}", name, err));
+            continue;
+        }
+
+        if is_image_file(&name) {
+            verification.pages += 1;
+            if file_contents.is_empty() {
+                verification
+                    .problems
+                    .push(format!("{}: zero-byte page", name));
+            } else if is_truncated_image(&file_contents) {
+                verification
+                    .problems
+                    .push(format!("{}: truncated page", name));
+            } else if let Err(err) = image::load_from_memory(&file_contents) {
+                verification
+                    .problems
+                    .push(format!("{}: can't decode: {}", name, err));
+            }
+        } else if Path::new(&name)
+            .file_name()
+            .is_some_and(|file_name| file_name.eq_ignore_ascii_case(COMIC_INFO_NAME))
+        {
+            comic_info = Some((name, file_contents));
+        }
+    }
+
+    if let Some((name, xml_data)) = comic_info {
+        if let Err(err) = validate_comic_info(&xml_data, verification.pages) {
+            let err = err.in_archive(cbz_file.as_ref()).at_entry(name);
+            verification.problems.push(err.to_string());
+        }
+    }
+    verification
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{ImageOutputFormat, RgbImage};
+    use std::io::Cursor;
+
+    fn encoded_page(format: ImageOutputFormat) -> Vec<u8> {
+        let mut data = Vec::new();
+        image::DynamicImage::ImageRgb8(RgbImage::new(16, 24))
+            .write_to(&mut Cursor::new(&mut data), format)
+            .unwrap();
+        data
+    }
+
+    #[test]
+    fn whole_images_are_not_truncated() {
+        for format in [ImageOutputFormat::Png, ImageOutputFormat::Jpeg(90)] {
+            assert!(!is_truncated_image(&encoded_page(format)));
+        }
+    }
+
+    #[test]
+    fn zero_padded_jpeg_is_not_truncated() {
+        let mut data = encoded_page(ImageOutputFormat::Jpeg(90));
+        data.extend_from_slice(&[0; 16]);
+        assert!(!is_truncated_image(&data));
+    }
+
+    #[test]
+    fn cut_images_are_truncated() {
+        for format in [ImageOutputFormat::Png, ImageOutputFormat::Jpeg(90)] {
+            let data = encoded_page(format);
+            assert!(is_truncated_image(&data[..data.len() - 5]));
+        }
+    }
+
+    #[test]
+    fn unknown_data_is_not_truncated() {
+        assert!(!is_truncated_image(b"not an image"));
+    }
+
+    #[test]
+    fn valid_comic_info_passes() {
+        let xml = br#"<?xml version="1.0"?>
+<ComicInfo><Series>Horimiya</Series><Number>1</Number><Year>2011</Year><PageCount>3</PageCount></ComicInfo>"#;
+        assert!(validate_comic_info(xml, 3).is_ok());
+        assert!(validate_comic_info(b"<ComicInfo/>", 0).is_ok());
+    }
+
+    #[test]
+    fn references_are_resolved_before_checking() {
+        let xml = b"<ComicInfo><Year>20&#49;1</Year><PageCount>&#x31;2</PageCount></ComicInfo>";
+        assert!(validate_comic_info(xml, 12).is_ok());
+        assert!(validate_comic_info(xml, 1).is_err());
+        let xml = b"<ComicInfo><Year>20&nbsp;11</Year></ComicInfo>";
+        assert!(validate_comic_info(xml, 0).is_err());
+    }
+
+    #[test]
+    fn broken_comic_info_fails() {
+        let cases: [&[u8]; 5] = [
+            b"<Comic><Series>Horimiya</Series></Comic>",
+            b"<ComicInfo><Year>twenty</Year></ComicInfo>",
+            b"<ComicInfo><PageCount>4</PageCount></ComicInfo>",
+            b"",
+            b"<ComicInfo><Series>Horimiya</Title></ComicInfo>",
+        ];
+        for xml in cases {
+            assert!(
+                validate_comic_info(xml, 3).is_err(),
+                "{}",
+                String::from_utf8_lossy(xml)
+            );
+        }
+    }
+}
@@ -0,0 +1,271 @@
+use crate::cbz_actions::{
+    compress_dir_and_files_to_cbz, is_image_file, read_dir_and_files_from_folder, ArchiveMetadata,
+};
+use crate::config_actions::PipelineConfig;
+use crate::err_impl::CompressionError;
+use crate::pipeline_actions::Pipeline;
+use crate::progress_actions::{Progress, ProgressEvent};
+use crate::report_actions::ArchiveStatus;
+use notify::{Event, RecursiveMode, Watcher};
+use std::collections::HashMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+use walkdir::WalkDir;
+
+/// Folder inside the inbox that handled originals are moved to.
+pub const PROCESSED_DIR_NAME: &str = ".processed";
+
+/// Folder inside the inbox entries are staged in while they're processed.
+pub const STAGING_DIR_NAME: &str = ".staging";
+
+/// How often settled inbox entries are checked for.
+const WATCH_TICK: Duration = Duration::from_millis(500);
+
+/// Find the top level inbox entry a changed path belongs to.
+///
+/// Everything dropped into the inbox is handled per top level entry, so a
+/// chapter folder is only picked up once all of its pages stopped changing.
+/// Hidden entries (like `.processed` & `.staging`) are ignored.
+fn inbox_entry(inbox: &Path, path: &Path) -> Option<PathBuf> {
+    let first = path.strip_prefix(inbox).ok()?.components().next()?;
+    if first.as_os_str().to_string_lossy().starts_with('.') {
+        return None;
+    }
+    Some(inbox.join(first))
+}
+
+/// Path of `path` with `.cbz` added, `Ch 1.5` becomes `Ch 1.5.cbz`.
+fn with_cbz_suffix(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".cbz");
+    PathBuf::from(name)
+}
+
+/// Mirror an inbox entry into `STAGING_DIR_NAME`, as `.cbz` archives only.
+///
+/// `Pipeline` only takes archives, so every folder holding images is packed
+/// into one (without deflate, the pipeline repacks it anyway) and every
+/// `.cbz` is hard linked, or copied where that doesn't work.
+/// * `inbox`: Watched inbox folder.
+/// * `entry`: Top level inbox entry to stage.
+///
+/// Return `Vec<PathBuf>` pipeline inputs, the staged archive of `entry` itself
+/// and the staged folder of what's under it, each only if there is one.
+fn stage_entry(inbox: &Path, entry: &Path) -> Result<Vec<PathBuf>, CompressionError> {
+    let staging = inbox.join(STAGING_DIR_NAME);
+    let pack_config = PipelineConfig {
+        deflate_level: 0,
+        ..PipelineConfig::default()
+    };
+    let mut staged = Vec::new();
+    for item in WalkDir::new(entry).sort_by_file_name() {
+        let item = item?;
+        let is_cbz =
+            item.file_type().is_file() && item.path().extension().is_some_and(|ext| ext == "cbz");
+        let is_image_dir = item.file_type().is_dir()
+            && std::fs::read_dir(item.path())?
+                .filter_map(|e| e.ok())
+                .any(|e| e.path().is_file() && is_image_file(e.path()));
+        if !is_cbz && !is_image_dir {
+            continue;
+        }
+        let relative = item.path().strip_prefix(inbox).unwrap_or(item.path());
+        let mut target = staging.join(relative);
+        if is_image_dir {
+            target = with_cbz_suffix(&target);
+        }
+        if let Some(parent) = target.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        if is_cbz {
+            if std::fs::hard_link(item.path(), &target).is_err() {
+                std::fs::copy(item.path(), &target)?;
+            }
+        } else {
+            let pages = read_dir_and_files_from_folder(item.path())?;
+            let (_, data) =
+                compress_dir_and_files_to_cbz(pages, &ArchiveMetadata::default(), &pack_config)?;
+            std::fs::write(&target, data)?;
+        }
+        //NOTE: A chapter file or folder dropped in as is becomes one archive.
+        let input = if item.path() == entry {
+            target
+        } else {
+            staging.join(entry.strip_prefix(inbox).unwrap_or(entry))
+        };
+        if !staged.contains(&input) {
+            staged.push(input);
+        }
+    }
+    Ok(staged)
+}
+
+/// Remove a staged file or folder, it's only needed during one run.
+fn remove_staged(staged: &Path) -> io::Result<()> {
+    match std::fs::symlink_metadata(staged) {
+        Ok(meta) if meta.is_dir() => std::fs::remove_dir_all(staged),
+        Ok(_) => std::fs::remove_file(staged),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(err) => Err(err),
+    }
+}
+
+/// Run one settled inbox entry through the optimise-and-repack pipeline.
+///
+/// Every `.cbz` and every folder holding images under `entry` becomes an
+/// archive in the library, keeping its path relative to the inbox.
+/// They're staged as archives first, then go through a copy of `pipeline`,
+/// so its threads, keep-going, cache & journal settings apply here too.
+/// The original is moved into `PROCESSED_DIR_NAME` once all of them are done.
+/// * `inbox`: Watched inbox folder.
+/// * `library`: Library the optimised archives go to.
+/// * `entry`: Top level inbox entry to process.
+/// * `pipeline`: Pipeline without inputs, every entry is run through a copy of it.
+/// * `progress`: Where progress events go.
+///
+/// Return `usize` number of archives moved into the library.
+pub async fn process_inbox_entry(
+    inbox: &Path,
+    library: &Path,
+    entry: &Path,
+    pipeline: &Pipeline,
+    progress: &Progress,
+) -> Result<usize, CompressionError> {
+    let staged = {
+        let (inbox, entry) = (inbox.to_owned(), entry.to_owned());
+        tokio::task::spawn_blocking(move || {
+            let staged = inbox
+                .join(STAGING_DIR_NAME)
+                .join(entry.strip_prefix(&inbox).unwrap_or(&entry));
+            //NOTE: Leftovers of an earlier attempt at this entry.
+            remove_staged(&staged)?;
+            remove_staged(&with_cbz_suffix(&staged))?;
+            stage_entry(&inbox, &entry)
+        })
+        .await
+        .map_err(io::Error::other)??
+    };
+    if staged.is_empty() {
+        return Ok(0);
+    }
+
+    let relative = entry.strip_prefix(inbox).unwrap_or(entry);
+    //NOTE: A lone folder input isn't part of the output paths, with several
+    //inputs (or a file input) it is.
+    let output_dir = match staged.as_slice() {
+        [folder] if folder.is_dir() => library.join(relative),
+        _ => library.to_owned(),
+    };
+    let mut entry_pipeline = pipeline
+        .clone()
+        .output_dir(output_dir)
+        .progress(progress.clone());
+    for input in &staged {
+        entry_pipeline = entry_pipeline.input(input);
+    }
+    let report = entry_pipeline.run().await;
+    for input in &staged {
+        remove_staged(input)?;
+    }
+    let report = report?;
+    if report
+        .archives
+        .iter()
+        .any(|archive_report| archive_report.status == ArchiveStatus::Failed)
+    {
+        return Err(CompressionError::container(report.failures().join("; ")).in_archive(entry));
+    }
+
+    let processed = inbox.join(PROCESSED_DIR_NAME).join(relative);
+    if let Some(parent) = processed.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::fs::rename(entry, processed)?;
+    Ok(report
+        .archives
+        .iter()
+        .filter(|archive_report| archive_report.output.is_some())
+        .count())
+}
+
+/// Watch an inbox folder and feed new chapters into the library.
+///
+/// Uses filesystem notifications to spot new `.cbz` files and image folders,
+/// waits until nothing under an entry changed for `settle`, then runs it
+/// through `process_inbox_entry`. Entries already in the inbox on start are
+/// picked up too. Runs until the watcher shuts down.
+/// * `inbox`: Folder the scrapers drop new chapters into.
+/// * `library`: Folder the optimised archives are moved to.
+/// * `settle`: Quiet period before an entry counts as complete.
+/// * `pipeline`: Pipeline without inputs, with the settings every entry is run with.
+/// * `progress`: Where progress events go.
+pub async fn watch_inbox<P1: AsRef<Path>, P2: AsRef<Path>>(
+    inbox: P1,
+    library: P2,
+    settle: Duration,
+    pipeline: &Pipeline,
+    progress: &Progress,
+) -> Result<(), CompressionError> {
+    let inbox = inbox.as_ref();
+    let library = library.as_ref();
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    let mut watcher = notify::recommended_watcher(move |res: notify::Result<Event>| {
+        let _ = tx.send(res);
+    })
+    .map_err(io::Error::other)?;
+    watcher
+        .watch(inbox, RecursiveMode::Recursive)
+        .map_err(io::Error::other)?;
+
+    let mut pending: HashMap<PathBuf, Instant> = HashMap::new();
+    for item in std::fs::read_dir(inbox)? {
+        if let Some(entry) = inbox_entry(inbox, &item?.path()) {
+            pending.insert(entry, Instant::now());
+        }
+    }
+    progress.event(ProgressEvent::Message(format!(
+        "Watching {} for new chapters...",
+        inbox.display()
+    )));
+
+    loop {
+        match tokio::time::timeout(WATCH_TICK, rx.recv()).await {
+            Ok(Some(Ok(event))) => {
+                for path in event.paths {
+                    if let Some(entry) = inbox_entry(inbox, &path) {
+                        pending.insert(entry, Instant::now());
+                    }
+                }
+            }
+            Ok(Some(Err(err))) => progress.event(ProgressEvent::Warning {
+                archive: None,
+                message: format!("Watch error: {}", err),
+            }),
+            Ok(None) => break,
+            Err(_) => {}
+        }
+
+        let settled: Vec<PathBuf> = pending
+            .iter()
+            .filter(|(_, last_change)| last_change.elapsed() >= settle)
+            .map(|(entry, _)| entry.to_owned())
+            .collect();
+        for entry in settled {
+            pending.remove(&entry);
+            if !entry.exists() {
+                continue;
+            }
+            //NOTE: A failed entry is left in the inbox as is.
+            if let Err(err) = process_inbox_entry(inbox, library, &entry, pipeline, progress).await
+            {
+                progress.event(ProgressEvent::Warning {
+                    archive: Some(entry.clone()),
+                    message: format!("Failed to process: {}", err),
+                });
+            }
+        }
+    }
+    Ok(())
+}
@@ -1,16 +1,170 @@
+use std::error::Error;
 use std::fmt;
 use std::io;
+use std::path::{Path, PathBuf};
 
 #[derive(Debug)]
 pub enum CompressionError {
-    IoError(io::Error),
+    /// Reading or writing a file failed.
+    IoError {
+        archive: Option<PathBuf>,
+        entry: Option<String>,
+        source: io::Error,
+    },
     UnsupportedFileType,
     WalkDirError(walkdir::Error),
+    /// Reading or writing the zip container of an archive failed.
+    ZipError {
+        archive: Option<PathBuf>,
+        entry: Option<String>,
+        source: zip::result::ZipError,
+    },
+    /// A page couldn't be decoded.
+    ImageDecodeError {
+        archive: Option<PathBuf>,
+        entry: Option<String>,
+        source: image::ImageError,
+    },
+    /// A page couldn't be encoded again.
+    ImageEncodeError {
+        archive: Option<PathBuf>,
+        entry: Option<String>,
+        source: image::ImageError,
+    },
+    /// Writing or reading an xz stream failed.
+    XzError {
+        archive: Option<PathBuf>,
+        source: io::Error,
+    },
+    /// Metadata like `ComicInfo.xml` or bundle headers is invalid.
+    MetadataError {
+        archive: Option<PathBuf>,
+        entry: Option<String>,
+        message: String,
+    },
+    /// The archive is readable but its layout is wrong, like a repacked
+    /// archive that doesn't read back.
+    ContainerError {
+        archive: Option<PathBuf>,
+        message: String,
+    },
+    /// A configuration file or override is invalid.
+    ConfigError {
+        path: Option<PathBuf>,
+        message: String,
+    },
+}
+
+impl CompressionError {
+    /// Xz stream error, without an archive attached yet.
+    pub fn xz(source: io::Error) -> Self {
+        CompressionError::XzError {
+            archive: None,
+            source,
+        }
+    }
+
+    /// Metadata error, without an archive or entry attached yet.
+    pub fn metadata<S: Into<String>>(message: S) -> Self {
+        CompressionError::MetadataError {
+            archive: None,
+            entry: None,
+            message: message.into(),
+        }
+    }
+
+    /// Container error, without an archive attached yet.
+    pub fn container<S: Into<String>>(message: S) -> Self {
+        CompressionError::ContainerError {
+            archive: None,
+            message: message.into(),
+        }
+    }
+
+    /// Configuration error, without a file attached yet.
+    pub fn config<S: Into<String>>(message: S) -> Self {
+        CompressionError::ConfigError {
+            path: None,
+            message: message.into(),
+        }
+    }
+
+    /// Attach the configuration file the error is in, if it has none yet.
+    pub fn in_config<P: AsRef<Path>>(mut self, config_path: P) -> Self {
+        if let CompressionError::ConfigError { path, .. } = &mut self {
+            path.get_or_insert_with(|| config_path.as_ref().to_owned());
+        }
+        self
+    }
+
+    /// Attach the archive the error happened in, if it has none yet.
+    ///
+    /// Walkdir errors have no room for it and are left as is.
+    pub fn in_archive<P: AsRef<Path>>(mut self, path: P) -> Self {
+        match &mut self {
+            CompressionError::IoError { archive, .. }
+            | CompressionError::ZipError { archive, .. }
+            | CompressionError::ImageDecodeError { archive, .. }
+            | CompressionError::ImageEncodeError { archive, .. }
+            | CompressionError::XzError { archive, .. }
+            | CompressionError::MetadataError { archive, .. }
+            | CompressionError::ContainerError { archive, .. } => {
+                archive.get_or_insert_with(|| path.as_ref().to_owned());
+            }
+            _ => {}
+        }
+        self
+    }
+
+    /// Attach the archive entry the error happened on, if it has none yet.
+    pub fn at_entry<S: AsRef<str>>(mut self, name: S) -> Self {
+        match &mut self {
+            CompressionError::IoError { entry, .. }
+            | CompressionError::ZipError { entry, .. }
+            | CompressionError::ImageDecodeError { entry, .. }
+            | CompressionError::ImageEncodeError { entry, .. }
+            | CompressionError::MetadataError { entry, .. } => {
+                entry.get_or_insert_with(|| name.as_ref().to_string());
+            }
+            _ => {}
+        }
+        self
+    }
+
+    /// Archive the error happened in, if known.
+    pub fn archive(&self) -> Option<&Path> {
+        match self {
+            CompressionError::IoError { archive, .. }
+            | CompressionError::ZipError { archive, .. }
+            | CompressionError::ImageDecodeError { archive, .. }
+            | CompressionError::ImageEncodeError { archive, .. }
+            | CompressionError::XzError { archive, .. }
+            | CompressionError::MetadataError { archive, .. }
+            | CompressionError::ContainerError { archive, .. } => archive.as_deref(),
+            _ => None,
+        }
+    }
+
+    /// Archive entry the error happened on, if known.
+    pub fn entry(&self) -> Option<&str> {
+        match self {
+            CompressionError::IoError { entry, .. }
+            | CompressionError::ZipError { entry, .. }
+            | CompressionError::ImageDecodeError { entry, .. }
+            | CompressionError::ImageEncodeError { entry, .. }
+            | CompressionError::MetadataError { entry, .. } => entry.as_deref(),
+            _ => None,
+        }
+    }
 }
 
 impl From<io::Error> for CompressionError {
     fn from(err: io::Error) -> Self {
-        CompressionError::IoError(err)
+        CompressionError::IoError {
+            archive: None,
+            entry: None,
+            source: err,
+        }
     }
 }
 
@@ -20,12 +174,134 @@ impl From<walkdir::Error> for CompressionError {
     }
 }
 
+impl From<zip::result::ZipError> for CompressionError {
+    fn from(err: zip::result::ZipError) -> Self {
+        CompressionError::ZipError {
+            archive: None,
+            entry: None,
+            source: err,
+        }
+    }
+}
+
+impl From<image::ImageError> for CompressionError {
+    fn from(err: image::ImageError) -> Self {
+        match err {
+            image::ImageError::Encoding(_) => CompressionError::ImageEncodeError {
+                archive: None,
+                entry: None,
+                source: err,
+            },
+            _ => CompressionError::ImageDecodeError {
+                archive: None,
+                entry: None,
+                source: err,
+            },
+        }
+    }
+}
+
+/// Write ` in <archive> (<entry>)` for whatever context is known.
+fn write_location(
+    f: &mut fmt::Formatter<'_>,
+    archive: &Option<PathBuf>,
+    entry: &Option<String>,
+) -> fmt::Result {
+    if let Some(archive) = archive {
+        write!(f, " in {}", archive.display())?;
+    }
+    if let Some(entry) = entry {
+        write!(f, " ({})", entry)?;
+    }
+    Ok(())
+}
+
 impl fmt::Display for CompressionError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            CompressionError::IoError(err) => write!(f, "I/O Error: {}", err),
+            CompressionError::IoError {
+                archive,
+                entry,
+                source,
+            } => {
+                write!(f, "I/O Error")?;
+                write_location(f, archive, entry)?;
+                write!(f, ": {}", source)
+            }
             CompressionError::UnsupportedFileType => write!(f, "Unsupported File Type!"),
             CompressionError::WalkDirError(err) => write!(f, "Failed to find directory: {}", err),
+            CompressionError::ZipError {
+                archive,
+                entry,
+                source,
+            } => {
+                write!(f, "Zip Error")?;
+                write_location(f, archive, entry)?;
+                write!(f, ": {}", source)
+            }
+            CompressionError::ImageDecodeError {
+                archive,
+                entry,
+                source,
+            } => {
+                write!(f, "Failed to decode image")?;
+                write_location(f, archive, entry)?;
+                write!(f, ": {}", source)
+            }
+            CompressionError::ImageEncodeError {
+                archive,
+                entry,
+                source,
+            } => {
+                write!(f, "Failed to encode image")?;
+                write_location(f, archive, entry)?;
+                write!(f, ": {}", source)
+            }
+            CompressionError::XzError { archive, source } => {
+                write!(f, "Xz Error")?;
+                write_location(f, archive, &None)?;
+                write!(f, ": {}", source)
+            }
+            CompressionError::MetadataError {
+                archive,
+                entry,
+                message,
+            } => {
+                write!(f, "Invalid metadata")?;
+                write_location(f, archive, entry)?;
+                write!(f, ": {}", message)
+            }
+            CompressionError::ContainerError { archive, message } => {
+                write!(f, "Invalid archive")?;
+                write_location(f, archive, &None)?;
+                write!(f, ": {}", message)
+            }
+            CompressionError::ConfigError { path, message } => {
+                write!(f, "Invalid configuration")?;
+                write_location(f, path, &None)?;
+                write!(f, ": {}", message)
+            }
         }
     }
 }
+
+//NOTE: `Display` already ends with the message of the underlying error, so it
+//isn't handed out as `source` too, reporters walking the chain would print it twice.
+impl Error for CompressionError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn io_errors_carry_their_archive_and_entry() {
+        let err = CompressionError::from(io::Error::other("disk full"))
+            .in_archive("Ch1.cbz")
+            .at_entry("001.png");
+        assert_eq!(err.archive(), Some(Path::new("Ch1.cbz")));
+        assert_eq!(err.entry(), Some("001.png"));
+        assert_eq!(err.to_string(), "I/O Error in Ch1.cbz (001.png): disk full");
+        //NOTE: The message is part of `Display` already.
+        assert!(err.source().is_none());
+    }
+}
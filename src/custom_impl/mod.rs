@@ -1 +1,3 @@
 pub mod err_impl;
+pub mod xz_decoder_impl;
+pub mod xz_encoder_impl;
@@ -0,0 +1,186 @@
+use crate::err_impl::CompressionError;
+use liblzma::stream::{Action, Status, Stream};
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use tokio::io::{AsyncBufRead, AsyncRead, ReadBuf};
+use tokio::task::JoinHandle;
+
+/// Most decompressed bytes one job produces, so a small but very
+/// compressible input can't blow up memory.
+const OUTPUT_CHUNK_SIZE: usize = 256 * 1024;
+
+/// What a decompression job hands back once it's done.
+struct Job {
+    stream: Stream,
+    output: Vec<u8>,
+    /// Input bytes the stream took, the rest is read again by the next job.
+    consumed: usize,
+    result: io::Result<Status>,
+}
+
+/// Decompress `input` until it's all taken, the output is full or the stream ends.
+fn run_job(mut stream: Stream, input: Vec<u8>, mut output: Vec<u8>, action: Action) -> Job {
+    output.clear();
+    output.reserve(OUTPUT_CHUNK_SIZE);
+    let mut consumed = 0;
+    let result = loop {
+        let total_in = stream.total_in();
+        match stream.process_vec(&input[consumed..], &mut output, action) {
+            Ok(status) => {
+                consumed += (stream.total_in() - total_in) as usize;
+                if status == Status::StreamEnd
+                    || consumed == input.len()
+                    || output.len() == output.capacity()
+                {
+                    break Ok(status);
+                }
+            }
+            Err(err) => break Err(err.into()),
+        }
+    };
+    Job {
+        stream,
+        output,
+        consumed,
+        result,
+    }
+}
+
+/// Xz decompressing `AsyncRead` adapter.
+///
+/// Reads one xz stream from `reader`, whatever follows it is left unread.
+/// Wrap plain readers like files in a `tokio::io::BufReader` first.
+/// Decompression is CPU bound, so it runs as a job on tokio's blocking pool
+/// on a copy of the reader's buffer, which is only consumed once the job
+/// says how much of it the stream took. Has to be used from inside a tokio runtime.
+pub struct AsyncXzDecoder<R: AsyncBufRead + Unpin> {
+    reader: R,
+    /// `None` while a job holds it.
+    stream: Option<Stream>,
+    job: Option<JoinHandle<Job>>,
+    buf: Vec<u8>,
+    pos: usize,
+    total_in: u64,
+    total_out: u64,
+    done: bool,
+}
+
+impl<R: AsyncBufRead + Unpin> AsyncXzDecoder<R> {
+    /// Decoder without a memory limit.
+    pub fn new(reader: R) -> Result<Self, CompressionError> {
+        let stream = Stream::new_stream_decoder(u64::MAX, 0)
+            .map_err(|err| CompressionError::xz(err.into()))?;
+        Ok(Self::new_stream(reader, stream))
+    }
+
+    /// Decoder around a stream set up by hand.
+    pub fn new_stream(reader: R, stream: Stream) -> Self {
+        Self {
+            reader,
+            stream: Some(stream),
+            job: None,
+            buf: Vec::new(),
+            pos: 0,
+            total_in: 0,
+            total_out: 0,
+            done: false,
+        }
+    }
+
+    pub fn get_ref(&self) -> &R {
+        &self.reader
+    }
+
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.reader
+    }
+
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+
+    /// Compressed bytes consumed so far, as of the last finished job.
+    pub fn total_in(&self) -> u64 {
+        self.total_in
+    }
+
+    /// Bytes decompressed so far, as of the last finished job.
+    pub fn total_out(&self) -> u64 {
+        self.total_out
+    }
+
+    /// Decompress the next piece of the reader into `buf`.
+    ///
+    /// Polled again after `Pending`, it waits on the job it already started.
+    fn poll_decompress(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if self.job.is_none() {
+            let input = ready!(Pin::new(&mut self.reader).poll_fill_buf(cx))?.to_vec();
+            let action = if input.is_empty() {
+                Action::Finish
+            } else {
+                Action::Run
+            };
+            let stream = self
+                .stream
+                .take()
+                .ok_or_else(|| io::Error::other("The xz stream was lost to a failed job"))?;
+            let output = std::mem::take(&mut self.buf);
+            self.pos = 0;
+            self.job = Some(tokio::task::spawn_blocking(move || {
+                run_job(stream, input, output, action)
+            }));
+        }
+        let Some(job) = &mut self.job else {
+            return Poll::Ready(Ok(()));
+        };
+        let done = ready!(Pin::new(job).poll(cx)).map_err(io::Error::other);
+        self.job = None;
+        let Job {
+            stream,
+            output,
+            consumed,
+            result,
+        } = done?;
+        //NOTE: The reader wasn't polled since the job copied its buffer, so that's still there.
+        Pin::new(&mut self.reader).consume(consumed);
+        self.total_in = stream.total_in();
+        self.total_out = stream.total_out();
+        self.stream = Some(stream);
+        self.buf = output;
+        self.pos = 0;
+        if result? == Status::StreamEnd {
+            self.done = true;
+        } else if consumed == 0 && self.buf.is_empty() {
+            return Poll::Ready(Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "Xz stream ends early",
+            )));
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<R: AsyncBufRead + Unpin> AsyncRead for AsyncXzDecoder<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        if buf.remaining() == 0 {
+            return Poll::Ready(Ok(()));
+        }
+        while this.pos == this.buf.len() {
+            if this.done {
+                return Poll::Ready(Ok(()));
+            }
+            ready!(this.poll_decompress(cx))?;
+        }
+        let read = buf.remaining().min(this.buf.len() - this.pos);
+        buf.put_slice(&this.buf[this.pos..this.pos + read]);
+        this.pos += read;
+        Poll::Ready(Ok(()))
+    }
+}
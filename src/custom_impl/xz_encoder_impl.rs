@@ -1,17 +1,195 @@
-use liblzma::write::XzEncoder;
-use std::io::Write;
+use crate::bundle_actions::XzOptions;
+use crate::err_impl::CompressionError;
+use liblzma::stream::{Action, Check, Status, Stream};
+use std::future::Future;
+use std::io;
 use std::pin::Pin;
-use std::task::{Context, Poll};
-use tokio::io::{AsyncWrite, Result};
+use std::task::{ready, Context, Poll};
+use tokio::io::AsyncWrite;
+use tokio::task::JoinHandle;
 
+/// Size of the buffer compressed data waits in before it reaches the writer.
+const OUTPUT_BUFFER_SIZE: usize = 32 * 1024;
+
+/// Most bytes of one write that are handed to a compression job.
+const INPUT_CHUNK_SIZE: usize = 256 * 1024;
+
+/// What a compression job hands back once it's done.
+struct Job {
+    stream: Stream,
+    output: Vec<u8>,
+    result: io::Result<()>,
+}
+
+/// Run `action` on `input` until all of it is taken, or for a flush or finish
+/// until the stream says it's done, growing `output` as needed.
+fn run_job(mut stream: Stream, input: Vec<u8>, mut output: Vec<u8>, action: Action) -> Job {
+    let mut consumed = 0;
+    let result = loop {
+        if output.len() == output.capacity() {
+            output.reserve(OUTPUT_BUFFER_SIZE);
+        }
+        let total_in = stream.total_in();
+        match stream.process_vec(&input[consumed..], &mut output, action) {
+            Ok(status) => {
+                consumed += (stream.total_in() - total_in) as usize;
+                let done = match action {
+                    Action::Run => consumed == input.len(),
+                    _ => status == Status::StreamEnd,
+                };
+                if done {
+                    break Ok(());
+                }
+            }
+            Err(err) => break Err(err.into()),
+        }
+    };
+    Job {
+        stream,
+        output,
+        result,
+    }
+}
+
+/// Xz compressing `AsyncWrite` adapter.
+///
+/// Compresses whatever is written to it into `writer`. The stream is only
+/// complete once `shutdown` returned, dropping it before leaves a truncated
+/// stream. Compression is CPU bound (and the multithreaded encoder waits on
+/// its own threads), so it runs as a job on tokio's blocking pool while the
+/// writer is fed, a write returns as soon as its data is handed to a job.
+/// An error of that job comes out of the next write, flush or shutdown.
+/// Has to be used from inside a tokio runtime.
 pub struct AsyncXzEncoder<W: AsyncWrite + Unpin> {
-    encoder: XzEncoder<W>,
+    writer: W,
+    /// `None` while a job holds it.
+    stream: Option<Stream>,
+    job: Option<JoinHandle<Job>>,
+    buf: Vec<u8>,
+    pos: usize,
+    total_in: u64,
+    total_out: u64,
+    /// A flush or finish job was started & not written out yet.
+    draining: bool,
+    finished: bool,
 }
 
 impl<W: AsyncWrite + Unpin> AsyncXzEncoder<W> {
-    pub fn new(writer: W, level: u32) -> Self {
-        let encoder = XzEncoder::new(writer, level);
-        Self { encoder }
+    /// Single threaded encoder with an xz preset.
+    pub fn new(writer: W, level: u32) -> Result<Self, CompressionError> {
+        let stream = Stream::new_easy_encoder(level, Check::Crc64)
+            .map_err(|err| CompressionError::xz(err.into()))?;
+        Ok(Self::new_stream(writer, stream))
+    }
+
+    /// Multithreaded encoder, like the one bundles are written with.
+    pub fn with_options(writer: W, options: &XzOptions) -> Result<Self, CompressionError> {
+        Ok(Self::new_stream(writer, options.encoder()?))
+    }
+
+    /// Encoder around a stream set up by hand.
+    pub fn new_stream(writer: W, stream: Stream) -> Self {
+        Self {
+            writer,
+            stream: Some(stream),
+            job: None,
+            buf: Vec::with_capacity(OUTPUT_BUFFER_SIZE),
+            pos: 0,
+            total_in: 0,
+            total_out: 0,
+            draining: false,
+            finished: false,
+        }
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.writer
+    }
+
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.writer
+    }
+
+    /// Give the writer back, only a complete stream once `shutdown` returned.
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+
+    /// Bytes written into the encoder, as of the last finished job.
+    pub fn total_in(&self) -> u64 {
+        self.total_in
+    }
+
+    /// Compressed bytes produced so far, as of the last finished job.
+    pub fn total_out(&self) -> u64 {
+        self.total_out
+    }
+
+    /// Wait for the running job, if any, & take the stream & its output back.
+    fn poll_job(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if let Some(job) = &mut self.job {
+            let done = ready!(Pin::new(job).poll(cx)).map_err(io::Error::other);
+            self.job = None;
+            let Job {
+                stream,
+                output,
+                result,
+            } = done?;
+            self.total_in = stream.total_in();
+            self.total_out = stream.total_out();
+            self.stream = Some(stream);
+            self.buf = output;
+            self.pos = 0;
+            result?;
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    /// Hand the buffered compressed data to the writer.
+    fn poll_dump(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.pos < self.buf.len() {
+            let written = ready!(Pin::new(&mut self.writer).poll_write(cx, &self.buf[self.pos..]))?;
+            if written == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.pos += written;
+        }
+        self.buf.clear();
+        self.pos = 0;
+        Poll::Ready(Ok(()))
+    }
+
+    /// Wait for the running job & write out what it produced.
+    fn poll_idle(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.poll_job(cx))?;
+        self.poll_dump(cx)
+    }
+
+    /// Start a job running `action` on `input`, the encoder has to be idle.
+    fn start_job(&mut self, input: Vec<u8>, action: Action) -> io::Result<()> {
+        let stream = self
+            .stream
+            .take()
+            .ok_or_else(|| io::Error::other("The xz stream was lost to a failed job"))?;
+        let output = std::mem::take(&mut self.buf);
+        self.job = Some(tokio::task::spawn_blocking(move || {
+            run_job(stream, input, output, action)
+        }));
+        Ok(())
+    }
+
+    /// Run `action` without input to its end & write out the result.
+    ///
+    /// Polled again after `Pending`, it waits on the job it already started.
+    fn poll_drain(&mut self, cx: &mut Context<'_>, action: Action) -> Poll<io::Result<()>> {
+        if !self.draining {
+            ready!(self.poll_idle(cx))?;
+            self.start_job(Vec::new(), action)?;
+            self.draining = true;
+        }
+        let drained = ready!(self.poll_idle(cx));
+        self.draining = false;
+        Poll::Ready(drained)
     }
 }
 
@@ -19,16 +197,39 @@ impl<W: AsyncWrite + Unpin> AsyncWrite for AsyncXzEncoder<W> {
     fn poll_write(
         mut self: Pin<&mut Self>,
         cx: &mut Context<'_>,
-        buf: &[u8],
-    ) -> Poll<Result<usize>> {
-        Pin::new(&mut self.encoder).poll_write(cx, buf)
+        data: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        if self.finished {
+            return Poll::Ready(Err(io::Error::other(
+                "Write after the xz stream was finished",
+            )));
+        }
+        ready!(self.poll_idle(cx))?;
+        if data.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        let chunk = &data[..data.len().min(INPUT_CHUNK_SIZE)];
+        self.start_job(chunk.to_vec(), Action::Run)?;
+        Poll::Ready(Ok(chunk.len()))
     }
 
-    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
-        Pin::new(&mut self.encoder).poll_flush(cx)
+    //NOTE: A full flush ends the current block, so flushing often costs ratio.
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if self.finished {
+            ready!(self.poll_idle(cx))?;
+        } else {
+            ready!(self.poll_drain(cx, Action::FullFlush))?;
+        }
+        Pin::new(&mut self.writer).poll_flush(cx)
     }
 
-    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
-        Pin::new(&mut self.encoder).poll_close(cx)
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        //NOTE: A finished stream can't be run again, only its output is left to write.
+        if !self.finished {
+            ready!(self.poll_drain(cx, Action::Finish))?;
+            self.finished = true;
+        }
+        ready!(self.poll_idle(cx))?;
+        Pin::new(&mut self.writer).poll_shutdown(cx)
     }
 }
@@ -2,6 +2,7 @@ mod compress_actions;
 mod custom_impl;
 mod types;
 
+pub use compress_actions::pipeline_actions::Pipeline;
 pub use compress_actions::*;
 pub use custom_impl::*;
 pub use types::*;
@@ -1,21 +1,39 @@
-use clap::{arg, Parser};
+use clap::{Parser, Subcommand, ValueEnum};
+use comics_archiver::bundle_actions::{
+    extract_bundle, read_bundle, solid_order, write_bundle, XzOptions, BUNDLE_EXTENSION,
+};
 use comics_archiver::cbz_actions::{
-    compress_dir_and_files_to_cbz, compress_images_with_img, extract_dir_and_files_from_cbz,
+    compress_dir_and_files_to_cbz, convert_pages, extract_dir_and_files_from_cbz,
+    read_archive_metadata, read_dir_and_files_from_folder, unpack_cbz_to_dir, ArchiveMetadata,
+    PageFormat,
 };
+use comics_archiver::config_actions::{stage_list, PageCodec, PipelineConfig, ProfileSettings};
+use comics_archiver::dedupe_actions::{fingerprint_cbz, group_duplicates, pick_keeper, KeepRule};
+use comics_archiver::dry_run_actions::project_archive;
+use comics_archiver::encoding_actions::NameEncoding;
 use comics_archiver::err_impl::CompressionError;
+use comics_archiver::inspect_actions::{archive_info, list_cbz};
+use comics_archiver::phash_actions::{
+    find_duplicate_pages, format_hash, hash_cbz_pages, is_low_information, HashBlocklist,
+    DEFAULT_MAX_DISTANCE,
+};
+use comics_archiver::pipeline_actions::input_cbz_files;
+use comics_archiver::progress_actions::{Progress, ProgressEvent, ProgressSink};
+use comics_archiver::report_actions::saving_percent;
+use comics_archiver::stage_actions::ImageChain;
+use comics_archiver::verify_actions::verify_cbz;
+use comics_archiver::watch_actions::watch_inbox;
+use comics_archiver::Pipeline;
 use humantime::format_duration;
-use indicatif::{HumanBytes, MultiProgress, ProgressBar, ProgressState, ProgressStyle};
-use liblzma::write::XzEncoder;
-use rayon::iter::{IntoParallelRefIterator, IntoParallelRefMutIterator, ParallelIterator};
+use indicatif::{HumanBytes, MultiProgress, ProgressBar, ProgressStyle};
+use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
 use std::fs::File;
-use std::io::{self, BufReader, Cursor, Write};
-use std::path::{Path, PathBuf};
+use std::io::BufWriter;
+use std::path::Path;
 use std::process;
-use std::sync::{Arc, Mutex};
-use std::time::Instant;
-use tokio::fs::File as AsyncFile;
-use tokio::io::{copy as Async_Copy, AsyncReadExt, AsyncWriteExt, BufWriter};
-use walkdir::{DirEntry, WalkDir};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 #[derive(Parser, Debug)]
 #[command(
@@ -25,434 +43,1012 @@ use walkdir::{DirEntry, WalkDir};
     long_about = "Compress your manga .cbz files with max settings"
 )]
 struct Args {
-    #[arg(short, long)]
+    #[command(subcommand)]
+    command: Command,
+
+    #[command(flatten)]
+    config: ConfigArgs,
+}
+
+/// Overrides of the configuration files & environment, for every subcommand.
+#[derive(clap::Args, Debug)]
+struct ConfigArgs {
+    /// Configuration profile, like `archive`, `ereader` or `lossless`.
+    #[arg(long, global = true)]
+    profile: Option<String>,
+
+    /// Codec pages are written with: `jpeg`, `png` or `keep`.
+    #[arg(long, global = true)]
+    codec: Option<PageCodec>,
+
+    /// Jpeg quality, 1 to 100.
+    #[arg(long, global = true, value_parser = clap::value_parser!(u8).range(1..=100))]
+    jpeg_quality: Option<u8>,
+
+    /// Scale pages wider than this down.
+    #[arg(long, global = true, value_parser = clap::value_parser!(u32).range(1..))]
+    max_width: Option<u32>,
+
+    /// Scale pages taller than this down.
+    #[arg(long, global = true, value_parser = clap::value_parser!(u32).range(1..))]
+    max_height: Option<u32>,
+
+    /// Deflate level of the repacked archives, 0 to 9.
+    #[arg(long, global = true, value_parser = clap::value_parser!(i32).range(0..=9))]
+    deflate_level: Option<i32>,
+
+    /// Stages pages go through, in order, like `grayscale,resize,sharpen,encode`.
+    #[arg(long, global = true)]
+    stages: Option<String>,
+}
+
+impl ConfigArgs {
+    /// Load the configuration with these overrides on top, exit if it's invalid.
+    fn load(&self) -> PipelineConfig {
+        let overrides = ProfileSettings {
+            codec: self.codec,
+            jpeg_quality: self.jpeg_quality,
+            max_width: self.max_width,
+            max_height: self.max_height,
+            deflate_level: self.deflate_level,
+            xz_level: None,
+            stages: self.stages.as_deref().map(stage_list),
+            ..Default::default()
+        };
+        match PipelineConfig::load(self.profile.as_deref(), &overrides) {
+            Ok(config) => config,
+            Err(err) => exit_with_error(err),
+        }
+    }
+}
+
+/// Options of the `optimize` subcommand.
+#[derive(clap::Args, Debug)]
+struct OptimizeArgs {
+    /// Folder with the `.cbz` files to optimise.
     #[arg(short, long)]
     input_dir: String,
 
+    /// Folder the optimised archives are written to, `<input_dir>/tmp` by default.
+    #[arg(short, long)]
+    output_dir: Option<String>,
+
+    /// Project sizes & time from a sample of pages without writing anything.
+    #[arg(long)]
+    dry_run: bool,
+
+    /// Pages per archive compressed during a dry run, 0 only counts pages & sizes.
+    #[arg(long, default_value_t = 3)]
+    sample_pages: usize,
+
+    /// Write a per archive & per page report of the run as JSON.
+    #[arg(long)]
+    report_json: Option<String>,
+
+    /// Write a per archive & per page report of the run as CSV.
+    #[arg(long)]
+    report_csv: Option<String>,
+
+    /// Ignore the job journal of a previous run and reprocess every archive.
+    #[arg(long)]
+    restart: bool,
+
+    /// Don't skip archives found in the content-hash cache.
+    #[arg(long)]
+    no_cache: bool,
+
+    /// Keep going when an archive or page fails, list the failures at the end.
     #[arg(short, long)]
-    output_file: String,
+    keep_going: bool,
+
+    /// Recover the intact pages of damaged or truncated archives instead of failing them.
+    #[arg(long)]
+    salvage: bool,
+
+    /// Decode every repacked page & compare it with its source page before the run moves on.
+    #[arg(long)]
+    verify_output: bool,
+
+    /// Strip pages matching the hashes in this file, one hex hash per line.
+    #[arg(long)]
+    strip_blocklist: Option<String>,
+
+    /// Store pages without recompressing them & copy untouched entries straight from the source.
+    #[arg(long)]
+    passthrough: bool,
+
+    /// Encoding of entry names without the UTF-8 flag, like `shift_jis`, `gbk` or `cp437`.
+    #[arg(long, default_value_t = NameEncoding::Auto)]
+    name_encoding: NameEncoding,
+
+    /// Worker threads for unpacking, page compression & repacking, 0 uses every core.
+    #[arg(long, default_value_t = 0)]
+    threads: usize,
+
+    /// Archives held in memory at once, their pages share the threads, 0 matches `--threads`.
+    #[arg(long, default_value_t = 0)]
+    parallel_archives: usize,
 }
 
-/// Define compress worker
-fn compress_worker<P2: AsRef<Path>>(
-    source_path: DirEntry,
-    out_file: P2,
-) -> Result<u64, CompressionError> {
-    let input_dir = File::open(source_path.path());
-    let mut in_file = match input_dir {
-        Ok(in_file) => in_file,
-        Err(err) => {
-            eprintln!("Failed due to: {}", err);
-            return Err(CompressionError::IoError(err));
-        }
-    };
-    let output_file = File::create(&out_file);
-    let out = match output_file {
-        Ok(out) => out,
-        Err(err) => {
-            eprintln!("Failed to create output file: {}", err);
-            return Err(CompressionError::IoError(err));
-        }
-    };
+/// Exit codes of the binary, these stay stable so scripts can rely on them.
+const EXIT_FAILURE: i32 = 1;
+/// The run finished but some archives or pages failed.
+const EXIT_PARTIAL_FAILURE: i32 = 2;
+const EXIT_UNSUPPORTED_FILE: i32 = 3;
+const EXIT_WALKDIR: i32 = 4;
+const EXIT_ZIP: i32 = 5;
+const EXIT_IMAGE_DECODE: i32 = 6;
+const EXIT_IMAGE_ENCODE: i32 = 7;
+const EXIT_XZ: i32 = 8;
+const EXIT_METADATA: i32 = 9;
+const EXIT_CONTAINER: i32 = 10;
+const EXIT_CONFIG: i32 = 11;
 
-    let mut encoder = XzEncoder::new(out, 9);
-    let meta = format!(
-        "{}:{}\n",
-        source_path.path().file_name().unwrap().to_str().unwrap(),
-        source_path.metadata()?.len()
-    );
-    let _ = encoder.write_all(meta.as_bytes());
-    io::copy(&mut in_file, &mut encoder)?;
-    encoder.try_finish()?;
-    Ok(encoder.total_out())
+/// Exit code for an error that stopped the run.
+fn exit_code(err: &CompressionError) -> i32 {
+    match err {
+        CompressionError::IoError { .. } => EXIT_FAILURE,
+        CompressionError::UnsupportedFileType => EXIT_UNSUPPORTED_FILE,
+        CompressionError::WalkDirError(_) => EXIT_WALKDIR,
+        CompressionError::ZipError { .. } => EXIT_ZIP,
+        CompressionError::ImageDecodeError { .. } => EXIT_IMAGE_DECODE,
+        CompressionError::ImageEncodeError { .. } => EXIT_IMAGE_ENCODE,
+        CompressionError::XzError { .. } => EXIT_XZ,
+        CompressionError::MetadataError { .. } => EXIT_METADATA,
+        CompressionError::ContainerError { .. } => EXIT_CONTAINER,
+        CompressionError::ConfigError { .. } => EXIT_CONFIG,
+    }
 }
 
-fn cbz_file_count(file_dir: Arc<impl AsRef<Path> + Send + Sync>) -> u64 {
-    let mut file_count = 0;
+/// Print the error then exit with its exit code.
+fn exit_with_error(err: CompressionError) -> ! {
+    eprintln!("{}", err);
+    process::exit(exit_code(&err));
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Optimise the pages of every archive in a folder & repack them.
+    Optimize(OptimizeArgs),
+    /// Pack every archive in a folder into one xz compressed `.cbz.xz` bundle.
+    Bundle {
+        /// Folder with the `.cbz` files to pack.
+        #[arg(short, long)]
+        input_dir: String,
+
+        /// Bundle to write, like `library.cbz.xz`.
+        #[arg(short, long)]
+        output_file: String,
+
+        /// xz preset, 0 to 9, `xz_level` of the configuration by default.
+        #[arg(long, value_parser = clap::value_parser!(u32).range(0..=9))]
+        level: Option<u32>,
+
+        /// Encoder threads, 0 uses every core, `xz_threads` of the configuration by default.
+        #[arg(long)]
+        threads: Option<u32>,
+
+        /// Uncompressed bytes per xz block, 0 lets liblzma pick,
+        /// `xz_block_size` of the configuration by default.
+        #[arg(long)]
+        block_size: Option<u64>,
+
+        /// xz dictionary in bytes, `xz_dict_size` of the configuration by default.
+        #[arg(long, value_parser = clap::value_parser!(u32).range(4096..=1536 << 20))]
+        dict_size: Option<u32>,
+
+        /// Solid bundle for cold storage: archives ordered by series & page codec,
+        /// a 256 MiB dictionary & the slowest, longest range xz settings.
+        /// Written as one block by a single thread, `--threads` & `--block-size` are ignored.
+        #[arg(long)]
+        solid: bool,
+
+        /// With `--solid`, also keep archives of one release group & page size together.
+        #[arg(long, requires = "solid")]
+        group_similar: bool,
+    },
+    /// Unpack a `.cbz` archive or a `.cbz.xz` bundle into a folder.
+    Extract {
+        /// `.cbz` archive or `.cbz.xz` bundle to unpack.
+        #[arg(short, long)]
+        input: String,
+
+        /// Folder the entries are written to.
+        #[arg(short, long)]
+        output_dir: String,
+
+        /// Encoding of entry names without the UTF-8 flag, like `shift_jis`, `gbk` or `cp437`.
+        #[arg(long, default_value_t = NameEncoding::Auto)]
+        name_encoding: NameEncoding,
+    },
+    /// List the entries of a `.cbz` archive or the members of a `.cbz.xz` bundle.
+    List {
+        /// `.cbz` archive or `.cbz.xz` bundle to list.
+        #[arg(short, long)]
+        input: String,
+
+        /// Encoding of entry names without the UTF-8 flag, like `shift_jis`, `gbk` or `cp437`.
+        #[arg(long, default_value_t = NameEncoding::Auto)]
+        name_encoding: NameEncoding,
+    },
+    /// Check every archive for bad CRCs, broken pages & invalid `ComicInfo.xml`.
+    Verify {
+        /// Folder with the `.cbz` files to check.
+        #[arg(short, long)]
+        input_dir: String,
+    },
+    /// Show the pages, sizes, codecs & `ComicInfo.xml` fields of an archive.
+    Info {
+        /// `.cbz` archive to look at.
+        #[arg(short, long)]
+        input: String,
+    },
+    /// Turn a folder of images or a `.cbz` into a `.cbz`, converting its pages on the way.
+    Convert {
+        /// Folder of images or `.cbz` archive to convert.
+        #[arg(short, long)]
+        input: String,
+
+        /// `.cbz` archive to write.
+        #[arg(short, long)]
+        output_file: String,
 
-    for entry in WalkDir::new(file_dir.as_ref())
-        .into_iter()
-        .filter_map(|e| e.ok())
-    {
-        if let Some(ext) = entry.path().extension() {
-            if ext == "cbz" {
-                file_count += 1;
+        /// Format the pages are converted to.
+        #[arg(long, value_enum, default_value_t = ConvertTo::Keep)]
+        format: ConvertTo,
+    },
+    /// Watch an inbox folder and move optimised chapters into the library.
+    Watch {
+        /// Folder new `.cbz` files or image folders get dropped into.
+        #[arg(short, long)]
+        inbox: String,
+
+        /// Folder the optimised archives are moved to.
+        #[arg(short, long)]
+        library: String,
+
+        /// Seconds without writes before an inbox item counts as complete.
+        #[arg(short, long, default_value_t = 5)]
+        settle_secs: u64,
+
+        /// Decode every repacked page & compare it with its source page before the original is moved.
+        #[arg(long)]
+        verify_output: bool,
+
+        /// Keep the original data of pages that fail to compress instead of leaving the chapter in the inbox.
+        #[arg(short, long)]
+        keep_going: bool,
+
+        /// Don't skip chapters whose optimised output is already in the library.
+        #[arg(long)]
+        no_cache: bool,
+
+        /// Worker threads for unpacking, page compression & repacking, 0 uses every core.
+        #[arg(long, default_value_t = 0)]
+        threads: usize,
+    },
+    /// Find archives holding the same chapter & pick the one to keep.
+    Dedupe {
+        /// Folder with the `.cbz` files to scan.
+        #[arg(short, long)]
+        input_dir: String,
+
+        /// Which archive of a duplicate group to keep.
+        #[arg(long, value_enum, default_value_t = KeepBy::LargestResolution)]
+        keep: KeepBy,
+
+        /// Release groups in order of preference, for `--keep preferred-group`.
+        #[arg(long, value_delimiter = ',')]
+        prefer_group: Vec<String>,
+
+        /// Largest page hash distance still counted as the same page.
+        #[arg(long, default_value_t = DEFAULT_MAX_DISTANCE)]
+        max_distance: u32,
+
+        /// Move the duplicates that aren't kept into this folder.
+        #[arg(long)]
+        move_to: Option<String>,
+    },
+    /// Find pages repeated within & across archives, like credit pages & ads.
+    Duplicates {
+        /// Folder with the `.cbz` files to scan.
+        #[arg(short, long)]
+        input_dir: String,
+
+        /// Largest hash distance still counted as the same page.
+        #[arg(long, default_value_t = DEFAULT_MAX_DISTANCE)]
+        max_distance: u32,
+    },
+}
+
+/// Renders the progress of a run with `indicatif`, one bar for archives & one for pages.
+struct IndicatifProgress {
+    multi: MultiProgress,
+    archives: ProgressBar,
+    pages: ProgressBar,
+    written: AtomicU64,
+}
+
+impl IndicatifProgress {
+    fn new() -> Self {
+        let style = ProgressStyle::with_template("{wide_bar} {pos}/{len} {msg}").unwrap();
+        let multi = MultiProgress::new();
+        let archives = multi.add(ProgressBar::new(0).with_style(style.clone()));
+        let pages = multi.add(ProgressBar::new(0).with_style(style));
+        pages.set_message("pages");
+        Self {
+            multi,
+            archives,
+            pages,
+            written: AtomicU64::new(0),
+        }
+    }
+}
+
+impl ProgressSink for IndicatifProgress {
+    fn event(&self, event: ProgressEvent) {
+        match &event {
+            ProgressEvent::RunStarted { archives } => self.archives.set_length(*archives),
+            ProgressEvent::ArchiveStarted { pages, .. } => self.pages.inc_length(*pages),
+            ProgressEvent::PageProcessed { .. } => self.pages.inc(1),
+            ProgressEvent::BytesWritten { bytes, .. } => {
+                let written = self.written.fetch_add(*bytes, Ordering::Relaxed) + bytes;
+                self.archives
+                    .set_message(format!("archives, {} written", HumanBytes(written)));
+            }
+            ProgressEvent::ArchiveFinished { .. } => self.archives.inc(1),
+            //NOTE: `MultiProgress::println` drops lines when stderr isn't a terminal.
+            ProgressEvent::Warning { .. } => self.multi.suspend(|| eprintln!("Warning: {}", event)),
+            ProgressEvent::Message(_) => self.multi.suspend(|| println!("{}", event)),
+            ProgressEvent::RunFinished => {
+                self.pages.finish();
+                self.archives.finish();
             }
         }
     }
-    file_count as u64
 }
 
-fn cbz_file_list(
-    file_list: Arc<impl AsRef<Path> + Send + Sync>,
-) -> Result<Vec<PathBuf>, CompressionError> {
-    let mut discovered_entries = Vec::new();
-
-    for entry in WalkDir::new(file_list.as_ref())
-        .into_iter()
-        .filter_map(|e| e.ok())
-    {
-        if let Some(ext) = entry.path().extension() {
-            if ext == "cbz" {
-                discovered_entries.push(entry.path().to_owned());
+/// Prints the progress of long running commands line by line, like `watch`.
+struct LineProgress;
+
+impl ProgressSink for LineProgress {
+    fn event(&self, event: ProgressEvent) {
+        match &event {
+            ProgressEvent::ArchiveStarted { .. }
+            | ProgressEvent::ArchiveFinished { .. }
+            | ProgressEvent::Message(_) => {
+                println!("{}", event)
             }
+            ProgressEvent::Warning { .. } => eprintln!("Warning: {}", event),
+            _ => {}
         }
     }
-    Ok(discovered_entries)
 }
 
-//TODO: Move this to another file then write the decompression logic.
-/// Define compress action
-/// Compress the given files, then return
-/// the list of included files for compression & output file size.
-/// * `dir_path`: Directory with cbz files.
-/// * `output_file`: Name of output file.
-async fn compress_action<'a, P2: AsRef<Path>>(
-    dir_path: Arc<impl AsRef<Path> + Send + Sync + 'static>,
-    output_file: P2,
-) -> Result<(Vec<(String, Vec<u8>, PathBuf)>, u64), CompressionError> {
-    let mut compressed_list: Vec<PathBuf> = Vec::new();
-    let compressed_size: u64;
-    let out_file = match AsyncFile::create(output_file).await {
-        Ok(out) => out,
-        Err(err) => {
-            eprintln!("Fail!");
-            return Err(CompressionError::IoError(err));
+/// Page format `convert` writes.
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum ConvertTo {
+    Keep,
+    Jpeg,
+    Png,
+}
+
+impl From<ConvertTo> for PageFormat {
+    fn from(format: ConvertTo) -> Self {
+        match format {
+            ConvertTo::Keep => PageFormat::Keep,
+            ConvertTo::Jpeg => PageFormat::Jpeg,
+            ConvertTo::Png => PageFormat::Png,
         }
-    };
+    }
+}
 
-    let dir_clone = &dir_path.clone();
-    let total_files = cbz_file_count(dir_path.clone());
-
-    let multi_pb = MultiProgress::new();
-    let pb = multi_pb.add(ProgressBar::new(total_files as u64));
-    //    let mut progress = 0;
-
-    let mut encoder = XzEncoder::new(out_file.into_std().await, 9);
-    //  let percent_completion = (progress as f32 / total_files as f32) * 100.0;
-    let mut compressed_list = Vec::new();
-    //NOTE: Parallelism idea?
-    /*
-    let mut entries: Result<Vec<DirEntry>, _> = WalkDir::new(dir_path)
-        .into_iter()
-        .filter_map(|entry| {
-            let entry = entry.unwrap();
-            if entry.path().extension().map_or(false, |ext| ext == "cbz") {
-                Some(entry.path().to_owned())
-            } else {
-                None
-            }
-        })
-        .try_fold(Vec::new(), |mut acc, path| {
-            acc.push(entry);
-            Ok(acc)
-        });
-    let res = entries
-        .par_iter()
-        .for_each(|x| match extract_dir_and_files_from_cbz(x).await {
-            Ok(f) => f,
+/// Rule for picking the archive `dedupe` keeps.
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum KeepBy {
+    LargestResolution,
+    PreferredGroup,
+    Newest,
+}
+
+impl From<KeepBy> for KeepRule {
+    fn from(keep: KeepBy) -> Self {
+        match keep {
+            KeepBy::LargestResolution => KeepRule::LargestResolution,
+            KeepBy::PreferredGroup => KeepRule::PreferredGroup,
+            KeepBy::Newest => KeepRule::Newest,
+        }
+    }
+}
+
+/// Define dry run action
+/// Project the outcome of a `Pipeline` run from a sample of pages
+/// and print it per archive, without writing anything.
+/// Archives that can't be read are listed as failed and left out of the total.
+/// * `dir_path`: Directory with cbz files.
+/// * `sample_pages`: Pages per archive to actually compress.
+/// * `config`: Settings the pages get compressed with.
+fn dry_run_action(
+    dir_path: Arc<impl AsRef<Path> + Send + Sync>,
+    sample_pages: usize,
+    config: &PipelineConfig,
+) -> Result<(), CompressionError> {
+    let chain = ImageChain::from_config(config)?;
+    let mut total_original: u64 = 0;
+    let mut total_projected: Option<u64> = Some(0);
+    let mut total_time = Duration::ZERO;
+    let mut failed = Vec::new();
+
+    println!(
+        "{:<40} {:>6} {:>12} {:>12} {:>8} {:>16}",
+        "Archive", "Pages", "Original", "Projected", "Saving", "Time"
+    );
+    for filez in input_cbz_files(dir_path.clone())? {
+        let archive_name = filez.file_name().unwrap_or_default().to_string_lossy();
+        let projection = match project_archive(&filez, sample_pages, &chain) {
+            Ok(projection) => projection,
             Err(err) => {
-                eprintln!("Failed due to: {}", err);
-                return Err(CompressionError::IoError(err));
+                println!("{:<40} {:>6}", archive_name, "failed");
+                failed.push(err);
+                continue;
             }
-        });
-    for (_, file_path) in res {
-        println!(": {}", file_path.to_str().unwrap());
+        };
+        println!(
+            "{:<40} {:>6} {:>12} {}",
+            archive_name,
+            projection.page_count,
+            HumanBytes(projection.original_size).to_string(),
+            projection_columns(
+                projection.original_size,
+                projection.projected_size,
+                projection.projected_time
+            )
+        );
+        total_original += projection.original_size;
+        total_projected = total_projected
+            .zip(projection.projected_size)
+            .map(|(a, b)| a + b);
+        total_time += projection.projected_time.unwrap_or_default();
     }
-    */
-    println!("Begin the process...");
-    let raw_files = tokio::spawn(async {
-        let raw_file_list = cbz_file_list(dir_path).unwrap();
-        let mut extracted_files = Vec::new();
-        for filez in raw_file_list {
-            let data = match extract_dir_and_files_from_cbz(filez).await {
-                Ok(f) => f,
-                Err(err) => {
-                    eprintln!("Failed due to: {}", err);
-                    return Err(CompressionError::IoError(err));
-                }
-            };
-            //TODO: Figure out filter conditions.
-            if !extracted_files.contains(&data) {
-                extracted_files.push(data);
-            }
+
+    //NOTE: Pages are compressed on every core, so wall time is roughly split between them.
+    let wall_time = total_time / rayon::current_num_threads() as u32;
+    println!(
+        "{:<40} {:>6} {:>12} {}",
+        "Total",
+        "",
+        HumanBytes(total_original).to_string(),
+        projection_columns(
+            total_original,
+            total_projected,
+            total_projected.map(|_| wall_time)
+        )
+    );
+    if !failed.is_empty() {
+        eprintln!("Failed to read {} archives:", failed.len());
+        for err in &failed {
+            eprintln!("  {}", err);
+        }
+    }
+    Ok(())
+}
+
+/// Projected size, saving & time columns of a dry run row, `-` when nothing was projected.
+fn projection_columns(
+    original_size: u64,
+    projected_size: Option<u64>,
+    projected_time: Option<Duration>,
+) -> String {
+    match (projected_size, projected_time) {
+        (Some(projected_size), Some(projected_time)) => format!(
+            "{:>12} {:>7.1}% {:>16}",
+            HumanBytes(projected_size).to_string(),
+            saving_percent(original_size, projected_size),
+            format_duration(Duration::from_millis(projected_time.as_millis() as u64)).to_string()
+        ),
+        _ => format!("{:>12} {:>8} {:>16}", "-", "-", "-"),
+    }
+}
+
+/// Define dedupe action
+/// Fingerprint every archive, group the ones holding the same chapter
+/// and print which one of each group is kept.
+/// * `dir_path`: Directory with cbz files.
+/// * `rule`: Which archive of a group to keep.
+/// * `preferred_groups`: Release groups in order of preference.
+/// * `max_distance`: Largest page hash distance still counted as the same page.
+/// * `move_to`: Folder to move the dropped archives into, nothing is moved without it.
+fn dedupe_action(
+    dir_path: Arc<impl AsRef<Path> + Send + Sync>,
+    rule: KeepRule,
+    preferred_groups: &[String],
+    max_distance: u32,
+    move_to: Option<&Path>,
+) -> Result<(), CompressionError> {
+    let archives = input_cbz_files(dir_path.clone())?;
+    let fingerprinted: Vec<_> = archives.par_iter().map(fingerprint_cbz).collect();
+
+    let mut fingerprints = Vec::new();
+    for (archive, fingerprint) in archives.iter().zip(fingerprinted) {
+        match fingerprint {
+            Ok(fingerprint) => fingerprints.push(fingerprint),
+            Err(err) => eprintln!("Skipping {}: {}", archive.display(), err),
         }
-        Ok(extracted_files)
-    });
-    /*
-    * NOTE: This block does work though
-    for entry in WalkDir::new(&dir_path).into_iter().filter_map(|e| e.ok()) {
-        if let Some(ext) = entry.path().extension() {
-            if ext == "cbz" {
-                //NOTE: Takes time to extract.
-                let cbz_entries = match extract_dir_and_files_from_cbz(entry.path()).await {
-                    Ok(f) => f,
-                    Err(err) => {
-                        eprintln!("Failed due to: {}", err);
-                        return Err(CompressionError::IoError(err));
-                    }
-                };
-
-                //NOTE: If it doesnt work out remove chunks * clones
-                //EDIT: This does help free up some performance, problem is its writing more than
-                //once?
-                //NOTE: Chunking was the right answer :) 4 allows it to at least repack all files
-                //nicely.
-                for file_data in cbz_entries.chunks(4) {
-                    if let Some(s) = file_data.first() {
-                        let compressed_img = match compress_images_with_img(s.1.clone()) {
-                            Ok(img) => img,
-                            Err(err) => {
-                                eprintln!("Error compressing image! : {}", err);
-                                return Err(err);
-                            }
-                        };
-                        compressed_list.push((s.0.clone(), compressed_img, s.2.clone()));
-                    };
+    }
+
+    let groups = group_duplicates(&fingerprints, max_distance);
+    let mut dropped = 0;
+    for group in &groups {
+        let keeper = pick_keeper(group, rule, preferred_groups);
+        println!("Duplicate group ({} archives)", group.len());
+        for (idx, fingerprint) in group.iter().enumerate() {
+            if idx == keeper {
+                println!("  KEEP {}", fingerprint.archive.display());
+                continue;
+            }
+            println!("  DROP {}", fingerprint.archive.display());
+            dropped += 1;
+            if let Some(move_to) = move_to {
+                let relative = fingerprint
+                    .archive
+                    .strip_prefix(dir_path.as_ref())
+                    .unwrap_or(&fingerprint.archive);
+                let target = move_to.join(relative);
+                if let Some(parent) = target.parent() {
+                    std::fs::create_dir_all(parent)?;
                 }
+                std::fs::rename(&fingerprint.archive, &target)?;
             }
         }
-        */
-    /*
-    for (file_name, _, file_path) in &compressed_list {
-        println!(
-            "file_name: {}, compressed_list: {}",
-            file_name,
-            file_path.to_str().unwrap()
-        );
     }
-    */
-    /*
-    let mut size: usize = 0;
-    if let Some(f) = compressed_list.first() {
-        size = f.1.len();
-    }
-    */
-    //BUG: Being called too many times without waiting for unpacking to finish.
-    let mut raw_data: Vec<Vec<(String, Vec<u8>, PathBuf)>> = raw_files.await.unwrap().unwrap();
-    let pb_imgs = multi_pb.insert_after(&pb, ProgressBar::new(raw_data.len() as u64));
-    let mutex_data = Arc::new(Mutex::new(raw_data.clone()));
-    pb_imgs.set_message("Compressing images...");
-    raw_data.par_iter_mut().for_each(|imgs| {
-        let mut raw_data = mutex_data.lock().unwrap();
-        for inner_items in imgs.iter_mut() {
-            let img_1 = inner_items.1.clone();
-            inner_items.1 = compress_images_with_img(img_1).expect("Failed to compress!");
-            pb_imgs.inc(1);
+    println!(
+        "{} archives scanned, {} duplicate groups, {} archives to drop",
+        fingerprints.len(),
+        groups.len(),
+        dropped
+    );
+    Ok(())
+}
+
+/// Define duplicates action
+/// Hash every page & print the groups of pages that look the same,
+/// within a single archive or across archives.
+/// * `dir_path`: Directory with cbz files.
+/// * `max_distance`: Largest hash distance still counted as the same page.
+fn duplicates_action(
+    dir_path: Arc<impl AsRef<Path> + Send + Sync>,
+    max_distance: u32,
+) -> Result<(), CompressionError> {
+    let archives = input_cbz_files(dir_path.clone())?;
+    let hashed: Vec<_> = archives.par_iter().map(hash_cbz_pages).collect();
+
+    let mut pages = Vec::new();
+    for (archive, hashes) in archives.iter().zip(hashed) {
+        match hashes {
+            Ok(hashes) => pages.extend(hashes),
+            //NOTE: One broken archive shouldn't stop the scan, `verify` tells what's wrong with it.
+            Err(err) => eprintln!("Skipping {}: {}", archive.display(), err),
         }
-    });
-    pb_imgs.finish_with_message("Finished compressing images!");
-
-    //Loop inside all cbz archives.
-    let final_compression: Vec<Result<(String, Vec<u8>), CompressionError>> = raw_data
-        .par_iter()
-        .map(
-            |files| match compress_dir_and_files_to_cbz(files.to_vec()) {
-                Ok(complete) => Ok(complete),
-                Err(err) => {
-                    return Err(CompressionError::IoError(err));
-                }
-            },
-        )
-        .collect();
+    }
 
-    let tmp_output_path = format!(
-        "{}/{}",
-        dir_clone.as_ref().as_ref().to_str().unwrap(),
-        "tmp"
+    let groups = find_duplicate_pages(&pages, max_distance);
+    for group in &groups {
+        println!("{} ({} pages)", format_hash(group[0].hash), group.len());
+        for page in group {
+            println!(
+                "  {} {}:{}",
+                format_hash(page.hash),
+                page.archive.display(),
+                page.entry
+            );
+        }
+    }
+    println!(
+        "{} pages hashed, {} blank pages skipped, {} duplicate groups",
+        pages.len(),
+        pages
+            .iter()
+            .filter(|page| is_low_information(page.hash))
+            .count(),
+        groups.len()
     );
-    tokio::fs::create_dir_all(tmp_output_path.clone()).await?;
+    Ok(())
+}
 
-    for items in final_compression {
-        let _ = if let Ok(item) = items {
-            let tmp_file_path = format!("{}/{}", tmp_output_path, item.0);
-            match tokio::fs::write(tmp_file_path, item.1).await {
-                Ok(done) => done,
+/// Define verify action
+/// Check the integrity of every archive without changing anything
+/// and print a pass or fail line per archive.
+/// * `dir_path`: Directory with cbz files.
+///
+/// Return `usize` number of archives that failed.
+fn verify_action(dir_path: Arc<impl AsRef<Path> + Send + Sync>) -> Result<usize, CompressionError> {
+    let archives = input_cbz_files(dir_path.clone())?;
+    let verifications: Vec<_> = archives.par_iter().map(verify_cbz).collect();
 
-                Err(err) => {
-                    eprintln!("Error writing cbz file! : {}", err);
-                    return Err(CompressionError::IoError(err));
-                }
-            };
-        };
+    let mut failed = 0;
+    for verification in &verifications {
+        if verification.passed() {
+            println!(
+                "PASS {} ({} pages)",
+                verification.archive.display(),
+                verification.pages
+            );
+        } else {
+            failed += 1;
+            println!("FAIL {}", verification.archive.display());
+            for problem in &verification.problems {
+                println!("  {}", problem);
+            }
+        }
     }
-    pb.inc(1);
-    /*
-            match tokio::io::copy(&mut Cursor::new(data.1), &mut optimized_file).await {
-                Ok(_done) => {
-                    println!("Copying Done!");
-                }
-                Err(err) => {
-                    eprintln!("Failed to copy repacked file: {}", err);
-                }
-            };
-    */
-
-    /*
-            //TODO: MAke this async
-            match encoder.write_all(&data[..]) {
-                Ok(_done) => {
-                    println!("Writing to file...: ");
-                    encoder.try_finish()?;
-                }
-                Err(err) => {
-                    eprintln!("Error repacking files! : {}", err);
-                    return Err(CompressionError::IoError(err));
-                }
-            };
-    */
+    println!("{} passed, {} failed", verifications.len() - failed, failed);
+    Ok(failed)
+}
 
-    // Using it directly with no error handling works fine
-    //let cbz_entries = extract_dir_and_files_from_cbz(entry.path()).await?;
+/// Whether `path` is a `.cbz.xz` bundle rather than a single archive.
+fn is_bundle<P: AsRef<Path>>(path: P) -> bool {
+    path.as_ref()
+        .to_string_lossy()
+        .ends_with(&format!(".{}", BUNDLE_EXTENSION))
+}
 
-    // Print cbz_entries on screen for testing
-    /*
-            for (file_name, file_path) in cbz_entries {
-                println!("{:?}: {}", file_name, file_path.to_str().unwrap());
-            }
-    */
-    //TODO: fetch entries and print
-    /*
-    * NOTE: This only compresses individual files not grabbing everything then compressing.
-    if val.file_type().is_file() && val.path().extension().map_or(false, |ext| ext == "rs") {
-        let file = val.path().to_path_buf();
-        compressed_size = compress_worker(val, &output_file)?;
-        compressed_list.push(file);
+/// Define bundle action
+/// Pack every archive of a folder into one xz bundle,
+/// members keep their path relative to the folder.
+/// * `dir_path`: Directory with cbz files.
+/// * `output_file`: Bundle to write.
+/// * `options`: xz preset, threads & block size.
+fn bundle_action<P2: AsRef<Path>>(
+    dir_path: Arc<impl AsRef<Path> + Send + Sync>,
+    output_file: P2,
+    options: &XzOptions,
+    group_similar: bool,
+) -> Result<(), CompressionError> {
+    let mut members = Vec::new();
+    let mut original_size = 0;
+    for filez in input_cbz_files(dir_path.clone())? {
+        let relative = filez.strip_prefix(dir_path.as_ref()).unwrap_or(&filez);
+        //NOTE: Members always use `/`, so bundles unpack the same on every platform.
+        let name = relative
+            .components()
+            .map(|component| component.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/");
+        original_size += filez.metadata()?.len();
+        members.push((name, filez));
     }
-    */
-
-    /*
-                progress += 1;
-                if let Some(ext) = entry.path().extension() {
-                    if ext == "cbz" {
-                        let file = File::open(entry.path());
-                        compressed_list.push(entry.path().to_path_buf());
-                        let curr_file = match file {
-                            Ok(curr_file) => curr_file,
-                            Err(err) => {
-                                eprintln!("Failed to create file: {}", err);
-                                return Err(CompressionError::IoError(err));
-                            }
-                        };
-                        let metadata = format!(
-                            "{}:{}\n",
-                            entry.path().file_name().unwrap().to_str().unwrap(),
-                            entry.metadata()?.len()
-                        );
-                        encoder.write_all(metadata.as_bytes())?;
-                        println!("{}", entry.path().file_name().unwrap().to_str().unwrap());
-                        io::copy(&mut io::BufReader::new(curr_file), &mut encoder)?;
-                    }
-
-                    print!("\r[");
-                    let percent_chars = (percent_completion / 2.0) as usize;
-                    for _ in 0..percent_chars {
-                        print!("=");
-                    }
-                    for _ in percent_chars..50 {
-                        print!(" ");
-                    }
-                    print!("] {:.2}% ", percent_completion);
-                    io::stdout().flush().unwrap();
-                }
+    if options.solid {
+        solid_order(&mut members, group_similar);
+    } else {
+        members.sort();
+    }
+    println!(
+        "Bundling {} archives with {} threads, about {} of memory...",
+        members.len(),
+        options.thread_count(),
+        HumanBytes(options.memory_usage()?)
+    );
+    let bundle_size = write_bundle(&members, &output_file, options)?;
+    println!(
+        "{} -> {} ({:.1}% saved) in {}",
+        HumanBytes(original_size),
+        HumanBytes(bundle_size),
+        saving_percent(original_size, bundle_size),
+        output_file.as_ref().display()
+    );
+    Ok(())
+}
 
+/// Define extract action
+/// Unpack a `.cbz` archive or a `.cbz.xz` bundle into a folder.
+/// * `input`: Archive or bundle to unpack.
+/// * `output_dir`: Folder the entries are written to.
+/// * `name_encoding`: How to decode entry names without the UTF-8 flag.
+fn extract_action<P1: AsRef<Path>, P2: AsRef<Path>>(
+    input: P1,
+    output_dir: P2,
+    name_encoding: NameEncoding,
+) -> Result<(), CompressionError> {
+    std::fs::create_dir_all(output_dir.as_ref())?;
+    let written = if is_bundle(&input) {
+        extract_bundle(&input, &output_dir)?
+    } else {
+        unpack_cbz_to_dir(&input, &output_dir, name_encoding)?
+    };
+    println!(
+        "Extracted {} files to {}",
+        written.len(),
+        output_dir.as_ref().display()
+    );
+    Ok(())
+}
 
-        println!("before repacking: {}", HumanBytes(size as u64));
-        println!("after repacking: {}", HumanBytes(data.1.len() as u64));
+/// Define list action
+/// Print the entries of a `.cbz` archive or the members of a `.cbz.xz` bundle.
+/// * `input`: Archive or bundle to list.
+/// * `name_encoding`: How to decode entry names without the UTF-8 flag.
+fn list_action<P: AsRef<Path>>(
+    input: P,
+    name_encoding: NameEncoding,
+) -> Result<(), CompressionError> {
+    if is_bundle(&input) {
+        let mut members = 0;
+        let mut total_size = 0;
+        println!("{:>12} Name", "Size");
+        //NOTE: Members are only skipped over, never written anywhere.
+        read_bundle(&input, |name, size, _| {
+            println!("{:>12} {}", HumanBytes(size).to_string(), name);
+            members += 1;
+            total_size += size;
+            Ok(())
+        })?;
+        println!(
+            "{:>12} {} members",
+            HumanBytes(total_size).to_string(),
+            members
+        );
+        return Ok(());
+    }
+    let listing = list_cbz(&input, name_encoding)?;
+    println!(
+        "{:>12} {:>12} {:<10} {:<19} Name",
+        "Size", "Compressed", "Method", "Modified"
+    );
+    for entry in &listing {
+        println!(
+            "{:>12} {:>12} {:<10} {:<19} {}",
+            HumanBytes(entry.size).to_string(),
+            HumanBytes(entry.compressed_size).to_string(),
+            entry.method,
+            entry.modified,
+            entry.name
+        );
+    }
+    println!(
+        "{:>12} {:>12} {} entries",
+        HumanBytes(listing.iter().map(|entry| entry.size).sum()).to_string(),
+        HumanBytes(listing.iter().map(|entry| entry.compressed_size).sum()).to_string(),
+        listing.len()
+    );
+    Ok(())
+}
+
+/// Define info action
+/// Print an overview of a `.cbz` archive.
+/// * `input`: Archive to look at.
+fn info_action<P: AsRef<Path>>(input: P) -> Result<(), CompressionError> {
+    let info = archive_info(input)?;
+    let format_dimensions = |dimensions: Option<(u32, u32)>| match dimensions {
+        Some((width, height)) => format!("{}x{}", width, height),
+        None => "-".to_string(),
+    };
+    println!("Archive:    {}", info.archive.display());
+    println!("Size:       {}", HumanBytes(info.size));
+    println!("Entries:    {}", info.entries);
+    println!(
+        "Pages:      {} ({})",
+        info.pages,
+        HumanBytes(info.page_bytes)
+    );
+    let codecs: Vec<String> = info
+        .codecs
+        .iter()
+        .map(|(codec, count)| format!("{}: {}", codec, count))
+        .collect();
+    println!("Codecs:     {}", codecs.join(", "));
+    println!("Smallest:   {}", format_dimensions(info.min_dimensions));
+    println!("Largest:    {}", format_dimensions(info.max_dimensions));
+    if !info.comment.is_empty() {
+        println!("Comment:    {}", info.comment);
     }
+    if !info.comic_info.is_empty() {
+        println!("ComicInfo:");
+        for (field, value) in &info.comic_info {
+            println!("  {:<10} {}", field, value);
+        }
+    }
+    Ok(())
+}
 
-        */
-    pb.finish_with_message("Compression done!");
-    //encoder.try_finish()?;
-    compressed_size = encoder.total_out();
-    Ok((compressed_list.clone(), compressed_size))
+/// Define convert action
+/// Turn a folder of images or a `.cbz` into a `.cbz`,
+/// re-encoding its pages into `format` on the way.
+/// * `input`: Folder of images or `.cbz` archive.
+/// * `output_file`: `.cbz` archive to write.
+/// * `format`: Format the pages are converted to.
+/// * `config`: Settings like the jpeg quality & deflate level.
+async fn convert_action<P1: AsRef<Path>, P2: AsRef<Path>>(
+    input: P1,
+    output_file: P2,
+    format: PageFormat,
+    config: &PipelineConfig,
+) -> Result<(), CompressionError> {
+    let input = input.as_ref();
+    let (mut entries, metadata) = if input.is_dir() {
+        (
+            read_dir_and_files_from_folder(input)?,
+            ArchiveMetadata::default(),
+        )
+    } else {
+        (
+            extract_dir_and_files_from_cbz(input, NameEncoding::Auto).await?,
+            read_archive_metadata(input, NameEncoding::Auto)?,
+        )
+    };
+    if entries.is_empty() {
+        return Err(CompressionError::container("Nothing to convert").in_archive(input));
+    }
+    convert_pages(&mut entries, format, config).map_err(|err| err.in_archive(input))?;
+    let (_, data) = compress_dir_and_files_to_cbz(entries, &metadata, config)?;
+    std::fs::write(output_file.as_ref(), data)?;
+    println!("Wrote {}", output_file.as_ref().display());
+    Ok(())
 }
-#[tokio::main]
-async fn main() {
-    let args = Args::parse();
-    let output_file = args.output_file.clone();
-    let input_dir = Arc::new(args.input_dir);
+
+/// Define optimize action
+/// Run a `Pipeline` or `dry_run_action` with the `optimize` options,
+/// then print & save the report.
+async fn optimize_action(args: OptimizeArgs, config: PipelineConfig) {
+    if args.dry_run {
+        if let Err(err) = dry_run_action(Arc::new(args.input_dir), args.sample_pages, &config) {
+            exit_with_error(err);
+        }
+        return;
+    }
+    println!("Profile {}: {}", config.profile, config.settings());
+    let mut pipeline = Pipeline::new()
+        .input(&args.input_dir)
+        .config(config)
+        .threads(args.threads)
+        .parallel_archives(args.parallel_archives)
+        .restart(args.restart)
+        .use_cache(!args.no_cache)
+        .keep_going(args.keep_going)
+        .salvage(args.salvage)
+        .verify_output(args.verify_output)
+        .passthrough(args.passthrough)
+        .name_encoding(args.name_encoding)
+        .progress(Arc::new(IndicatifProgress::new()));
+    if let Some(output_dir) = &args.output_dir {
+        pipeline = pipeline.output_dir(output_dir);
+    }
+    if let Some(path) = &args.strip_blocklist {
+        match HashBlocklist::load(path, DEFAULT_MAX_DISTANCE) {
+            Ok(blocklist) => pipeline = pipeline.strip_blocklist(blocklist),
+            Err(err) => exit_with_error(err.into()),
+        }
+    }
     let time_taken = Instant::now();
-    /*
-     * TODO: Refactoring on how the code/logic behaves.
-     * NOTE: The below snippets are meant to be in `process_chapters`.
-     * Which will be one function handling all the data processing & file IO.
-     * As it goes over chapters.
-     * NOTE: Get name of img, img_data, metadata
-     * let extracted_data: Vec<String, Vec<u8>, Vec<Path>> = extract_files(AsRef<Path>).await
-     * let optimize_images: Vec<String, Vec<u8>, Vec<Path>> = optimize_imgs(extracted_data.await)
-     * let compressed_data: Vec<String, Vec<u8>> = repack_files(optimize_images)
-     * write_to_disk(compressed_data).await
-     * */
-    match compress_action(input_dir, args.output_file).await {
-        Ok(compressed) => {
+    match pipeline.run().await {
+        Ok(report) => {
             println!("Compression done for: ");
-            for file in compressed.0 {
-                //println!("{}", file.1.to_str().unwrap());
+            println!("{}", report.summary_table());
+            let mut report_error = None;
+            if let Some(report_json) = &args.report_json {
+                let saved = File::create(report_json)
+                    .and_then(|file| report.write_json(BufWriter::new(file)));
+                if let Err(err) = saved {
+                    eprintln!("Failed to write JSON report: {}", err);
+                    report_error = Some(err);
+                }
+            }
+            if let Some(report_csv) = &args.report_csv {
+                let saved = File::create(report_csv)
+                    .and_then(|file| report.write_csv(BufWriter::new(file)));
+                if let Err(err) = saved {
+                    eprintln!("Failed to write CSV report: {}", err);
+                    report_error = Some(err);
+                }
             }
-            println!("New compressed file name: {}", output_file);
-            println!("New file size: {}", compressed.1);
             println!(
                 "Total time taken for compression: {}",
-                format_duration(time_taken.elapsed()).to_string()
+                format_duration(time_taken.elapsed())
             );
+            let failures = report.failures();
+            if !failures.is_empty() {
+                eprintln!("Failed archives & pages:");
+                for failure in failures {
+                    eprintln!("  {}", failure);
+                }
+                process::exit(EXIT_PARTIAL_FAILURE);
+            }
+            if let Some(err) = report_error {
+                exit_with_error(err.into());
+            }
         }
-        Err(CompressionError::IoError(err)) => {
-            eprintln!("I/O Error: {}", err);
-            process::exit(1);
-        }
-
-        Err(CompressionError::UnsupportedFileType) => {
-            eprintln!("Unsupported File Type only CBZ files are supported");
-            process::exit(1);
-        }
-
-        Err(CompressionError::WalkDirError(err)) => {
-            eprintln!("Failed to find files in directory: {}", err);
-            process::exit(1);
-        }
+        Err(err) => exit_with_error(err),
     }
 }
 
-// This function works fine but hangs since it doesnt exit
-fn main_working() -> io::Result<()> {
+#[tokio::main]
+async fn main() {
     let args = Args::parse();
-    let out_file_path = args.output_file;
-    let output_file = File::create(out_file_path);
-    let out = match output_file {
-        Ok(out) => out,
-        Err(err) => {
-            eprintln!("Error opening stream: {}", err);
-            return Err(err);
+    let result = match args.command {
+        Command::Optimize(optimize_args) => {
+            optimize_action(optimize_args, args.config.load()).await;
+            Ok(())
         }
-    };
-
-    let mut xz_encoder = XzEncoder::new(out, 9);
-
-    for entry in WalkDir::new(args.input_dir)
-        .into_iter()
-        .filter_map(|e| e.ok())
-    {
-        if let Some(extension) = entry.path().extension() {
-            if extension == "rs" {
-                let file_buff = File::open(entry.path())?;
-                let file_reader = BufReader::new(file_buff);
-                let _ = match io::copy(&mut BufReader::new(file_reader), &mut xz_encoder) {
-                    Ok(complete) => {
-                        println!(
-                            "Compression complete! About {} bytes were written",
-                            complete
-                        );
-                        let _ = xz_encoder.try_finish();
-                        Ok(())
-                    }
-                    Err(err) => Err(err),
-                };
-            }
+        Command::Bundle {
+            input_dir,
+            output_file,
+            level,
+            threads,
+            block_size,
+            dict_size,
+            solid,
+            group_similar,
+        } => {
+            let mut options = XzOptions::from(&args.config.load());
+            options.level = level.unwrap_or(options.level);
+            options.threads = threads.unwrap_or(options.threads);
+            options.block_size = block_size.unwrap_or(options.block_size);
+            options.dict_size = dict_size.unwrap_or(options.dict_size);
+            options.solid = solid;
+            bundle_action(Arc::new(input_dir), output_file, &options, group_similar)
+        }
+        Command::Extract {
+            input,
+            output_dir,
+            name_encoding,
+        } => extract_action(input, output_dir, name_encoding),
+        Command::List {
+            input,
+            name_encoding,
+        } => list_action(input, name_encoding),
+        Command::Verify { input_dir } => match verify_action(Arc::new(input_dir)) {
+            Ok(0) => Ok(()),
+            Ok(_) => process::exit(EXIT_PARTIAL_FAILURE),
+            Err(err) => Err(err),
+        },
+        Command::Info { input } => info_action(input),
+        Command::Convert {
+            input,
+            output_file,
+            format,
+        } => convert_action(input, output_file, format.into(), &args.config.load()).await,
+        Command::Watch {
+            inbox,
+            library,
+            settle_secs,
+            verify_output,
+            keep_going,
+            no_cache,
+            threads,
+        } => {
+            let pipeline = Pipeline::new()
+                .config(args.config.load())
+                .threads(threads)
+                .use_cache(!no_cache)
+                .keep_going(keep_going)
+                .verify_output(verify_output);
+            watch_inbox(
+                inbox,
+                library,
+                Duration::from_secs(settle_secs),
+                &pipeline,
+                &(Arc::new(LineProgress) as Progress),
+            )
+            .await
         }
+        Command::Dedupe {
+            input_dir,
+            keep,
+            prefer_group,
+            max_distance,
+            move_to,
+        } => dedupe_action(
+            Arc::new(input_dir),
+            keep.into(),
+            &prefer_group,
+            max_distance,
+            move_to.as_deref().map(Path::new),
+        ),
+        Command::Duplicates {
+            input_dir,
+            max_distance,
+        } => duplicates_action(Arc::new(input_dir), max_distance),
+    };
+    if let Err(err) = result {
+        exit_with_error(err);
     }
-    xz_encoder.finish()?;
-
-    println!("Compression complete. Output: ");
-    Ok(())
 }
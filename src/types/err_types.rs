@@ -1,9 +1,2 @@
-use std::io;
-
-// Custom Error type
-#[derive(Debug)]
-pub enum CompressionError {
-    IoError(io::Error),
-    UnsupportedFileType,
-    WalkDirError(walkdir::Error),
-}
+// Custom Error type, defined with its impls in `custom_impl::err_impl`.
+pub use crate::err_impl::CompressionError;